@@ -0,0 +1,483 @@
+'use strict';
+
+// ---------- helpers ----------
+
+function toLocalInputValue(d) {
+  const off = d.getTimezoneOffset();
+  const local = new Date(d.getTime() - off * 60000);
+  return local.toISOString().slice(0, 16); // YYYY-MM-DDTHH:MM
+}
+
+function toISOStringFromInputValue(v) {
+  // v is 'YYYY-MM-DDTHH:MM' in local time
+  return new Date(v).toISOString();
+}
+
+function fmtBytes(n) {
+  const bytes = Number(n) || 0;
+  const units = ['B', 'KB', 'MB', 'GB', 'TB', 'PB'];
+  if (bytes < 1024) return `${bytes} B`;
+  let i = Math.floor(Math.log(bytes) / Math.log(1024));
+  i = Math.min(i, units.length - 1);
+  const val = bytes / Math.pow(1024, i);
+  const nf = new Intl.NumberFormat(undefined, { maximumFractionDigits: 2 });
+  return `${nf.format(val)} ${units[i]}`;
+}
+
+function fmtNumber(n) {
+  return new Intl.NumberFormat().format(Number(n) || 0);
+}
+
+function fmtTime(v) {
+  return new Date(v).toLocaleString();
+}
+
+function fmtDuration(ms) {
+  let s = Math.max(0, Math.floor(ms / 1000));
+  const h = Math.floor(s / 3600);
+  s -= h * 3600;
+  const m = Math.floor(s / 60);
+  s -= m * 60;
+  return h ? `${h}h ${m}m` : m ? `${m}m ${s}s` : `${s}s`;
+}
+
+function esc(v) {
+  return String(v ?? '').replace(/[&<>"']/g, (c) => ({
+    '&': '&amp;', '<': '&lt;', '>': '&gt;', '"': '&quot;', "'": '&#39;',
+  }[c]));
+}
+
+function link(kind, value) {
+  if (value === null || value === undefined) return '<em>unknown</em>';
+  return `<a href="#/${kind}/${encodeURIComponent(value)}">${esc(value)}</a>`;
+}
+
+async function getJSON(url) {
+  const res = await fetch(url);
+  if (!res.ok) {
+    const text = await res.text();
+    throw new Error(`HTTP ${res.status}${text ? ': ' + text : ''}`);
+  }
+  return res.json();
+}
+
+function showError(e) {
+  const alertBox = document.getElementById('alert');
+  alertBox.textContent = 'Failed to load data: ' + e.message;
+  alertBox.classList.remove('hidden');
+}
+
+function clearError() {
+  document.getElementById('alert').classList.add('hidden');
+}
+
+// ---------- range & parameters ----------
+
+const tz = Intl.DateTimeFormat().resolvedOptions().timeZone || 'UTC';
+
+function currentRange() {
+  const start = toISOStringFromInputValue(document.getElementById('start').value);
+  const end = toISOStringFromInputValue(document.getElementById('end').value);
+  let step = document.getElementById('step').value;
+  if (step === 'auto') {
+    const hours = (new Date(end) - new Date(start)) / 3600000;
+    step = hours <= 6 ? 'minute' : hours <= 24 * 14 ? 'hour' : 'day';
+  }
+  return { start, end, step };
+}
+
+function query(params) {
+  const p = new URLSearchParams();
+  Object.entries(params).forEach(([k, v]) => {
+    if (v !== undefined && v !== null && v !== '') p.set(k, v);
+  });
+  return p.toString();
+}
+
+// ---------- chart ----------
+
+// Renders a bar chart of `value(point)` over time into an <svg> element.
+// Points are positioned by `bucket_ts`, so missing buckets simply stay empty.
+function renderChart(svg, points, range, value, opts = {}) {
+  const W = svg.clientWidth || 800;
+  const H = svg.clientHeight || 220;
+  const pad = { l: 70, r: 10, t: 10, b: 24 };
+  const t0 = new Date(range.start).getTime() / 1000;
+  const t1 = new Date(range.end).getTime() / 1000;
+  const stepSecs = { minute: 60, hour: 3600, day: 86400 }[range.step] || 3600;
+  const max = Math.max(1, ...points.map(value));
+  const x = (t) => pad.l + ((t - t0) / Math.max(1, t1 - t0)) * (W - pad.l - pad.r);
+  const y = (v) => H - pad.b - (v / max) * (H - pad.t - pad.b);
+  const barW = Math.max(1, x(t0 + stepSecs) - x(t0) - 1);
+  const fmt = opts.format || fmtNumber;
+
+  let out = `<line class="axis" x1="${pad.l}" y1="${H - pad.b}" x2="${W - pad.r}" y2="${H - pad.b}"/>`;
+  out += `<line class="axis" x1="${pad.l}" y1="${pad.t}" x2="${pad.l}" y2="${H - pad.b}"/>`;
+  out += `<text x="${pad.l - 6}" y="${pad.t + 10}" text-anchor="end">${esc(fmt(max))}</text>`;
+  out += `<text x="${pad.l - 6}" y="${H - pad.b}" text-anchor="end">0</text>`;
+  out += `<text x="${pad.l}" y="${H - 6}">${esc(fmtTime(range.start))}</text>`;
+  out += `<text x="${W - pad.r}" y="${H - 6}" text-anchor="end">${esc(fmtTime(range.end))}</text>`;
+  points.forEach((p) => {
+    const v = value(p);
+    if (!v) return;
+    const top = y(v);
+    out += `<rect class="bar${opts.alt ? ' alt' : ''}" x="${x(p.bucket_ts)}" y="${top}" width="${barW}" height="${H - pad.b - top}">` +
+      `<title>${esc(p.bucket)}: ${esc(fmt(v))}</title></rect>`;
+  });
+  svg.setAttribute('viewBox', `0 0 ${W} ${H}`);
+  svg.innerHTML = out;
+}
+
+// Sums time series points by bucket (dropping the group key).
+function totalsByBucket(points) {
+  const map = new Map();
+  points.forEach((p) => {
+    const cur = map.get(p.bucket_ts) || { bucket: p.bucket, bucket_ts: p.bucket_ts, bytes_from_to: 0, bytes_to_from: 0, connections: 0 };
+    cur.bytes_from_to += p.bytes_from_to;
+    cur.bytes_to_from += p.bytes_to_from;
+    cur.connections += p.connections;
+    map.set(p.bucket_ts, cur);
+  });
+  return [...map.values()].sort((a, b) => a.bucket_ts - b.bucket_ts);
+}
+
+// Sums time series points by group key (dropping the time dimension).
+function totalsByKey(points) {
+  const map = new Map();
+  points.forEach((p) => {
+    const cur = map.get(p.key) || { key: p.key, bytes_from_to: 0, bytes_to_from: 0, connections: 0 };
+    cur.bytes_from_to += p.bytes_from_to;
+    cur.bytes_to_from += p.bytes_to_from;
+    cur.connections += p.connections;
+    map.set(p.key, cur);
+  });
+  return [...map.values()].sort((a, b) =>
+    (b.bytes_from_to + b.bytes_to_from) - (a.bytes_from_to + a.bytes_to_from));
+}
+
+// ---------- views ----------
+
+function chartsBlock() {
+  return `
+    <div class="cards" id="cards"></div>
+    <h2>Traffic</h2>
+    <svg class="chart" id="chart-bytes"></svg>
+    <h2>Connections</h2>
+    <svg class="chart" id="chart-conns"></svg>`;
+}
+
+function renderCards(points) {
+  const t = totalsByBucket(points).reduce((acc, p) => {
+    acc.up += p.bytes_from_to;
+    acc.down += p.bytes_to_from;
+    acc.conns += p.connections;
+    return acc;
+  }, { up: 0, down: 0, conns: 0 });
+  document.getElementById('cards').innerHTML = `
+    <div class="card"><div class="value">${fmtBytes(t.up + t.down)}</div><div class="label">Total traffic</div></div>
+    <div class="card"><div class="value">${fmtBytes(t.up)}</div><div class="label">Client → remote</div></div>
+    <div class="card"><div class="value">${fmtBytes(t.down)}</div><div class="label">Remote → client</div></div>
+    <div class="card"><div class="value">${fmtNumber(t.conns)}</div><div class="label">Connections</div></div>`;
+}
+
+function renderCharts(points, range) {
+  const totals = totalsByBucket(points);
+  renderCards(points);
+  renderChart(document.getElementById('chart-bytes'), totals, range,
+    (p) => p.bytes_from_to + p.bytes_to_from, { format: fmtBytes });
+  renderChart(document.getElementById('chart-conns'), totals, range,
+    (p) => p.connections, { alt: true });
+}
+
+function trafficTable(rows, keyTitle, keyCell) {
+  const body = rows.map((row, idx) => `
+    <tr>
+      <th scope="row">${idx + 1}</th>
+      <td>${keyCell(row)}</td>
+      <td class="num">${fmtBytes(row.bytes_from_to)}</td>
+      <td class="num">${fmtBytes(row.bytes_to_from)}</td>
+      <td class="num">${fmtBytes((row.bytes_from_to || 0) + (row.bytes_to_from || 0))}</td>
+      ${row.connections !== undefined ? `<td class="num">${fmtNumber(row.connections)}</td>` : ''}
+    </tr>`).join('');
+  const hasConns = rows.length && rows[0].connections !== undefined;
+  return `
+    <table>
+      <thead><tr>
+        <th>#</th><th>${esc(keyTitle)}</th>
+        <th class="num">Client → remote</th><th class="num">Remote → client</th><th class="num">Total</th>
+        ${hasConns ? '<th class="num">Connections</th>' : ''}
+      </tr></thead>
+      <tbody>${body || '<tr><td colspan="6" class="muted">No data</td></tr>'}</tbody>
+    </table>`;
+}
+
+async function overviewView(view) {
+  const range = currentRange();
+  view.innerHTML = `<h1>Overview</h1>${chartsBlock()}
+    <h2>Rules</h2><div id="rules"></div>
+    <h2>Clients</h2><div id="clients"></div>`;
+  const [points, byRule, clients] = await Promise.all([
+    getJSON(`/stats/timeseries?${query({ ...range, tz })}`),
+    getJSON(`/stats/timeseries?${query({ ...range, tz, group_by: 'rule' })}`),
+    getJSON(`/stats/clients?${query({ start: range.start, end: range.end })}`),
+  ]);
+  renderCharts(points, range);
+  document.getElementById('rules').innerHTML =
+    trafficTable(totalsByKey(byRule), 'Rule', (r) => link('rule', r.key));
+  document.getElementById('clients').innerHTML =
+    trafficTable(clients, 'Client', (r) => link('client', r.client_addr));
+}
+
+// Disabled rules and rules outside their schedule window are marked in the rule header.
+function ruleState(rule) {
+  if (!rule.enabled) return ' · disabled';
+  const next = rule.next_change ? fmtTime(rule.next_change) : null;
+  if (!rule.open) return next ? ` · closed by schedule until ${next}` : ' · closed by schedule';
+  return next ? ` · open until ${next}` : '';
+}
+
+async function ruleView(view, name) {
+  const range = currentRange();
+  view.innerHTML = `<h1>Rule <code>${esc(name)}</code></h1><p class="muted" id="rule-info"></p>
+    ${chartsBlock()}<h2>Clients</h2><div id="clients"></div>`;
+  const [connects, points, clients] = await Promise.all([
+    getJSON('/config/connects').catch(() => []),
+    getJSON(`/stats/timeseries?${query({ ...range, tz, name })}`),
+    getJSON(`/stats/clients?${query({ start: range.start, end: range.end, name })}`),
+  ]);
+  const rule = connects.find((c) => c.name === name);
+  document.getElementById('rule-info').textContent = rule
+    ? `:${rule.local_port ?? rule.local_port_range} → ${rule.remote_address}:${rule.remote_port ?? rule.remote_port_range}${ruleState(rule)}`
+    : 'Rule is not in the current configuration';
+  renderCharts(points, range);
+  document.getElementById('clients').innerHTML =
+    trafficTable(clients, 'Client', (r) => link('client', r.client_addr));
+}
+
+async function clientView(view, client) {
+  const range = currentRange();
+  view.innerHTML = `<h1>Client <code>${esc(client)}</code></h1>${chartsBlock()}
+    <h2>Rules used</h2><div id="rules"></div>`;
+  const points = await getJSON(`/stats/timeseries?${query({ ...range, tz, client, group_by: 'rule' })}`);
+  renderCharts(points, range);
+  document.getElementById('rules').innerHTML =
+    trafficTable(totalsByKey(points), 'Rule', (r) => link('rule', r.key));
+}
+
+// ---------- top-N breakdowns ----------
+
+const TOP_VIEWS = {
+  rules: { title: 'Rules', by: 'rule', keyTitle: 'Rule', keyCell: (r) => link('rule', r.name) },
+  upstreams: { title: 'Upstreams', by: 'upstream', keyTitle: 'Upstream', keyCell: (r) => esc(r.upstream ?? 'unknown') },
+  clients: { title: 'Clients', by: 'client', keyTitle: 'Client', keyCell: (r) => link('client', r.client_addr) },
+  matrix: { title: 'Client × rule', by: 'client_rule' },
+  errors: { title: 'Errors' },
+};
+
+// Ranking and size of the top-N views; kept while switching between them.
+const topState = { order: 'bytes', limit: 10 };
+
+function breakdownTable(rows, keyTitle, keyCell) {
+  const body = rows.map((row, idx) => `
+    <tr>
+      <th scope="row">${idx + 1}</th>
+      <td>${keyCell(row)}</td>
+      <td class="num">${fmtBytes(row.bytes_from_to)}</td>
+      <td class="num">${fmtBytes(row.bytes_to_from)}</td>
+      <td class="num">${fmtBytes(row.bytes_from_to + row.bytes_to_from)}</td>
+      <td class="num">${fmtNumber(row.connections)}</td>
+      <td class="num">${fmtNumber(row.errors)}</td>
+      <td class="num">${fmtNumber(row.timeouts)}</td>
+    </tr>`).join('');
+  return `
+    <table>
+      <thead><tr>
+        <th>#</th><th>${esc(keyTitle)}</th>
+        <th class="num">Client → remote</th><th class="num">Remote → client</th><th class="num">Total</th>
+        <th class="num">Connections</th><th class="num">Errors</th><th class="num">Timeouts</th>
+      </tr></thead>
+      <tbody>${body || '<tr><td colspan="8" class="muted">No data</td></tr>'}</tbody>
+    </table>`;
+}
+
+// Pivots client × rule rows into a table with one row per client and one
+// column per rule, in ranking order.
+function matrixTable(rows, order) {
+  const clients = [...new Set(rows.map((r) => r.client_addr))];
+  const rules = [...new Set(rows.map((r) => r.name))];
+  const cells = new Map(rows.map((r) => [`${r.client_addr}\u0000${r.name}`, r]));
+  const value = (r) => (order === 'connections'
+    ? fmtNumber(r.connections)
+    : fmtBytes(r.bytes_from_to + r.bytes_to_from));
+  const body = clients.map((c) => `
+    <tr>
+      <td>${link('client', c)}</td>
+      ${rules.map((n) => {
+        const r = cells.get(`${c}\u0000${n}`);
+        return `<td class="num">${r ? value(r) : '<span class="muted">–</span>'}</td>`;
+      }).join('')}
+    </tr>`).join('');
+  return `
+    <div class="scroll">
+      <table>
+        <thead><tr><th>Client</th>${rules.map((n) => `<th class="num">${link('rule', n)}</th>`).join('')}</tr></thead>
+        <tbody>${body || '<tr><td class="muted">No data</td></tr>'}</tbody>
+      </table>
+    </div>`;
+}
+
+function failuresTable(rows) {
+  return `
+    <table>
+      <thead><tr><th>#</th><th>Kind</th><th>Close reason</th><th>Error</th><th class="num">Count</th><th>Last seen</th></tr></thead>
+      <tbody>${rows.map((f, idx) => `
+        <tr>
+          <th scope="row">${idx + 1}</th>
+          <td><span class="badge">${esc(f.log_name)}</span></td>
+          <td>${esc(f.close_reason ?? '')}</td>
+          <td>${esc(f.error ?? '')}</td>
+          <td class="num">${fmtNumber(f.count)}</td>
+          <td>${esc(fmtTime(f.last_ts * 1000))}</td>
+        </tr>`).join('') || '<tr><td colspan="6" class="muted">No failures</td></tr>'}
+      </tbody>
+    </table>`;
+}
+
+async function topView(view, kind) {
+  const range = currentRange();
+  const current = TOP_VIEWS[kind] ? kind : 'rules';
+  const def = TOP_VIEWS[current];
+  const tabs = Object.entries(TOP_VIEWS).map(([k, v]) =>
+    `<a href="#/top/${k}" class="${k === current ? 'active' : ''}">${esc(v.title)}</a>`).join('');
+  view.innerHTML = `<h1>Top</h1>
+    <nav class="tabs">${tabs}</nav>
+    <div class="toolbar">
+      ${def.by ? `<label>Rank by
+        <select id="top-order">
+          <option value="bytes">Bytes</option>
+          <option value="connections">Connections</option>
+        </select>
+      </label>` : ''}
+      <label>Show
+        <select id="top-limit">
+          <option value="10">Top 10</option>
+          <option value="25">Top 25</option>
+          <option value="100">Top 100</option>
+        </select>
+      </label>
+    </div>
+    <div id="top"></div>`;
+  const orderSel = document.getElementById('top-order');
+  const limitSel = document.getElementById('top-limit');
+  if (orderSel) orderSel.value = topState.order;
+  limitSel.value = String(topState.limit);
+  const rerender = () => {
+    if (orderSel) topState.order = orderSel.value;
+    topState.limit = Number(limitSel.value);
+    topView(view, current).catch(showError);
+  };
+  if (orderSel) orderSel.addEventListener('change', rerender);
+  limitSel.addEventListener('change', rerender);
+
+  const out = document.getElementById('top');
+  const span = { start: range.start, end: range.end };
+  if (current === 'errors') {
+    const rows = await getJSON(`/stats/failures?${query({ ...span, limit: topState.limit })}`);
+    out.innerHTML = failuresTable(rows);
+  } else if (current === 'matrix') {
+    // The top cells by the chosen ranking; a client or rule outside them is left out.
+    const limit = Math.min(1000, topState.limit * 10);
+    const rows = await getJSON(`/stats/breakdown?${query({ ...span, by: def.by, order: topState.order, limit })}`);
+    out.innerHTML = `<p class="muted">Top ${limit} client × rule pairs by ${esc(topState.order)}.</p>` +
+      matrixTable(rows, topState.order);
+  } else {
+    const rows = await getJSON(`/stats/breakdown?${query({ ...span, by: def.by, order: topState.order, limit: topState.limit })}`);
+    out.innerHTML = breakdownTable(rows, def.keyTitle, def.keyCell);
+  }
+}
+
+async function liveView(view) {
+  view.innerHTML = `<h1>Live</h1>
+    <h2>Active sessions <span class="muted" id="active-count"></span></h2><div id="active"></div>
+    <h2>Recent errors and timeouts</h2><div id="errors"></div>`;
+  const [active, errors] = await Promise.all([
+    getJSON('/sessions/active'),
+    getJSON('/errors/recent'),
+  ]);
+  const now = Date.now();
+  document.getElementById('active-count').textContent = `(${active.length})`;
+  document.getElementById('active').innerHTML = `
+    <table>
+      <thead><tr><th>Started</th><th>Duration</th><th>Rule</th><th>Client</th><th>Upstream</th></tr></thead>
+      <tbody>${active.map((s) => `
+        <tr>
+          <td>${esc(fmtTime(s.started))}</td>
+          <td>${fmtDuration(now - new Date(s.started).getTime())}</td>
+          <td>${link('rule', s.name)}</td>
+          <td>${link('client', s.client_addr)}</td>
+          <td>${esc(s.remote_address)}:${s.remote_port}</td>
+        </tr>`).join('') || '<tr><td colspan="5" class="muted">No active sessions</td></tr>'}
+      </tbody>
+    </table>`;
+  document.getElementById('errors').innerHTML = `
+    <table>
+      <thead><tr><th>Time</th><th>Kind</th><th>Rule</th><th>Client</th><th>Upstream</th><th>Error</th></tr></thead>
+      <tbody>${errors.map((e) => `
+        <tr>
+          <td>${esc(fmtTime(e.ts))}</td>
+          <td><span class="badge">${esc(e.kind)}</span></td>
+          <td>${link('rule', e.name)}</td>
+          <td>${link('client', e.client_addr)}</td>
+          <td>${esc(e.remote_address)}:${e.remote_port}</td>
+          <td>${esc(e.error)}</td>
+        </tr>`).join('') || '<tr><td colspan="6" class="muted">No errors</td></tr>'}
+      </tbody>
+    </table>`;
+}
+
+// ---------- router ----------
+
+let refreshTimer = null;
+
+async function route() {
+  clearError();
+  if (refreshTimer) {
+    clearInterval(refreshTimer);
+    refreshTimer = null;
+  }
+  const view = document.getElementById('view');
+  const parts = location.hash.replace(/^#\/?/, '').split('/').map(decodeURIComponent);
+  const page = parts[0] || 'overview';
+  document.querySelectorAll('[data-nav]').forEach((a) =>
+    a.classList.toggle('active', a.dataset.nav === page));
+  document.getElementById('range-form').classList.toggle('hidden', page === 'live');
+  try {
+    if (page === 'rule' && parts[1]) {
+      await ruleView(view, parts[1]);
+    } else if (page === 'client' && parts[1]) {
+      await clientView(view, parts[1]);
+    } else if (page === 'top') {
+      await topView(view, parts[1]);
+    } else if (page === 'live') {
+      await liveView(view);
+      refreshTimer = setInterval(() => liveView(view).catch(showError), 3000);
+    } else {
+      await overviewView(view);
+    }
+  } catch (e) {
+    showError(e);
+  }
+}
+
+document.addEventListener('DOMContentLoaded', () => {
+  const now = new Date();
+  const weekAgo = new Date(now.getTime() - 7 * 24 * 60 * 60 * 1000);
+  document.getElementById('start').value = toLocalInputValue(weekAgo);
+  document.getElementById('end').value = toLocalInputValue(now);
+  document.getElementById('range-form').addEventListener('submit', (e) => {
+    e.preventDefault();
+    route();
+  });
+  window.addEventListener('hashchange', route);
+  route();
+});
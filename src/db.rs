@@ -1,11 +1,17 @@
-use chrono::{DateTime, Utc};
-use serde::Serialize;
+use chrono::{DateTime, Duration, LocalResult, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::sync::Arc;
 use tokio_rusqlite::Connection as AsyncConnection;
 
+use crate::events::{instance_id, LogEvent};
+
 pub type SharedDb = Arc<AsyncConnection>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ConnectionRow {
     pub ts: i64,
     pub name: String,
@@ -16,6 +22,137 @@ pub struct ConnectionRow {
     pub client_addr: Option<String>,
     pub bytes_from_to: u64,
     pub bytes_to_from: u64,
+    /// Correlates the started/closed/timeout rows of one session.
+    pub session_id: u64,
+    /// Instance that wrote the row (`instance_id`); session ids are only
+    /// unique within one instance. `None` for rows written before it existed.
+    pub instance_id: Option<String>,
+    pub upstream_ip: Option<String>,
+    pub error: Option<String>,
+    pub close_reason: Option<String>,
+    pub duration_ms: Option<u64>,
+}
+
+impl ConnectionRow {
+    /// The stored row for a connection event; rule events (`rule_opened`,
+    /// `rule_closed`) are only streamed and give `None`.
+    pub fn from_event(event: LogEvent) -> Option<Self> {
+        let log_name = String::from(event.kind());
+        let row = match event {
+            LogEvent::ConnectionStarted {
+                ts,
+                session_id,
+                name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                upstream_ip,
+            } => ConnectionRow {
+                ts: ts.timestamp(),
+                name,
+                log_name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                bytes_from_to: 0,
+                bytes_to_from: 0,
+                session_id,
+                instance_id: Some(instance_id().to_string()),
+                upstream_ip,
+                error: None,
+                close_reason: None,
+                duration_ms: None,
+            },
+            LogEvent::ConnectionClosed {
+                ts,
+                session_id,
+                name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                upstream_ip,
+                bytes_from_to,
+                bytes_to_from,
+                duration_ms,
+                close_reason,
+                error,
+            } => ConnectionRow {
+                ts: ts.timestamp(),
+                name,
+                log_name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                bytes_from_to,
+                bytes_to_from,
+                session_id,
+                instance_id: Some(instance_id().to_string()),
+                upstream_ip,
+                error,
+                close_reason: Some(close_reason.as_str().to_string()),
+                duration_ms: Some(duration_ms),
+            },
+            LogEvent::ConnectionError {
+                ts,
+                session_id,
+                name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                error,
+            } => ConnectionRow {
+                ts: ts.timestamp(),
+                name,
+                log_name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                bytes_from_to: 0,
+                bytes_to_from: 0,
+                session_id,
+                instance_id: Some(instance_id().to_string()),
+                upstream_ip: None,
+                error: Some(error),
+                close_reason: None,
+                duration_ms: None,
+            },
+            LogEvent::ConnectionTimeout {
+                ts,
+                session_id,
+                name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                upstream_ip,
+                error,
+            } => ConnectionRow {
+                ts: ts.timestamp(),
+                name,
+                log_name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                bytes_from_to: 0,
+                bytes_to_from: 0,
+                session_id,
+                instance_id: Some(instance_id().to_string()),
+                upstream_ip,
+                error: Some(error),
+                close_reason: None,
+                duration_ms: None,
+            },
+            LogEvent::RuleOpened { .. } | LogEvent::RuleClosed { .. } => return None,
+        };
+        Some(row)
+    }
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -25,94 +162,617 @@ pub struct ClientTraffic {
     pub bytes_to_from: u64,
 }
 
-pub async fn init_db(path: &str) -> anyhow::Result<SharedDb> {
-    let conn = AsyncConnection::open(path).await?;
-    // Create a simple table to store connection stats
-    conn
-        .call(|c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<()> {
-            c.execute(
-                r#"
-                CREATE TABLE IF NOT EXISTS connections (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    ts INTEGER NOT NULL,
-                    name TEXT,
-                    log_name TEXT,
-                    local_port INTEGER,
-                    remote_address TEXT,
-                    remote_port INTEGER,
-                    client_addr TEXT,
-                    bytes_from_to INTEGER,
-                    bytes_to_from INTEGER
-                );
-                "#,
-                [],
-            )
-            .map_err(tokio_rusqlite::Error::from)?;
+/// Dimension a time series is split by.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    Rule,
+    Client,
+    Upstream,
+}
 
-            // Indexes to speed up lookups by remote_address and client_addr
-            c.execute(
-                "CREATE INDEX IF NOT EXISTS idx_connections_remote_address ON connections(remote_address)",
-                [],
-            )
-            .map_err(tokio_rusqlite::Error::from)?;
-            c.execute(
-                "CREATE INDEX IF NOT EXISTS idx_connections_client_addr ON connections(client_addr)",
-                [],
+impl GroupBy {
+    pub(crate) fn sql_expr(self) -> &'static str {
+        match self {
+            GroupBy::Rule => "name",
+            GroupBy::Client => "client_addr",
+            GroupBy::Upstream => "remote_address || ':' || remote_port",
+        }
+    }
+}
+
+/// Dimensions a traffic breakdown is grouped by.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Breakdown {
+    Rule,
+    Client,
+    Upstream,
+    /// Client × rule matrix.
+    ClientRule,
+}
+
+impl Breakdown {
+    /// The `name`, `client_addr` and upstream result columns; dimensions not
+    /// grouped by are NULL. Valid in both SQLite and PostgreSQL.
+    pub(crate) fn sql_columns(self) -> [&'static str; 3] {
+        const NULL: &str = "CAST(NULL AS TEXT)";
+        match self {
+            Breakdown::Rule => ["name", NULL, NULL],
+            Breakdown::Client => [NULL, "client_addr", NULL],
+            Breakdown::Upstream => [NULL, NULL, GroupBy::Upstream.sql_expr()],
+            Breakdown::ClientRule => ["name", "client_addr", NULL],
+        }
+    }
+}
+
+/// What a breakdown is ranked by.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TopBy {
+    #[default]
+    Bytes,
+    Connections,
+}
+
+impl TopBy {
+    /// `ORDER BY` over the aggregated source columns, best first; ties are
+    /// broken by the group columns so every backend returns the same order.
+    pub(crate) fn sql_order(self) -> &'static str {
+        match self {
+            TopBy::Bytes => {
+                "SUM(bytes_from_to) + SUM(bytes_to_from) DESC, SUM(sessions) DESC, 1, 2, 3"
+            }
+            TopBy::Connections => {
+                "SUM(sessions) DESC, SUM(bytes_from_to) + SUM(bytes_to_from) DESC, 1, 2, 3"
+            }
+        }
+    }
+}
+
+/// Totals for one group of a breakdown; only the grouped dimensions are set.
+#[derive(Clone, Debug, Serialize)]
+pub struct BreakdownRow {
+    pub name: Option<String>,
+    pub client_addr: Option<String>,
+    /// `address:port` of the remote side.
+    pub upstream: Option<String>,
+    pub bytes_from_to: u64,
+    pub bytes_to_from: u64,
+    /// Sessions opened (as in the time series).
+    pub connections: u64,
+    /// Failed upstream connects.
+    pub errors: u64,
+    pub timeouts: u64,
+}
+
+/// Optional filters shared by the time series queries.
+#[derive(Clone, Debug, Default)]
+pub struct TrafficFilter {
+    pub name: Option<String>,
+    pub client_addr: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TimeseriesPoint {
+    /// Bucket start in the requested timezone (RFC3339 with offset).
+    pub bucket: String,
+    /// Bucket start as unix seconds.
+    pub bucket_ts: i64,
+    /// Value of the `group_by` dimension; `None` when not grouped.
+    pub key: Option<String>,
+    pub bytes_from_to: u64,
+    pub bytes_to_from: u64,
+    pub connections: u64,
+}
+
+/// Upper bound on buckets per key, so a tiny step over a long range can't
+/// produce an unbounded response.
+pub const MAX_TIMESERIES_BUCKETS: i64 = 10_000;
+
+/// Ordered schema migrations; entry `i` brings the database to version `i + 1`.
+/// Never edit a released migration, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema. Uses IF NOT EXISTS so databases created before
+    // versioning was introduced are adopted as version 1 unchanged.
+    r#"
+    CREATE TABLE IF NOT EXISTS connections (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        ts INTEGER NOT NULL,
+        name TEXT,
+        log_name TEXT,
+        local_port INTEGER,
+        remote_address TEXT,
+        remote_port INTEGER,
+        client_addr TEXT,
+        bytes_from_to INTEGER,
+        bytes_to_from INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS idx_connections_remote_address ON connections(remote_address);
+    CREATE INDEX IF NOT EXISTS idx_connections_client_addr ON connections(client_addr);
+    CREATE INDEX IF NOT EXISTS idx_connections_log_name ON connections(log_name);
+    CREATE INDEX IF NOT EXISTS idx_connections_ts ON connections(ts);
+    "#,
+    // 2: per-session details. Rows written before this version have NULLs.
+    r#"
+    ALTER TABLE connections ADD COLUMN session_id INTEGER;
+    ALTER TABLE connections ADD COLUMN upstream_ip TEXT;
+    ALTER TABLE connections ADD COLUMN error TEXT;
+    ALTER TABLE connections ADD COLUMN close_reason TEXT;
+    ALTER TABLE connections ADD COLUMN duration_ms INTEGER;
+    CREATE INDEX IF NOT EXISTS idx_connections_session_id ON connections(session_id);
+    "#,
+    // 3: hourly and daily rollups, backfilled from existing rows. Key columns
+    // use '' / 0 instead of NULL so upserts hit the primary key.
+    r#"
+    CREATE TABLE traffic_hourly (
+        bucket INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        client_addr TEXT NOT NULL,
+        remote_address TEXT NOT NULL,
+        remote_port INTEGER NOT NULL,
+        bytes_from_to INTEGER NOT NULL DEFAULT 0,
+        bytes_to_from INTEGER NOT NULL DEFAULT 0,
+        sessions INTEGER NOT NULL DEFAULT 0,
+        errors INTEGER NOT NULL DEFAULT 0,
+        timeouts INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (bucket, name, client_addr, remote_address, remote_port)
+    );
+    CREATE TABLE traffic_daily (
+        bucket INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        client_addr TEXT NOT NULL,
+        remote_address TEXT NOT NULL,
+        remote_port INTEGER NOT NULL,
+        bytes_from_to INTEGER NOT NULL DEFAULT 0,
+        bytes_to_from INTEGER NOT NULL DEFAULT 0,
+        sessions INTEGER NOT NULL DEFAULT 0,
+        errors INTEGER NOT NULL DEFAULT 0,
+        timeouts INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (bucket, name, client_addr, remote_address, remote_port)
+    );
+    INSERT INTO traffic_hourly
+    SELECT (ts / 3600) * 3600, COALESCE(name, ''), COALESCE(client_addr, ''),
+           COALESCE(remote_address, ''), COALESCE(remote_port, 0),
+           COALESCE(SUM(bytes_from_to), 0), COALESCE(SUM(bytes_to_from), 0),
+           SUM(log_name = 'connection_started'), SUM(log_name = 'connection_error'),
+           SUM(log_name = 'connection_timeout')
+    FROM connections GROUP BY 1, 2, 3, 4, 5;
+    INSERT INTO traffic_daily
+    SELECT (bucket / 86400) * 86400, name, client_addr, remote_address, remote_port,
+           SUM(bytes_from_to), SUM(bytes_to_from), SUM(sessions), SUM(errors), SUM(timeouts)
+    FROM traffic_hourly GROUP BY 1, 2, 3, 4, 5;
+    "#,
+    // 4: instance that wrote the row, so sessions of instances sharing one
+    // database don't merge. Older rows keep NULL.
+    r#"
+    ALTER TABLE connections ADD COLUMN instance_id TEXT;
+    "#,
+];
+
+/// Schema version this binary writes and understands.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// The database was migrated by a newer binary; running against it could
+/// corrupt data, so startup is refused.
+#[derive(Debug)]
+pub struct SchemaTooNew {
+    pub found: i64,
+    pub supported: i64,
+}
+
+impl std::fmt::Display for SchemaTooNew {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "database schema version {} is newer than supported version {}, upgrade rs-port-forward",
+            self.found, self.supported
+        )
+    }
+}
+
+impl std::error::Error for SchemaTooNew {}
+
+/// A database opened read-only is at another schema version than this
+/// binary; it is reported rather than migrated.
+#[derive(Debug)]
+pub struct SchemaMismatch {
+    pub found: i64,
+    pub expected: i64,
+}
+
+impl std::fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.found > self.expected {
+            write!(
+                f,
+                "database schema version {} is newer than supported version {}, upgrade rs-port-forward",
+                self.found, self.expected
             )
-            .map_err(tokio_rusqlite::Error::from)?;
-            c.execute(
-                "CREATE INDEX IF NOT EXISTS idx_connections_log_name ON connections(log_name)",
-                [],
+        } else {
+            write!(
+                f,
+                "database schema version {} is older than {}, start rs-port-forward once to migrate it",
+                self.found, self.expected
             )
-            .map_err(tokio_rusqlite::Error::from)?;
+        }
+    }
+}
 
-            Ok(())
+impl std::error::Error for SchemaMismatch {}
+
+enum MigrationOutcome {
+    UpToDate(i64),
+    Applied { from: i64, to: i64 },
+    TooNew(i64),
+}
+
+/// Applies pending migrations in a single transaction: either the database
+/// ends up at `SCHEMA_VERSION` or it is left untouched.
+fn migrate(c: &mut rusqlite::Connection) -> rusqlite::Result<MigrationOutcome> {
+    let tx = c.transaction()?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            applied_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    let current: i64 = tx.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        [],
+        |row| row.get(0),
+    )?;
+    if current > SCHEMA_VERSION {
+        return Ok(MigrationOutcome::TooNew(current));
+    }
+    if current == SCHEMA_VERSION {
+        return Ok(MigrationOutcome::UpToDate(current));
+    }
+    let now = Utc::now().timestamp();
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        tx.execute_batch(sql)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, applied_at) VALUES (?1, ?2)",
+            rusqlite::params![index as i64 + 1, now],
+        )?;
+    }
+    tx.commit()?;
+    Ok(MigrationOutcome::Applied {
+        from: current,
+        to: SCHEMA_VERSION,
+    })
+}
+
+/// SQLite connection settings (`sqlite_*` in the config).
+#[derive(Clone, Debug)]
+pub struct SqliteOptions {
+    /// `synchronous` pragma: `off`, `normal`, `full` or `extra`. `normal` is
+    /// durable across application crashes in WAL mode and much cheaper.
+    pub synchronous: String,
+    /// Page cache per connection in MiB; `None` keeps SQLite's default.
+    pub cache_size_mb: Option<u64>,
+    /// How long a connection waits for a lock before failing with `SQLITE_BUSY`.
+    pub busy_timeout: std::time::Duration,
+    /// Read-only connections serving the HTTP API and exports.
+    pub read_connections: usize,
+}
+
+impl Default for SqliteOptions {
+    fn default() -> Self {
+        Self {
+            synchronous: "normal".to_string(),
+            cache_size_mb: None,
+            busy_timeout: std::time::Duration::from_secs(5),
+            read_connections: 4,
+        }
+    }
+}
+
+pub const SYNCHRONOUS_MODES: &[&str] = &["off", "normal", "full", "extra"];
+
+/// Settings every connection needs, reader or writer.
+fn apply_connection_options(
+    c: &rusqlite::Connection,
+    options: &SqliteOptions,
+) -> rusqlite::Result<()> {
+    c.busy_timeout(options.busy_timeout)?;
+    if let Some(mb) = options.cache_size_mb {
+        // A negative cache_size is in KiB rather than pages.
+        c.pragma_update(None, "cache_size", -((mb * 1024) as i64))?;
+    }
+    Ok(())
+}
+
+/// Opens the writer connection: switches the database to WAL so readers never
+/// block the writer, then applies pending migrations.
+pub async fn init_db(path: &str, options: &SqliteOptions) -> anyhow::Result<SharedDb> {
+    let synchronous = options.synchronous.to_lowercase();
+    if !SYNCHRONOUS_MODES.contains(&synchronous.as_str()) {
+        anyhow::bail!(
+            "invalid sqlite_synchronous {:?}, expected one of {}",
+            options.synchronous,
+            SYNCHRONOUS_MODES.join(", ")
+        );
+    }
+    let conn = AsyncConnection::open(path).await?;
+    let options = options.clone();
+    let (journal_mode, outcome) = conn
+        .call(move |c: &mut rusqlite::Connection| {
+            apply_connection_options(c, &options).map_err(tokio_rusqlite::Error::from)?;
+            let journal_mode: String = c
+                .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))
+                .map_err(tokio_rusqlite::Error::from)?;
+            c.pragma_update(None, "synchronous", &synchronous)
+                .map_err(tokio_rusqlite::Error::from)?;
+            let outcome = migrate(c).map_err(tokio_rusqlite::Error::from)?;
+            Ok((journal_mode, outcome))
         })
         .await?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        warn!(
+            "SQLite refused WAL mode, using journal_mode={} (readers may block the writer)",
+            journal_mode
+        );
+    }
+    match outcome {
+        MigrationOutcome::UpToDate(version) => {
+            info!("SQLite schema is up to date (version {})", version);
+        }
+        MigrationOutcome::Applied { from, to } => {
+            info!("SQLite schema migrated from version {} to {}", from, to);
+        }
+        MigrationOutcome::TooNew(found) => {
+            return Err(SchemaTooNew {
+                found,
+                supported: SCHEMA_VERSION,
+            }
+            .into());
+        }
+    }
 
     Ok(Arc::new(conn))
 }
 
-#[allow(clippy::too_many_arguments)]
-pub async fn insert_connection_row(
-    db: &SharedDb,
-    name: &str,
-    local_port: u16,
-    remote_address: &str,
-    remote_port: u16,
-    client_addr: Option<String>,
-    bytes_from_to: u64,
-    bytes_to_from: u64,
-) -> anyhow::Result<()> {
-    let ts: i64 = Utc::now().timestamp();
-    let name = name.to_string();
-    let remote_address = remote_address.to_string();
-    db
-        .call(move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<()> {
-            let mut stmt = c
-                .prepare(
-                    "INSERT INTO connections (ts, name, local_port, remote_address, remote_port, client_addr, bytes_from_to, bytes_to_from)
-                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+fn read_only_flags() -> rusqlite::OpenFlags {
+    rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+        | rusqlite::OpenFlags::SQLITE_OPEN_URI
+        | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX
+}
+
+/// Opens the read-only connections used for queries. Call after `init_db`,
+/// which creates the file and the schema.
+pub async fn open_readers(path: &str, options: &SqliteOptions) -> anyhow::Result<Vec<SharedDb>> {
+    let mut readers = Vec::with_capacity(options.read_connections);
+    for _ in 0..options.read_connections.max(1) {
+        let conn = AsyncConnection::open_with_flags(path, read_only_flags()).await?;
+        let options = options.clone();
+        conn.call(move |c: &mut rusqlite::Connection| {
+            apply_connection_options(c, &options).map_err(tokio_rusqlite::Error::from)
+        })
+        .await?;
+        readers.push(Arc::new(conn));
+    }
+    Ok(readers)
+}
+
+/// Opens an existing database for queries only: no file is created, nothing
+/// is migrated and the journal mode is left alone. Fails with
+/// [`SchemaMismatch`] unless the schema is at `SCHEMA_VERSION`.
+pub async fn open_existing(path: &str, options: &SqliteOptions) -> anyhow::Result<Vec<SharedDb>> {
+    if !Path::new(path).is_file() {
+        anyhow::bail!("database file {} does not exist", path);
+    }
+    let readers = open_readers(path, options).await?;
+    let found = readers[0]
+        .call(|c: &mut rusqlite::Connection| {
+            let versioned: bool = c
+                .query_row(
+                    "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations'",
+                    [],
+                    |row| row.get(0),
                 )
                 .map_err(tokio_rusqlite::Error::from)?;
-            stmt
-                .execute(rusqlite::params![
-                    ts,
-                    name,
-                    local_port as i64,
-                    remote_address,
-                    remote_port as i64,
-                    client_addr,
-                    bytes_from_to as i64,
-                    bytes_to_from as i64
-                ])
-                .map(|_| ())
-                .map_err(tokio_rusqlite::Error::from)
+            if !versioned {
+                return Ok(0);
+            }
+            c.query_row(
+                "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(tokio_rusqlite::Error::from)
         })
+        .await?;
+    if found != SCHEMA_VERSION {
+        return Err(SchemaMismatch {
+            found,
+            expected: SCHEMA_VERSION,
+        }
+        .into());
+    }
+    Ok(readers)
+}
+
+/// Copies a consistent snapshot of the database at `src` to `dest` with
+/// SQLite's online backup API. `dest` is replaced atomically; the copy is
+/// first written to a private temporary file in the same directory.
+pub fn backup_file(
+    src: &str,
+    dest: &Path,
+    busy_timeout: std::time::Duration,
+) -> anyhow::Result<()> {
+    let dir = match dest.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmp = backup_to_temp(src, dir, busy_timeout)?;
+    tmp.persist(dest)?;
+    Ok(())
+}
+
+/// Writes a snapshot of `src` to a new temporary file in `dir`, created with
+/// a random name and owner-only permissions. The file is deleted when the
+/// returned path is dropped.
+///
+/// The copy is taken in a single step inside one read transaction, so it is
+/// never restarted by concurrent writes and, in WAL mode, does not stall the
+/// writer.
+pub fn backup_to_temp(
+    src: &str,
+    dir: &Path,
+    busy_timeout: std::time::Duration,
+) -> anyhow::Result<tempfile::TempPath> {
+    let source = rusqlite::Connection::open_with_flags(src, read_only_flags())?;
+    source.busy_timeout(busy_timeout)?;
+    let tmp = tempfile::Builder::new()
+        .prefix(".rs-port-forward-backup-")
+        .suffix(".sqlite")
+        .tempfile_in(dir)?
+        .into_temp_path();
+    let mut target = rusqlite::Connection::open(&tmp)?;
+    {
+        let backup = rusqlite::backup::Backup::new(&source, &mut target)?;
+        let deadline = std::time::Instant::now() + busy_timeout;
+        loop {
+            // -1 copies every page in one step.
+            match backup.step(-1)? {
+                rusqlite::backup::StepResult::Done => break,
+                rusqlite::backup::StepResult::More => {}
+                _ if std::time::Instant::now() >= deadline => {
+                    anyhow::bail!("database stayed locked for {:?}", busy_timeout)
+                }
+                _ => std::thread::sleep(std::time::Duration::from_millis(50)),
+            }
+        }
+    }
+    target.close().map_err(|(_, e)| e)?;
+    Ok(tmp)
+}
+
+/// How long to keep data; `None` keeps it forever.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetentionPolicy {
+    /// Raw rows in `connections`.
+    pub raw_days: Option<u64>,
+    /// Aggregated (rollup) tables.
+    pub rollup_days: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PruneReport {
+    pub raw_rows: u64,
+    pub rollup_rows: u64,
+    pub freed_pages: u64,
+}
+
+/// Rows deleted per statement; small batches keep the writer from stalling.
+pub(crate) const PRUNE_BATCH: i64 = 5000;
+/// Pages returned to the OS per `incremental_vacuum` call.
+const VACUUM_PAGES: i64 = 10_000;
+/// Aggregated tables with their bucket timestamp column.
+pub(crate) const ROLLUP_TABLES: &[(&str, &str)] =
+    &[("traffic_hourly", "bucket"), ("traffic_daily", "bucket")];
+
+/// Rollup granularities, finest first: bucket size in seconds and table.
+pub(crate) const ROLLUP_LEVELS: &[(i64, &str)] =
+    &[(3600, "traffic_hourly"), (86400, "traffic_daily")];
+
+/// Adds one row's contribution to a rollup bucket.
+fn rollup_upsert_sql(table: &str) -> String {
+    format!(
+        "INSERT INTO {table} (bucket, name, client_addr, remote_address, remote_port,
+                              bytes_from_to, bytes_to_from, sessions, errors, timeouts)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+         ON CONFLICT (bucket, name, client_addr, remote_address, remote_port) DO UPDATE SET
+             bytes_from_to = bytes_from_to + excluded.bytes_from_to,
+             bytes_to_from = bytes_to_from + excluded.bytes_to_from,
+             sessions = sessions + excluded.sessions,
+             errors = errors + excluded.errors,
+             timeouts = timeouts + excluded.timeouts"
+    )
+}
+
+/// Switches the database to incremental auto-vacuum so pruning can give space
+/// back. Converting an existing database needs a one-time full `VACUUM`.
+pub async fn enable_incremental_vacuum(db: &SharedDb) -> anyhow::Result<()> {
+    db.call(
+        |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<()> {
+            let mode: i64 = c
+                .query_row("PRAGMA auto_vacuum", [], |row| row.get(0))
+                .map_err(tokio_rusqlite::Error::from)?;
+            // 2 = INCREMENTAL
+            if mode != 2 {
+                info!("Converting SQLite database to incremental auto-vacuum (one-time VACUUM)");
+                c.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM;")
+                    .map_err(tokio_rusqlite::Error::from)?;
+            }
+            Ok(())
+        },
+    )
     .await?;
     Ok(())
 }
 
+async fn delete_older_than(
+    db: &SharedDb,
+    table: &'static str,
+    column: &'static str,
+    cutoff: i64,
+) -> anyhow::Result<u64> {
+    let mut total: u64 = 0;
+    loop {
+        let sql = format!(
+            "DELETE FROM {table} WHERE rowid IN (SELECT rowid FROM {table} WHERE {column} < ?1 LIMIT ?2)"
+        );
+        let deleted = db
+            .call(
+                move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<usize> {
+                    c.execute(&sql, rusqlite::params![cutoff, PRUNE_BATCH])
+                        .map_err(tokio_rusqlite::Error::from)
+                },
+            )
+            .await?;
+        total += deleted as u64;
+        if (deleted as i64) < PRUNE_BATCH {
+            return Ok(total);
+        }
+    }
+}
+
+/// Deletes rows older than the retention policy in small batches, then
+/// releases free pages with `incremental_vacuum`.
+pub async fn prune(db: &SharedDb, policy: RetentionPolicy) -> anyhow::Result<PruneReport> {
+    let now = Utc::now().timestamp();
+    let mut report = PruneReport::default();
+    if let Some(days) = policy.raw_days {
+        let cutoff = now - (days as i64) * 86400;
+        report.raw_rows = delete_older_than(db, "connections", "ts", cutoff).await?;
+    }
+    if let Some(days) = policy.rollup_days {
+        let cutoff = now - (days as i64) * 86400;
+        for (table, column) in ROLLUP_TABLES {
+            report.rollup_rows += delete_older_than(db, table, column, cutoff).await?;
+        }
+    }
+    report.freed_pages = db
+        .call(
+            |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<u64> {
+                let before: i64 = c
+                    .query_row("PRAGMA freelist_count", [], |row| row.get(0))
+                    .map_err(tokio_rusqlite::Error::from)?;
+                c.execute_batch(&format!("PRAGMA incremental_vacuum({});", VACUUM_PAGES))
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let after: i64 = c
+                    .query_row("PRAGMA freelist_count", [], |row| row.get(0))
+                    .map_err(tokio_rusqlite::Error::from)?;
+                Ok((before - after).max(0) as u64)
+            },
+        )
+        .await?;
+    Ok(report)
+}
+
 pub async fn insert_connection_rows(db: &SharedDb, rows: &[ConnectionRow]) -> anyhow::Result<()> {
     if rows.is_empty() {
         return Ok(());
@@ -125,8 +785,9 @@ pub async fn insert_connection_rows(db: &SharedDb, rows: &[ConnectionRow]) -> an
             {
                 let mut stmt = tx
                     .prepare(
-                        "INSERT INTO connections (ts, name, log_name, local_port, remote_address, remote_port, client_addr, bytes_from_to, bytes_to_from)
-                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                        "INSERT INTO connections (ts, name, log_name, local_port, remote_address, remote_port, client_addr, bytes_from_to, bytes_to_from,
+                                                  session_id, upstream_ip, error, close_reason, duration_ms, instance_id)
+                         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
                     )
                     .map_err(tokio_rusqlite::Error::from)?;
                 for r in rows_vec.iter() {
@@ -140,10 +801,39 @@ pub async fn insert_connection_rows(db: &SharedDb, rows: &[ConnectionRow]) -> an
                             r.remote_port as i64,
                             r.client_addr,
                             r.bytes_from_to as i64,
-                            r.bytes_to_from as i64
+                            r.bytes_to_from as i64,
+                            r.session_id as i64,
+                            r.upstream_ip,
+                            r.error,
+                            r.close_reason,
+                            r.duration_ms.map(|d| d as i64),
+                            r.instance_id
                         ])
                         .map_err(tokio_rusqlite::Error::from)?;
                 }
+                // Rollups are updated in the same transaction so they never
+                // disagree with the raw rows.
+                for (size, table) in ROLLUP_LEVELS {
+                    let mut stmt = tx
+                        .prepare(&rollup_upsert_sql(table))
+                        .map_err(tokio_rusqlite::Error::from)?;
+                    for r in rows_vec.iter() {
+                        stmt
+                            .execute(rusqlite::params![
+                                r.ts.div_euclid(*size) * size,
+                                r.name,
+                                r.client_addr.as_deref().unwrap_or(""),
+                                r.remote_address,
+                                r.remote_port as i64,
+                                r.bytes_from_to as i64,
+                                r.bytes_to_from as i64,
+                                (r.log_name == "connection_started") as i64,
+                                (r.log_name == "connection_error") as i64,
+                                (r.log_name == "connection_timeout") as i64
+                            ])
+                            .map_err(tokio_rusqlite::Error::from)?;
+                    }
+                }
             }
             tx.commit().map_err(tokio_rusqlite::Error::from)?;
             Ok(())
@@ -152,29 +842,93 @@ pub async fn insert_connection_rows(db: &SharedDb, rows: &[ConnectionRow]) -> an
     Ok(())
 }
 
-pub async fn query_traffic_by_client(
+/// Splits `[from, to)` so whole buckets of each rollup level are read from
+/// that level and only the partial edges fall back to the finer `edge` table.
+pub(crate) fn plan_segments(
+    from: i64,
+    to: i64,
+    levels: &[(i64, &'static str)],
+    edge: &'static str,
+    out: &mut Vec<(&'static str, i64, i64)>,
+) {
+    if from >= to {
+        return;
+    }
+    if let Some((&(size, table), coarser)) = levels.split_first() {
+        let inner_from = from + (size - from.rem_euclid(size)) % size;
+        let inner_to = to - to.rem_euclid(size);
+        if inner_from < inner_to {
+            plan_segments(from, inner_from, &[], edge, out);
+            plan_segments(inner_from, inner_to, coarser, table, out);
+            plan_segments(inner_to, to, &[], edge, out);
+            return;
+        }
+    }
+    out.push((edge, from, to));
+}
+
+/// Builds a subquery over `[start, end)` with the columns `ts, name,
+/// client_addr, remote_address, remote_port, bytes_from_to, bytes_to_from,
+/// sessions, errors, timeouts`, reading whole buckets from the given rollup
+/// levels and raw `connections` rows elsewhere.
+fn traffic_source(
+    start: i64,
+    end: i64,
+    levels: &[(i64, &'static str)],
+) -> (String, Vec<rusqlite::types::Value>) {
+    let mut segments = Vec::new();
+    plan_segments(start, end, levels, "connections", &mut segments);
+    let mut parts = Vec::with_capacity(segments.len());
+    let mut params = Vec::with_capacity(segments.len() * 2);
+    for (table, from, to) in segments {
+        parts.push(if table == "connections" {
+            "SELECT ts, name, client_addr, remote_address, remote_port,
+                    bytes_from_to, bytes_to_from,
+                    log_name = 'connection_started' AS sessions,
+                    log_name = 'connection_error' AS errors,
+                    log_name = 'connection_timeout' AS timeouts
+             FROM connections WHERE ts >= ? AND ts < ?"
+                .to_string()
+        } else {
+            format!(
+                "SELECT bucket AS ts, NULLIF(name, '') AS name,
+                        NULLIF(client_addr, '') AS client_addr,
+                        NULLIF(remote_address, '') AS remote_address, remote_port,
+                        bytes_from_to, bytes_to_from, sessions, errors, timeouts
+                 FROM {table} WHERE bucket >= ? AND bucket < ?"
+            )
+        });
+        params.push(from.into());
+        params.push(to.into());
+    }
+    (parts.join(" UNION ALL "), params)
+}
+
+pub async fn query_traffic_by_client_filtered(
     db: &SharedDb,
     start: DateTime<Utc>,
     end: DateTime<Utc>,
+    name: Option<String>,
 ) -> anyhow::Result<Vec<ClientTraffic>> {
-    let start_s: i64 = start.timestamp();
-    let end_s: i64 = end.timestamp();
+    let (source, mut params) = traffic_source(start.timestamp(), end.timestamp(), ROLLUP_LEVELS);
+    let name: rusqlite::types::Value = name.into();
+    params.push(name.clone());
+    params.push(name);
+    let sql = format!(
+        "SELECT client_addr,
+                COALESCE(SUM(bytes_from_to), 0) AS sum_from_to,
+                COALESCE(SUM(bytes_to_from), 0) AS sum_to_from
+         FROM ({source})
+         WHERE ? IS NULL OR name = ?
+         GROUP BY client_addr
+         ORDER BY sum_from_to + sum_to_from DESC"
+    );
     let result: Vec<ClientTraffic> = db
         .call(
             move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<Vec<ClientTraffic>> {
-                let mut stmt = c
-                    .prepare(
-                        "SELECT client_addr,
-                            COALESCE(SUM(bytes_from_to), 0) AS sum_from_to,
-                            COALESCE(SUM(bytes_to_from), 0) AS sum_to_from
-                     FROM connections
-                     WHERE ts >= ?1 AND ts < ?2
-                     GROUP BY client_addr
-                     ORDER BY sum_from_to + sum_to_from DESC",
-                    )
-                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut stmt = c.prepare(&sql).map_err(tokio_rusqlite::Error::from)?;
                 let mut rows = stmt
-                    .query(rusqlite::params![start_s, end_s])
+                    .query(rusqlite::params_from_iter(params))
                     .map_err(tokio_rusqlite::Error::from)?;
                 let mut out: Vec<ClientTraffic> = Vec::new();
                 while let Some(row) = rows.next().map_err(tokio_rusqlite::Error::from)? {
@@ -195,71 +949,264 @@ pub async fn query_traffic_by_client(
     Ok(result)
 }
 
-pub async fn query_traffic_by_client_filtered(
+/// Traffic in `[start, end)` grouped by `by`, the top `limit` groups by `top`.
+pub async fn query_traffic_breakdown(
+    db: &SharedDb,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    by: Breakdown,
+    top: TopBy,
+    filter: TrafficFilter,
+    limit: u32,
+) -> anyhow::Result<Vec<BreakdownRow>> {
+    let (source, mut params) = traffic_source(start.timestamp(), end.timestamp(), ROLLUP_LEVELS);
+    let name: rusqlite::types::Value = filter.name.into();
+    let client_addr: rusqlite::types::Value = filter.client_addr.into();
+    params.extend([name.clone(), name, client_addr.clone(), client_addr]);
+    params.push(limit.into());
+    let [name_col, client_col, upstream_col] = by.sql_columns();
+    let order = top.sql_order();
+    let sql = format!(
+        "SELECT {name_col}, {client_col}, {upstream_col},
+                SUM(bytes_from_to), SUM(bytes_to_from), SUM(sessions), SUM(errors), SUM(timeouts)
+         FROM ({source})
+         WHERE (? IS NULL OR name = ?)
+           AND (? IS NULL OR client_addr = ?)
+         GROUP BY 1, 2, 3
+         ORDER BY {order}
+         LIMIT ?"
+    );
+    let result = db
+        .call(
+            move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<Vec<BreakdownRow>> {
+                let mut stmt = c.prepare(&sql).map_err(tokio_rusqlite::Error::from)?;
+                let mut rows = stmt
+                    .query(rusqlite::params_from_iter(params))
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut out = Vec::new();
+                while let Some(row) = rows.next().map_err(tokio_rusqlite::Error::from)? {
+                    let get_u64 = |i: usize| -> tokio_rusqlite::Result<u64> {
+                        let v: i64 = row.get(i).map_err(tokio_rusqlite::Error::from)?;
+                        Ok(v.max(0) as u64)
+                    };
+                    out.push(BreakdownRow {
+                        name: row.get(0).map_err(tokio_rusqlite::Error::from)?,
+                        client_addr: row.get(1).map_err(tokio_rusqlite::Error::from)?,
+                        upstream: row.get(2).map_err(tokio_rusqlite::Error::from)?,
+                        bytes_from_to: get_u64(3)?,
+                        bytes_to_from: get_u64(4)?,
+                        connections: get_u64(5)?,
+                        errors: get_u64(6)?,
+                        timeouts: get_u64(7)?,
+                    });
+                }
+                Ok(out)
+            },
+        )
+        .await?;
+    Ok(result)
+}
+
+/// One session assembled from its started/closed/timeout/error rows.
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub session_id: u64,
+    /// Instance the session ran on; `None` for rows written before it was recorded.
+    pub instance_id: Option<String>,
+    pub started: i64,
+    pub closed: Option<i64>,
+    pub name: Option<String>,
+    /// Local port the client connected to; differs within a port range rule.
+    pub local_port: u16,
+    pub client_addr: Option<String>,
+    pub remote_address: Option<String>,
+    pub remote_port: u16,
+    pub upstream_ip: Option<String>,
+    pub bytes_from_to: u64,
+    pub bytes_to_from: u64,
+    pub duration_ms: Option<u64>,
+    pub close_reason: Option<String>,
+    /// `connection_error` if the upstream connect failed, `connection_timeout`
+    /// if it hit the idle timeout, otherwise `connection_closed` (or
+    /// `connection_started` while still open).
+    pub outcome: String,
+    pub error: Option<String>,
+}
+
+/// Number of failed sessions grouped by failure kind and message.
+#[derive(Clone, Debug, Serialize)]
+pub struct FailureReason {
+    pub log_name: String,
+    pub close_reason: Option<String>,
+    pub error: Option<String>,
+    pub count: u64,
+    pub last_ts: i64,
+}
+
+/// A raw `connections` row together with its id.
+#[derive(Clone, Debug)]
+pub struct StoredConnection {
+    pub id: i64,
+    pub row: ConnectionRow,
+}
+
+/// Up to `limit` raw rows in `[start, end)` ordered by `(ts, id)` and
+/// strictly after the `after` cursor. Walking the `ts` index keeps every
+/// page cheap regardless of how far into the range it is.
+pub async fn query_connections_page(
     db: &SharedDb,
     start: DateTime<Utc>,
     end: DateTime<Utc>,
     name: Option<String>,
-) -> anyhow::Result<Vec<ClientTraffic>> {
+    after: (i64, i64),
+    limit: u32,
+) -> anyhow::Result<Vec<StoredConnection>> {
     let start_s: i64 = start.timestamp();
     let end_s: i64 = end.timestamp();
-    let name_opt = name.clone();
-    let result: Vec<ClientTraffic> = db
+    let result = db
         .call(
-            move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<Vec<ClientTraffic>> {
-                let mut out: Vec<ClientTraffic> = Vec::new();
-                if let Some(name) = name_opt {
-                    let mut stmt = c
-                        .prepare(
-                            "SELECT client_addr,
-                                COALESCE(SUM(bytes_from_to), 0) AS sum_from_to,
-                                COALESCE(SUM(bytes_to_from), 0) AS sum_to_from
-                         FROM connections
-                         WHERE ts >= ?1 AND ts < ?2 AND name = ?3
-                         GROUP BY client_addr
-                         ORDER BY sum_from_to + sum_to_from DESC",
-                        )
-                        .map_err(tokio_rusqlite::Error::from)?;
-                    let mut rows = stmt
-                        .query(rusqlite::params![start_s, end_s, name])
-                        .map_err(tokio_rusqlite::Error::from)?;
-                    while let Some(row) = rows.next().map_err(tokio_rusqlite::Error::from)? {
-                        let client_addr: Option<String> =
-                            row.get(0).map_err(tokio_rusqlite::Error::from)?;
-                        let sum_from_to: i64 = row.get(1).map_err(tokio_rusqlite::Error::from)?;
-                        let sum_to_from: i64 = row.get(2).map_err(tokio_rusqlite::Error::from)?;
-                        out.push(ClientTraffic {
-                            client_addr,
-                            bytes_from_to: (sum_from_to.max(0)) as u64,
-                            bytes_to_from: (sum_to_from.max(0)) as u64,
-                        });
-                    }
-                } else {
-                    let mut stmt = c
-                        .prepare(
-                            "SELECT client_addr,
-                                COALESCE(SUM(bytes_from_to), 0) AS sum_from_to,
-                                COALESCE(SUM(bytes_to_from), 0) AS sum_to_from
+            move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<Vec<StoredConnection>> {
+                let mut stmt = c
+                    .prepare(
+                        "SELECT id, ts, name, log_name, local_port, remote_address, remote_port, client_addr,
+                            bytes_from_to, bytes_to_from, session_id, upstream_ip, error, close_reason, duration_ms,
+                            instance_id
+                     FROM connections
+                     WHERE ts >= ?1 AND ts < ?2 AND (ts, id) > (?3, ?4)
+                       AND (?5 IS NULL OR name = ?5)
+                     ORDER BY ts, id
+                     LIMIT ?6",
+                    )
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut rows = stmt
+                    .query(rusqlite::params![start_s, end_s, after.0, after.1, name, limit])
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut out = Vec::new();
+                while let Some(row) = rows.next().map_err(tokio_rusqlite::Error::from)? {
+                    let get_i64 = |i: usize| -> tokio_rusqlite::Result<i64> {
+                        let v: Option<i64> = row.get(i).map_err(tokio_rusqlite::Error::from)?;
+                        Ok(v.unwrap_or(0))
+                    };
+                    let get_text = |i: usize| -> tokio_rusqlite::Result<Option<String>> {
+                        row.get(i).map_err(tokio_rusqlite::Error::from)
+                    };
+                    let duration_ms: Option<i64> =
+                        row.get(14).map_err(tokio_rusqlite::Error::from)?;
+                    out.push(StoredConnection {
+                        id: get_i64(0)?,
+                        row: ConnectionRow {
+                            ts: get_i64(1)?,
+                            name: get_text(2)?.unwrap_or_default(),
+                            log_name: get_text(3)?.unwrap_or_default(),
+                            local_port: get_i64(4)? as u16,
+                            remote_address: get_text(5)?.unwrap_or_default(),
+                            remote_port: get_i64(6)? as u16,
+                            client_addr: get_text(7)?,
+                            bytes_from_to: get_i64(8)?.max(0) as u64,
+                            bytes_to_from: get_i64(9)?.max(0) as u64,
+                            session_id: get_i64(10)? as u64,
+                            instance_id: get_text(15)?,
+                            upstream_ip: get_text(11)?,
+                            error: get_text(12)?,
+                            close_reason: get_text(13)?,
+                            duration_ms: duration_ms.map(|d| d.max(0) as u64),
+                        },
+                    });
+                }
+                Ok(out)
+            },
+        )
+        .await?;
+    Ok(result)
+}
+
+/// Sessions in `[start, end)` (by first row), newest first. With
+/// `failed_only`, only sessions that ended with an error or timeout.
+pub async fn query_sessions(
+    db: &SharedDb,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    filter: TrafficFilter,
+    failed_only: bool,
+    limit: u32,
+) -> anyhow::Result<Vec<SessionInfo>> {
+    let start_s: i64 = start.timestamp();
+    let end_s: i64 = end.timestamp();
+    let result = db
+        .call(
+            move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<Vec<SessionInfo>> {
+                let mut stmt = c
+                    .prepare(
+                        "WITH wanted AS (
+                         SELECT DISTINCT COALESCE(instance_id, '') AS instance_id, session_id
                          FROM connections
-                         WHERE ts >= ?1 AND ts < ?2
-                         GROUP BY client_addr
-                         ORDER BY sum_from_to + sum_to_from DESC",
-                        )
-                        .map_err(tokio_rusqlite::Error::from)?;
-                    let mut rows = stmt
-                        .query(rusqlite::params![start_s, end_s])
-                        .map_err(tokio_rusqlite::Error::from)?;
-                    while let Some(row) = rows.next().map_err(tokio_rusqlite::Error::from)? {
-                        let client_addr: Option<String> =
-                            row.get(0).map_err(tokio_rusqlite::Error::from)?;
-                        let sum_from_to: i64 = row.get(1).map_err(tokio_rusqlite::Error::from)?;
-                        let sum_to_from: i64 = row.get(2).map_err(tokio_rusqlite::Error::from)?;
-                        out.push(ClientTraffic {
-                            client_addr,
-                            bytes_from_to: (sum_from_to.max(0)) as u64,
-                            bytes_to_from: (sum_to_from.max(0)) as u64,
-                        });
-                    }
+                         WHERE ts >= ?1 AND ts < ?2 AND session_id IS NOT NULL
+                           AND (?3 IS NULL OR name = ?3)
+                           AND (?4 IS NULL OR client_addr = ?4)
+                     )
+                     SELECT w.session_id,
+                            MIN(ts),
+                            MAX(CASE WHEN log_name IN ('connection_closed', 'connection_error') THEN ts END),
+                            MAX(name), MAX(client_addr), MAX(remote_address), MAX(remote_port),
+                            MAX(upstream_ip),
+                            COALESCE(SUM(bytes_from_to), 0), COALESCE(SUM(bytes_to_from), 0),
+                            MAX(duration_ms), MAX(close_reason),
+                            CASE
+                                WHEN SUM(log_name = 'connection_error') > 0 THEN 'connection_error'
+                                WHEN SUM(log_name = 'connection_timeout') > 0 THEN 'connection_timeout'
+                                WHEN SUM(log_name = 'connection_closed') > 0 THEN 'connection_closed'
+                                ELSE 'connection_started'
+                            END AS outcome,
+                            MAX(error), MAX(local_port), NULLIF(w.instance_id, '')
+                     FROM wanted w
+                     JOIN connections c ON c.session_id = w.session_id
+                         AND COALESCE(c.instance_id, '') = w.instance_id
+                     GROUP BY w.instance_id, w.session_id
+                     HAVING ?5 = 0 OR outcome IN ('connection_error', 'connection_timeout')
+                        OR MAX(close_reason) = 'io_error'
+                     ORDER BY MIN(ts) DESC, w.session_id DESC
+                     LIMIT ?6",
+                    )
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut rows = stmt
+                    .query(rusqlite::params![
+                        start_s,
+                        end_s,
+                        filter.name,
+                        filter.client_addr,
+                        failed_only,
+                        limit
+                    ])
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut out = Vec::new();
+                while let Some(row) = rows.next().map_err(tokio_rusqlite::Error::from)? {
+                    let session_id: i64 = row.get(0).map_err(tokio_rusqlite::Error::from)?;
+                    let remote_port: Option<i64> =
+                        row.get(6).map_err(tokio_rusqlite::Error::from)?;
+                    let sum_from_to: i64 = row.get(8).map_err(tokio_rusqlite::Error::from)?;
+                    let sum_to_from: i64 = row.get(9).map_err(tokio_rusqlite::Error::from)?;
+                    let duration_ms: Option<i64> =
+                        row.get(10).map_err(tokio_rusqlite::Error::from)?;
+                    let local_port: Option<i64> =
+                        row.get(14).map_err(tokio_rusqlite::Error::from)?;
+                    out.push(SessionInfo {
+                        session_id: session_id as u64,
+                        instance_id: row.get(15).map_err(tokio_rusqlite::Error::from)?,
+                        started: row.get(1).map_err(tokio_rusqlite::Error::from)?,
+                        closed: row.get(2).map_err(tokio_rusqlite::Error::from)?,
+                        name: row.get(3).map_err(tokio_rusqlite::Error::from)?,
+                        local_port: local_port.unwrap_or(0) as u16,
+                        client_addr: row.get(4).map_err(tokio_rusqlite::Error::from)?,
+                        remote_address: row.get(5).map_err(tokio_rusqlite::Error::from)?,
+                        remote_port: remote_port.unwrap_or(0) as u16,
+                        upstream_ip: row.get(7).map_err(tokio_rusqlite::Error::from)?,
+                        bytes_from_to: sum_from_to.max(0) as u64,
+                        bytes_to_from: sum_to_from.max(0) as u64,
+                        duration_ms: duration_ms.map(|d| d.max(0) as u64),
+                        close_reason: row.get(11).map_err(tokio_rusqlite::Error::from)?,
+                        outcome: row.get(12).map_err(tokio_rusqlite::Error::from)?,
+                        error: row.get(13).map_err(tokio_rusqlite::Error::from)?,
+                    });
                 }
                 Ok(out)
             },
@@ -267,3 +1214,829 @@ pub async fn query_traffic_by_client_filtered(
         .await?;
     Ok(result)
 }
+
+/// Why sessions fail: errors, timeouts and I/O-error closes in `[start, end)`
+/// grouped by kind and message, most frequent first.
+pub async fn query_failure_reasons(
+    db: &SharedDb,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    filter: TrafficFilter,
+) -> anyhow::Result<Vec<FailureReason>> {
+    let start_s: i64 = start.timestamp();
+    let end_s: i64 = end.timestamp();
+    let result = db
+        .call(
+            move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<Vec<FailureReason>> {
+                let mut stmt = c
+                    .prepare(
+                        "SELECT log_name, close_reason, error, COUNT(*), MAX(ts)
+                     FROM connections
+                     WHERE ts >= ?1 AND ts < ?2
+                       AND (log_name IN ('connection_error', 'connection_timeout')
+                            OR close_reason = 'io_error')
+                       AND (?3 IS NULL OR name = ?3)
+                       AND (?4 IS NULL OR client_addr = ?4)
+                     GROUP BY log_name, close_reason, error
+                     ORDER BY COUNT(*) DESC",
+                    )
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut rows = stmt
+                    .query(rusqlite::params![
+                        start_s,
+                        end_s,
+                        filter.name,
+                        filter.client_addr
+                    ])
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut out = Vec::new();
+                while let Some(row) = rows.next().map_err(tokio_rusqlite::Error::from)? {
+                    let count: i64 = row.get(3).map_err(tokio_rusqlite::Error::from)?;
+                    out.push(FailureReason {
+                        log_name: row.get(0).map_err(tokio_rusqlite::Error::from)?,
+                        close_reason: row.get(1).map_err(tokio_rusqlite::Error::from)?,
+                        error: row.get(2).map_err(tokio_rusqlite::Error::from)?,
+                        count: count.max(0) as u64,
+                        last_ts: row.get(4).map_err(tokio_rusqlite::Error::from)?,
+                    });
+                }
+                Ok(out)
+            },
+        )
+        .await?;
+    Ok(result)
+}
+
+/// Rejects time series requests that are malformed or would be too large.
+pub fn check_timeseries_range(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    step_secs: i64,
+) -> Result<(), String> {
+    if step_secs < 60 {
+        return Err("step must be at least 60 seconds".to_string());
+    }
+    if end <= start {
+        return Err("end must be after start".to_string());
+    }
+    if (end - start).num_seconds() / step_secs > MAX_TIMESERIES_BUCKETS {
+        return Err(format!(
+            "too many buckets, use a larger step (max {})",
+            MAX_TIMESERIES_BUCKETS
+        ));
+    }
+    Ok(())
+}
+
+/// Pre-aggregated traffic for one UTC slot and group key.
+pub(crate) struct SlotRow {
+    pub slot: i64,
+    pub key: Option<String>,
+    pub bytes_from_to: i64,
+    pub bytes_to_from: i64,
+    pub connections: i64,
+}
+
+/// Converts a local wall-clock time to UTC. Ambiguous times (DST fall back)
+/// resolve to the earlier instant; times inside a DST gap move forward to the
+/// first valid 15-minute mark.
+pub(crate) fn resolve_local(tz: Tz, naive: NaiveDateTime) -> DateTime<Tz> {
+    let mut candidate = naive;
+    for _ in 0..16 {
+        match tz.from_local_datetime(&candidate) {
+            LocalResult::Single(t) => return t,
+            LocalResult::Ambiguous(earliest, _) => return earliest,
+            LocalResult::None => candidate += Duration::minutes(15),
+        }
+    }
+    tz.from_utc_datetime(&naive)
+}
+
+pub(crate) fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Rollup levels whose UTC buckets fall entirely inside one local bucket of
+/// `step_secs` in `tz` for the whole range; others would smear traffic across
+/// neighbouring buckets.
+pub(crate) fn usable_rollup_levels(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    step_secs: i64,
+    tz: Tz,
+) -> Vec<(i64, &'static str)> {
+    // Offsets are sampled daily; real zones never change offset twice a day.
+    let mut offsets = Vec::new();
+    let mut t = start;
+    while t < end {
+        offsets.push(
+            tz.offset_from_utc_datetime(&t.naive_utc())
+                .fix()
+                .local_minus_utc() as i64,
+        );
+        t += Duration::days(1);
+    }
+    offsets.push(
+        tz.offset_from_utc_datetime(&end.naive_utc())
+            .fix()
+            .local_minus_utc() as i64,
+    );
+    ROLLUP_LEVELS
+        .iter()
+        .copied()
+        .filter(|(size, _)| {
+            step_secs % size == 0 && offsets.iter().all(|offset| offset % size == 0)
+        })
+        .collect()
+}
+
+/// Groups bytes and connection counts into buckets of `step_secs` whose
+/// boundaries follow the local wall clock of `tz` (so `86400` means local
+/// midnight to midnight, including 23/25-hour DST days).
+///
+/// SQLite pre-aggregates into UTC slots of `gcd(step, 15 min)`; every real
+/// timezone offset is a multiple of 15 minutes, so each slot falls entirely
+/// into one local bucket and is mapped there in Rust. Rollups are used only
+/// when their buckets align with the local ones.
+pub async fn query_traffic_timeseries(
+    db: &SharedDb,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    step_secs: i64,
+    tz: Tz,
+    group_by: Option<GroupBy>,
+    filter: TrafficFilter,
+) -> anyhow::Result<Vec<TimeseriesPoint>> {
+    check_timeseries_range(start, end, step_secs).map_err(anyhow::Error::msg)?;
+    let start_s: i64 = start.timestamp();
+    let end_s: i64 = end.timestamp();
+    let slot = gcd(step_secs, 900);
+    let levels = usable_rollup_levels(start, end, step_secs, tz);
+    let (source, source_params) = traffic_source(start_s, end_s, &levels);
+    let name: rusqlite::types::Value = filter.name.into();
+    let client_addr: rusqlite::types::Value = filter.client_addr.into();
+    // Positional parameters, in the order they appear in the SQL text.
+    let mut params: Vec<rusqlite::types::Value> = vec![slot.into(), slot.into()];
+    params.extend(source_params);
+    params.extend([name.clone(), name, client_addr.clone(), client_addr]);
+    let key_expr = group_by.map(GroupBy::sql_expr).unwrap_or("NULL");
+    let sql = format!(
+        "SELECT (ts / ?) * ? AS slot,
+                {key_expr} AS key,
+                COALESCE(SUM(bytes_from_to), 0),
+                COALESCE(SUM(bytes_to_from), 0),
+                COALESCE(SUM(sessions), 0)
+         FROM ({source})
+         WHERE (? IS NULL OR name = ?)
+           AND (? IS NULL OR client_addr = ?)
+         GROUP BY slot, key"
+    );
+    let slots: Vec<SlotRow> = db
+        .call(
+            move |c: &mut rusqlite::Connection| -> tokio_rusqlite::Result<Vec<SlotRow>> {
+                let mut stmt = c.prepare(&sql).map_err(tokio_rusqlite::Error::from)?;
+                let mut rows = stmt
+                    .query(rusqlite::params_from_iter(params))
+                    .map_err(tokio_rusqlite::Error::from)?;
+                let mut out = Vec::new();
+                while let Some(row) = rows.next().map_err(tokio_rusqlite::Error::from)? {
+                    out.push(SlotRow {
+                        slot: row.get(0).map_err(tokio_rusqlite::Error::from)?,
+                        key: row.get(1).map_err(tokio_rusqlite::Error::from)?,
+                        bytes_from_to: row.get(2).map_err(tokio_rusqlite::Error::from)?,
+                        bytes_to_from: row.get(3).map_err(tokio_rusqlite::Error::from)?,
+                        connections: row.get(4).map_err(tokio_rusqlite::Error::from)?,
+                    });
+                }
+                Ok(out)
+            },
+        )
+        .await?;
+    Ok(slots_to_points(slots, step_secs, tz))
+}
+
+/// Maps UTC slots onto local wall-clock buckets of `step_secs` in `tz`.
+pub(crate) fn slots_to_points(slots: Vec<SlotRow>, step_secs: i64, tz: Tz) -> Vec<TimeseriesPoint> {
+    // (local bucket start, key) -> (bytes_from_to, bytes_to_from, connections)
+    let mut buckets: BTreeMap<(NaiveDateTime, Option<String>), (u64, u64, u64)> = BTreeMap::new();
+    for row in slots {
+        let Some(slot_utc) = DateTime::from_timestamp(row.slot, 0) else {
+            continue;
+        };
+        let local = slot_utc.with_timezone(&tz).naive_local();
+        let local_secs = local.and_utc().timestamp();
+        let bucket_secs = local_secs - local_secs.rem_euclid(step_secs);
+        let Some(bucket_local) = DateTime::from_timestamp(bucket_secs, 0) else {
+            continue;
+        };
+        let entry = buckets
+            .entry((bucket_local.naive_utc(), row.key))
+            .or_insert((0, 0, 0));
+        entry.0 += row.bytes_from_to.max(0) as u64;
+        entry.1 += row.bytes_to_from.max(0) as u64;
+        entry.2 += row.connections.max(0) as u64;
+    }
+
+    buckets
+        .into_iter()
+        .map(
+            |((naive, key), (bytes_from_to, bytes_to_from, connections))| {
+                let bucket = resolve_local(tz, naive);
+                TimeseriesPoint {
+                    bucket: bucket.to_rfc3339(),
+                    bucket_ts: bucket.timestamp(),
+                    key,
+                    bytes_from_to,
+                    bytes_to_from,
+                    connections,
+                }
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_TS: i64 = 1_700_000_000;
+
+    /// A database as an older binary left it: the first `version` migrations,
+    /// recorded in `schema_migrations` unless `version` is 0 (pre-versioning,
+    /// where only the initial tables exist).
+    fn database_at(version: usize) -> rusqlite::Connection {
+        let c = rusqlite::Connection::open_in_memory().unwrap();
+        if version == 0 {
+            c.execute_batch(MIGRATIONS[0]).unwrap();
+            return c;
+        }
+        c.execute_batch(
+            "CREATE TABLE schema_migrations (version INTEGER PRIMARY KEY, applied_at INTEGER NOT NULL)",
+        )
+        .unwrap();
+        for (index, sql) in MIGRATIONS[..version].iter().enumerate() {
+            c.execute_batch(sql).unwrap();
+            c.execute(
+                "INSERT INTO schema_migrations (version, applied_at) VALUES (?1, 0)",
+                [index as i64 + 1],
+            )
+            .unwrap();
+        }
+        c
+    }
+
+    fn insert_old_rows(c: &rusqlite::Connection) {
+        let has_rollups: bool = c
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM sqlite_master WHERE name = 'traffic_hourly'",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        for (offset, log_name, from_to, to_from) in [
+            (0, "connection_started", 0, 0),
+            (10, "connection_closed", 100, 200),
+            (20, "connection_error", 0, 0),
+            (4000, "connection_timeout", 5, 7),
+        ] {
+            c.execute(
+                "INSERT INTO connections (ts, name, log_name, local_port, remote_address,
+                     remote_port, client_addr, bytes_from_to, bytes_to_from)
+                 VALUES (?1, 'pg', ?2, 6432, 'db.internal', 5432, '10.0.0.1', ?3, ?4)",
+                rusqlite::params![HOUR_TS + offset, log_name, from_to, to_from],
+            )
+            .unwrap();
+            // Binaries that already had rollups kept them up to date on insert.
+            if has_rollups {
+                for (size, table) in ROLLUP_LEVELS {
+                    c.execute(
+                        &rollup_upsert_sql(table),
+                        rusqlite::params![
+                            (HOUR_TS + offset).div_euclid(*size) * size,
+                            "pg",
+                            "10.0.0.1",
+                            "db.internal",
+                            5432,
+                            from_to,
+                            to_from,
+                            (log_name == "connection_started") as i64,
+                            (log_name == "connection_error") as i64,
+                            (log_name == "connection_timeout") as i64
+                        ],
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    }
+
+    fn version(c: &rusqlite::Connection) -> i64 {
+        c.query_row("SELECT MAX(version) FROM schema_migrations", [], |r| {
+            r.get(0)
+        })
+        .unwrap()
+    }
+
+    fn assert_migrated(c: &mut rusqlite::Connection, from: i64) {
+        let outcome = migrate(c).unwrap();
+        assert!(
+            matches!(outcome, MigrationOutcome::Applied { from: f, to } if f == from && to == SCHEMA_VERSION)
+        );
+        assert_eq!(version(c), SCHEMA_VERSION);
+
+        let rows: i64 = c
+            .query_row("SELECT COUNT(*) FROM connections", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(rows, 4);
+        // Session columns exist and are NULL for rows written before them.
+        let sessions: i64 = c
+            .query_row(
+                "SELECT COUNT(*) FROM connections WHERE session_id IS NULL
+                     AND upstream_ip IS NULL AND error IS NULL
+                     AND close_reason IS NULL AND duration_ms IS NULL
+                     AND instance_id IS NULL",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(sessions, 4);
+
+        let hour = (HOUR_TS / 3600) * 3600;
+        let hourly: Vec<(i64, i64, i64, i64, i64, i64)> = c
+            .prepare(
+                "SELECT bucket, bytes_from_to, bytes_to_from, sessions, errors, timeouts
+                 FROM traffic_hourly WHERE name = 'pg' ORDER BY bucket",
+            )
+            .unwrap()
+            .query_map([], |r| {
+                Ok((
+                    r.get(0)?,
+                    r.get(1)?,
+                    r.get(2)?,
+                    r.get(3)?,
+                    r.get(4)?,
+                    r.get(5)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            hourly,
+            vec![(hour, 100, 200, 1, 1, 0), (hour + 3600, 5, 7, 0, 0, 1)]
+        );
+        let daily: (i64, i64, i64, i64, i64) = c
+            .query_row(
+                "SELECT bytes_from_to, bytes_to_from, sessions, errors, timeouts
+                 FROM traffic_daily WHERE bucket = ?1",
+                [(HOUR_TS / 86400) * 86400],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+            )
+            .unwrap();
+        assert_eq!(daily, (105, 207, 1, 1, 1));
+
+        assert!(matches!(
+            migrate(c).unwrap(),
+            MigrationOutcome::UpToDate(SCHEMA_VERSION)
+        ));
+    }
+
+    /// Every older schema, including the pre-versioning one (0), upgrades
+    /// to the current version and keeps its rows and rollups.
+    #[test]
+    fn migrates_every_older_version() {
+        for from in 0..SCHEMA_VERSION {
+            let mut c = database_at(from as usize);
+            insert_old_rows(&c);
+            assert_migrated(&mut c, from);
+        }
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let mut c = database_at(SCHEMA_VERSION as usize);
+        insert_old_rows(&c);
+        c.execute(
+            "INSERT INTO schema_migrations (version, applied_at) VALUES (?1, 0)",
+            [SCHEMA_VERSION + 1],
+        )
+        .unwrap();
+        assert!(matches!(
+            migrate(&mut c).unwrap(),
+            MigrationOutcome::TooNew(found) if found == SCHEMA_VERSION + 1
+        ));
+        assert_eq!(version(&c), SCHEMA_VERSION + 1);
+    }
+
+    #[tokio::test]
+    async fn open_existing_neither_creates_nor_migrates() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = SqliteOptions::default();
+
+        let missing = dir.path().join("missing.db");
+        let missing = missing.to_str().unwrap();
+        let err = open_existing(missing, &options).await.unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+        assert!(!Path::new(missing).exists());
+
+        let old = dir.path().join("old.db");
+        {
+            let c = database_at(2);
+            insert_old_rows(&c);
+            c.execute("VACUUM INTO ?1", [old.to_str().unwrap()])
+                .unwrap();
+        }
+        let old = old.to_str().unwrap();
+        let err = open_existing(old, &options).await.unwrap_err();
+        let mismatch = err.downcast_ref::<SchemaMismatch>().unwrap();
+        assert_eq!((mismatch.found, mismatch.expected), (2, SCHEMA_VERSION));
+        assert_eq!(version(&rusqlite::Connection::open(old).unwrap()), 2);
+
+        let current = dir.path().join("current.db");
+        database_at(SCHEMA_VERSION as usize)
+            .execute("VACUUM INTO ?1", [current.to_str().unwrap()])
+            .unwrap();
+        let current = current.to_str().unwrap();
+        let readers = open_existing(current, &options).await.unwrap();
+        let mode: String = readers[0]
+            .call(|c: &mut rusqlite::Connection| {
+                c.query_row("PRAGMA journal_mode", [], |r| r.get(0))
+                    .map_err(tokio_rusqlite::Error::from)
+            })
+            .await
+            .unwrap();
+        assert_eq!(mode, "delete");
+        let insert =
+            insert_connection_rows(&readers[0], &[row_at(HOUR_TS, "connection_closed")]).await;
+        assert!(insert.is_err());
+    }
+
+    #[test]
+    fn timeseries_range_limits() {
+        let start = Utc.timestamp_opt(HOUR_TS, 0).unwrap();
+        let day = start + Duration::days(1);
+        assert_eq!(check_timeseries_range(start, day, 3600), Ok(()));
+        assert_eq!(check_timeseries_range(start, day, 60), Ok(()));
+        assert!(check_timeseries_range(start, day, 59)
+            .unwrap_err()
+            .contains("at least 60"));
+        assert!(check_timeseries_range(start, start, 3600)
+            .unwrap_err()
+            .contains("after start"));
+        assert!(check_timeseries_range(day, start, 3600).is_err());
+        // Exactly MAX_TIMESERIES_BUCKETS buckets is allowed, one more is not.
+        let limit = start + Duration::minutes(MAX_TIMESERIES_BUCKETS);
+        assert_eq!(check_timeseries_range(start, limit, 60), Ok(()));
+        assert!(
+            check_timeseries_range(start, limit + Duration::minutes(1), 60)
+                .unwrap_err()
+                .contains("too many buckets")
+        );
+    }
+
+    #[tokio::test]
+    async fn session_details_are_stored_and_read_back() {
+        let ts = Utc.timestamp_opt(HOUR_TS, 0).unwrap();
+        let events = [
+            LogEvent::ConnectionStarted {
+                ts,
+                session_id: 42,
+                name: "pg".to_string(),
+                local_port: 6432,
+                remote_address: "db.internal".to_string(),
+                remote_port: 5432,
+                client_addr: Some("10.0.0.1".to_string()),
+                upstream_ip: Some("192.0.2.10".to_string()),
+            },
+            LogEvent::ConnectionClosed {
+                ts: ts + Duration::seconds(2),
+                session_id: 42,
+                name: "pg".to_string(),
+                local_port: 6432,
+                remote_address: "db.internal".to_string(),
+                remote_port: 5432,
+                client_addr: Some("10.0.0.1".to_string()),
+                upstream_ip: Some("192.0.2.10".to_string()),
+                bytes_from_to: 100,
+                bytes_to_from: 200,
+                duration_ms: 1500,
+                close_reason: crate::events::CloseReason::IoError,
+                error: Some("connection reset".to_string()),
+            },
+            LogEvent::ConnectionError {
+                ts: ts + Duration::seconds(3),
+                session_id: 43,
+                name: "pg".to_string(),
+                local_port: 6432,
+                remote_address: "db.internal".to_string(),
+                remote_port: 5432,
+                client_addr: None,
+                error: "connection refused".to_string(),
+            },
+            LogEvent::RuleOpened {
+                ts,
+                name: "pg".to_string(),
+                next_change: None,
+            },
+        ];
+        // Rule events are not stored.
+        let rows: Vec<ConnectionRow> = events
+            .into_iter()
+            .filter_map(ConnectionRow::from_event)
+            .collect();
+        assert_eq!(rows.len(), 3);
+
+        let db = init_db(":memory:", &SqliteOptions::default())
+            .await
+            .unwrap();
+        insert_connection_rows(&db, &rows).await.unwrap();
+        let (start, end) = (ts, ts + Duration::hours(1));
+
+        let stored = query_connections_page(&db, start, end, None, (i64::MIN, 0), 10)
+            .await
+            .unwrap();
+        let stored: Vec<&ConnectionRow> = stored.iter().map(|s| &s.row).collect();
+        assert_eq!(stored.len(), 3);
+        let closed = stored[1];
+        assert_eq!(closed.log_name, "connection_closed");
+        assert_eq!(closed.session_id, 42);
+        assert_eq!(closed.upstream_ip.as_deref(), Some("192.0.2.10"));
+        assert_eq!(closed.close_reason.as_deref(), Some("io_error"));
+        assert_eq!(closed.duration_ms, Some(1500));
+        assert_eq!(closed.error.as_deref(), Some("connection reset"));
+        assert_eq!((closed.bytes_from_to, closed.bytes_to_from), (100, 200));
+        let failed = stored[2];
+        assert_eq!(failed.log_name, "connection_error");
+        assert_eq!(failed.error.as_deref(), Some("connection refused"));
+        assert_eq!(
+            (
+                &failed.upstream_ip,
+                &failed.close_reason,
+                failed.duration_ms
+            ),
+            (&None, &None, None)
+        );
+
+        let sessions = query_sessions(&db, start, end, TrafficFilter::default(), false, 10)
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 2);
+        let (refused, reset) = (&sessions[0], &sessions[1]);
+        assert_eq!(reset.session_id, 42);
+        assert_eq!(reset.outcome, "connection_closed");
+        assert_eq!((reset.started, reset.closed), (HOUR_TS, Some(HOUR_TS + 2)));
+        assert_eq!(reset.upstream_ip.as_deref(), Some("192.0.2.10"));
+        assert_eq!(reset.close_reason.as_deref(), Some("io_error"));
+        assert_eq!(reset.duration_ms, Some(1500));
+        assert_eq!(reset.error.as_deref(), Some("connection reset"));
+        assert_eq!(refused.session_id, 43);
+        assert_eq!(refused.outcome, "connection_error");
+        assert_eq!(refused.error.as_deref(), Some("connection refused"));
+        assert_eq!(refused.upstream_ip, None);
+
+        // Both count as failed: one never connected, one closed on an I/O error.
+        let failed = query_sessions(&db, start, end, TrafficFilter::default(), true, 10)
+            .await
+            .unwrap();
+        assert_eq!(failed.len(), 2);
+
+        let mut reasons = query_failure_reasons(&db, start, end, TrafficFilter::default())
+            .await
+            .unwrap();
+        reasons.sort_by_key(|r| r.last_ts);
+        let reasons: Vec<_> = reasons
+            .iter()
+            .map(|r| {
+                (
+                    r.log_name.as_str(),
+                    r.close_reason.as_deref(),
+                    r.error.as_deref(),
+                    r.count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (
+                    "connection_closed",
+                    Some("io_error"),
+                    Some("connection reset"),
+                    1
+                ),
+                ("connection_error", None, Some("connection refused"), 1),
+            ]
+        );
+    }
+
+    fn row_at(ts: i64, log_name: &str) -> ConnectionRow {
+        ConnectionRow {
+            ts,
+            name: "pg".to_string(),
+            log_name: log_name.to_string(),
+            local_port: 6432,
+            remote_address: "db.internal".to_string(),
+            remote_port: 5432,
+            client_addr: Some("10.0.0.1".to_string()),
+            bytes_from_to: 10,
+            bytes_to_from: 20,
+            session_id: ts as u64,
+            instance_id: Some("a".to_string()),
+            upstream_ip: None,
+            error: None,
+            close_reason: None,
+            duration_ms: None,
+        }
+    }
+
+    async fn count(db: &SharedDb, table: &'static str) -> i64 {
+        db.call(move |c: &mut rusqlite::Connection| {
+            c.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |r| r.get(0))
+                .map_err(tokio_rusqlite::Error::from)
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn prune_deletes_old_rows_in_batches() {
+        let db = init_db(":memory:", &SqliteOptions::default())
+            .await
+            .unwrap();
+        let now = Utc::now().timestamp();
+        // All old rows fall into one hourly and one daily bucket.
+        let old = (now - 10 * 86400).div_euclid(86400) * 86400;
+        let mut rows: Vec<ConnectionRow> = (0..PRUNE_BATCH + 3)
+            .map(|i| row_at(old + i % 60, "connection_closed"))
+            .collect();
+        rows.push(row_at(now, "connection_started"));
+        rows.push(row_at(now, "connection_closed"));
+        insert_connection_rows(&db, &rows).await.unwrap();
+
+        let report = prune(
+            &db,
+            RetentionPolicy {
+                raw_days: Some(1),
+                rollup_days: Some(30),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.raw_rows, PRUNE_BATCH as u64 + 3);
+        assert_eq!(report.rollup_rows, 0);
+        assert_eq!(count(&db, "connections").await, 2);
+        // Rollups outlive the raw rows they were built from.
+        assert_eq!(count(&db, "traffic_hourly").await, 2);
+
+        let report = prune(
+            &db,
+            RetentionPolicy {
+                raw_days: None,
+                rollup_days: Some(5),
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.raw_rows, 0);
+        assert_eq!(report.rollup_rows, 2);
+        assert_eq!(count(&db, "traffic_hourly").await, 1);
+        assert_eq!(count(&db, "connections").await, 2);
+    }
+
+    fn segments(from: i64, to: i64) -> Vec<(&'static str, i64, i64)> {
+        let mut out = Vec::new();
+        plan_segments(from, to, ROLLUP_LEVELS, "connections", &mut out);
+        out
+    }
+
+    #[test]
+    fn segments_use_whole_buckets() {
+        const H: i64 = 3600;
+        const D: i64 = 86400;
+        // Shorter than an hour: raw rows only.
+        assert_eq!(segments(100, 200), vec![("connections", 100, 200)]);
+        assert_eq!(segments(200, 200), vec![]);
+        // Exactly one hour.
+        assert_eq!(segments(H, 2 * H), vec![("traffic_hourly", H, 2 * H)]);
+        // Partial edges around whole hours.
+        assert_eq!(
+            segments(H - 10, 3 * H + 5),
+            vec![
+                ("connections", H - 10, H),
+                ("traffic_hourly", H, 3 * H),
+                ("connections", 3 * H, 3 * H + 5),
+            ]
+        );
+        // Whole days inside whole hours inside raw edges.
+        assert_eq!(
+            segments(D - H - 1, 3 * D + 2 * H + 1),
+            vec![
+                ("connections", D - H - 1, D - H),
+                ("traffic_hourly", D - H, D),
+                ("traffic_daily", D, 3 * D),
+                ("traffic_hourly", 3 * D, 3 * D + 2 * H),
+                ("connections", 3 * D + 2 * H, 3 * D + 2 * H + 1),
+            ]
+        );
+        // Negative timestamps align the same way.
+        assert_eq!(
+            segments(-H - 1, H),
+            vec![("connections", -H - 1, -H), ("traffic_hourly", -H, H)]
+        );
+    }
+
+    #[test]
+    fn segments_cover_range_without_gaps() {
+        for (from, to) in [(7, 200_000), (3599, 3601), (86_399, 259_201), (0, 86_400)] {
+            let parts = segments(from, to);
+            assert_eq!(parts.first().unwrap().1, from);
+            assert_eq!(parts.last().unwrap().2, to);
+            for pair in parts.windows(2) {
+                assert_eq!(pair[0].2, pair[1].1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_of_different_instances_stay_apart() {
+        let db = init_db(":memory:", &SqliteOptions::default())
+            .await
+            .unwrap();
+        let mut rows = Vec::new();
+        for instance in ["a", "b"] {
+            for log_name in ["connection_started", "connection_closed"] {
+                let mut row = row_at(HOUR_TS, log_name);
+                row.session_id = 7;
+                row.instance_id = Some(instance.to_string());
+                rows.push(row);
+            }
+        }
+        // A row from before instance ids were recorded.
+        let mut old = row_at(HOUR_TS - 10, "connection_closed");
+        old.session_id = 7;
+        old.instance_id = None;
+        rows.push(old);
+        insert_connection_rows(&db, &rows).await.unwrap();
+
+        let start = Utc.timestamp_opt(HOUR_TS - 60, 0).unwrap();
+        let end = Utc.timestamp_opt(HOUR_TS + 60, 0).unwrap();
+        let mut sessions = query_sessions(&db, start, end, TrafficFilter::default(), false, 10)
+            .await
+            .unwrap();
+        sessions.sort_by(|a, b| a.instance_id.cmp(&b.instance_id));
+        let got: Vec<(Option<&str>, u64, u64)> = sessions
+            .iter()
+            .map(|s| (s.instance_id.as_deref(), s.session_id, s.bytes_from_to))
+            .collect();
+        assert_eq!(
+            got,
+            vec![(None, 7, 10), (Some("a"), 7, 20), (Some("b"), 7, 20)]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn backup_is_private_and_cleaned_up() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("stats.db");
+        let src = src.to_str().unwrap();
+        let db = init_db(src, &SqliteOptions::default()).await.unwrap();
+        insert_connection_rows(&db, &[row_at(HOUR_TS, "connection_closed")])
+            .await
+            .unwrap();
+        let busy = std::time::Duration::from_secs(1);
+
+        let tmp = backup_to_temp(src, dir.path(), busy).unwrap();
+        assert_eq!(tmp.parent(), Some(dir.path()));
+        let mode = std::fs::metadata(&tmp).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let copy = rusqlite::Connection::open(&tmp).unwrap();
+        let rows: i64 = copy
+            .query_row("SELECT COUNT(*) FROM connections", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+        drop(copy);
+        let path = tmp.to_path_buf();
+        drop(tmp);
+        assert!(!path.exists());
+
+        let dest = dir.path().join("backup.sqlite");
+        backup_file(src, &dest, busy).unwrap();
+        backup_file(src, &dest, busy).unwrap();
+        let mode = std::fs::metadata(&dest).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only the database, its WAL files and the backup are left.
+        let mut names: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| !name.starts_with("stats.db"))
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["backup.sqlite"]);
+    }
+}
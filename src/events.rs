@@ -1,27 +1,115 @@
 use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
 
-#[derive(Clone, Debug)]
+static SESSION_SEQ: OnceLock<AtomicU64> = OnceLock::new();
+
+/// Returns a new session id. The counter is seeded with the startup time in
+/// microseconds, so ids keep growing across restarts and stay unique in the DB.
+pub fn next_session_id() -> u64 {
+    SESSION_SEQ
+        .get_or_init(|| AtomicU64::new(Utc::now().timestamp_micros().max(0) as u64))
+        .fetch_add(1, Ordering::Relaxed)
+}
+
+static INSTANCE_ID: OnceLock<String> = OnceLock::new();
+
+/// Задаёт идентификатор экземпляра (`instance_id` из конфига, иначе случайный).
+/// Вызывается при старте до первой сессии; повторные вызовы ничего не меняют.
+pub fn set_instance_id(id: Option<String>) {
+    let _ = INSTANCE_ID.set(id.unwrap_or_else(random_instance_id));
+}
+
+/// Идентификатор экземпляра, который пишется в БД рядом с `session_id`:
+/// счётчики сессий нескольких экземпляров с общей базой могут совпасть.
+pub fn instance_id() -> &'static str {
+    INSTANCE_ID.get_or_init(random_instance_id)
+}
+
+fn random_instance_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    // RandomState заполняется случайным ключом процесса; время и pid — на всякий случай.
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_i64(Utc::now().timestamp_nanos_opt().unwrap_or_default());
+    hasher.write_u32(std::process::id());
+    format!("{:012x}", hasher.finish() >> 16)
+}
+
+/// Причина закрытия сессии.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// Клиент закрыл соединение (EOF).
+    ClientClosed,
+    /// Удалённая сторона закрыла соединение (EOF).
+    UpstreamClosed,
+    /// От клиента не было данных дольше `client_idle_timeout_seconds`.
+    ClientIdleTimeout,
+    /// От удалённой стороны не было данных дольше `upstream_idle_timeout_seconds`.
+    UpstreamIdleTimeout,
+    /// Ни в одном направлении не было данных дольше `idle_timeout_seconds`.
+    IdleTimeout,
+    /// Сессия длилась дольше `max_session_duration_seconds`.
+    MaxSessionDuration,
+    /// Ошибка чтения или записи в одном из направлений.
+    IoError,
+    /// Окно расписания правила закрылось (`schedule.close_sessions`).
+    ScheduleClosed,
+}
+
+impl CloseReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::ClientClosed => "client_closed",
+            CloseReason::UpstreamClosed => "upstream_closed",
+            CloseReason::ClientIdleTimeout => "client_idle_timeout",
+            CloseReason::UpstreamIdleTimeout => "upstream_idle_timeout",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::MaxSessionDuration => "max_session_duration",
+            CloseReason::IoError => "io_error",
+            CloseReason::ScheduleClosed => "schedule_closed",
+        }
+    }
+}
+
+/// Событие соединения или правила. В JSON сериализуется с полем `type`
+/// (`connection_started`, `connection_closed`, ...), совпадающим с `log_name` в БД.
+/// События правил (`rule_opened`, `rule_closed`) в БД не пишутся.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 pub enum LogEvent {
     ConnectionStarted {
         ts: DateTime<Utc>,
+        session_id: u64,
         name: String,
         local_port: u16,
         remote_address: String,
         remote_port: u16,
         client_addr: Option<String>,
+        /// IP, к которому фактически установлено исходящее соединение.
+        upstream_ip: Option<String>,
     },
     ConnectionClosed {
         ts: DateTime<Utc>,
+        session_id: u64,
         name: String,
         local_port: u16,
         remote_address: String,
         remote_port: u16,
         client_addr: Option<String>,
+        upstream_ip: Option<String>,
         bytes_from_to: u64,
         bytes_to_from: u64,
+        /// Длительность сессии от установки исходящего соединения до закрытия.
+        duration_ms: u64,
+        close_reason: CloseReason,
+        /// Текст ошибки ввода‑вывода, если сессия закрылась из‑за неё.
+        error: Option<String>,
     },
     ConnectionError {
         ts: DateTime<Utc>,
+        session_id: u64,
         name: String,
         local_port: u16,
         remote_address: String,
@@ -31,11 +119,65 @@ pub enum LogEvent {
     },
     ConnectionTimeout {
         ts: DateTime<Utc>,
+        session_id: u64,
         name: String,
         local_port: u16,
         remote_address: String,
         remote_port: u16,
         client_addr: Option<String>,
+        upstream_ip: Option<String>,
         error: String,
     },
+    /// Расписание открыло порты правила.
+    RuleOpened {
+        ts: DateTime<Utc>,
+        name: String,
+        /// Когда окно закроется; `None` — не закроется.
+        next_change: Option<DateTime<Utc>>,
+    },
+    /// Расписание закрыло порты правила.
+    RuleClosed {
+        ts: DateTime<Utc>,
+        name: String,
+        /// Когда откроется следующее окно; `None` — не откроется.
+        next_change: Option<DateTime<Utc>>,
+        /// Открытые сессии правила оборваны (`schedule.close_sessions`).
+        close_sessions: bool,
+    },
+}
+
+impl LogEvent {
+    /// Тип события, как в поле `type` JSON‑представления.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LogEvent::ConnectionStarted { .. } => "connection_started",
+            LogEvent::ConnectionClosed { .. } => "connection_closed",
+            LogEvent::ConnectionError { .. } => "connection_error",
+            LogEvent::ConnectionTimeout { .. } => "connection_timeout",
+            LogEvent::RuleOpened { .. } => "rule_opened",
+            LogEvent::RuleClosed { .. } => "rule_closed",
+        }
+    }
+
+    /// Имя правила, к которому относится событие.
+    pub fn name(&self) -> &str {
+        match self {
+            LogEvent::ConnectionStarted { name, .. }
+            | LogEvent::ConnectionClosed { name, .. }
+            | LogEvent::ConnectionError { name, .. }
+            | LogEvent::ConnectionTimeout { name, .. }
+            | LogEvent::RuleOpened { name, .. }
+            | LogEvent::RuleClosed { name, .. } => name,
+        }
+    }
+
+    pub fn client_addr(&self) -> Option<&str> {
+        match self {
+            LogEvent::ConnectionStarted { client_addr, .. }
+            | LogEvent::ConnectionClosed { client_addr, .. }
+            | LogEvent::ConnectionError { client_addr, .. }
+            | LogEvent::ConnectionTimeout { client_addr, .. } => client_addr.as_deref(),
+            LogEvent::RuleOpened { .. } | LogEvent::RuleClosed { .. } => None,
+        }
+    }
 }
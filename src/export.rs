@@ -0,0 +1,594 @@
+// Streaming exports of raw connection rows and per-client totals as CSV,
+// NDJSON or Parquet. Raw rows are read page by page with a `(ts, id)`
+// cursor, so memory use does not depend on the size of the range.
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::db::StoredConnection;
+use crate::storage::SharedStorage;
+
+/// Rows fetched per page (and per Parquet row group).
+const EXPORT_PAGE: u32 = 5000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+    Parquet,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" | "jsonl" => Ok(ExportFormat::Ndjson),
+            "parquet" if cfg!(feature = "parquet") => Ok(ExportFormat::Parquet),
+            "parquet" => Err("parquet export needs a build with the `parquet` feature".to_string()),
+            _ => Err("format must be csv, ndjson or parquet".to_string()),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+/// What to export: raw `connections` rows or per-client totals.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportKind {
+    Connections,
+    Clients,
+}
+
+impl std::str::FromStr for ExportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connections" => Ok(ExportKind::Connections),
+            "clients" => Ok(ExportKind::Clients),
+            _ => Err("export kind must be connections or clients".to_string()),
+        }
+    }
+}
+
+impl ExportKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ExportKind::Connections => "connections",
+            ExportKind::Clients => "clients",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ExportRequest {
+    pub kind: ExportKind,
+    pub format: ExportFormat,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// Only rows of this rule.
+    pub name: Option<String>,
+}
+
+impl ExportRequest {
+    /// Suggested file name, e.g. `clients-20260901-20261001.csv`.
+    pub fn file_name(&self) -> String {
+        format!(
+            "{}-{}-{}.{}",
+            self.kind.as_str(),
+            self.start.format("%Y%m%d"),
+            self.end.format("%Y%m%d"),
+            self.format.extension()
+        )
+    }
+}
+
+/// One exported raw row; field order is the CSV column order.
+#[derive(Serialize)]
+struct ConnectionRecord {
+    id: i64,
+    ts: i64,
+    name: String,
+    log_name: String,
+    local_port: u16,
+    remote_address: String,
+    remote_port: u16,
+    client_addr: Option<String>,
+    bytes_from_to: u64,
+    bytes_to_from: u64,
+    session_id: u64,
+    upstream_ip: Option<String>,
+    error: Option<String>,
+    close_reason: Option<String>,
+    duration_ms: Option<u64>,
+    instance_id: Option<String>,
+}
+
+impl From<StoredConnection> for ConnectionRecord {
+    fn from(c: StoredConnection) -> Self {
+        let r = c.row;
+        ConnectionRecord {
+            id: c.id,
+            ts: r.ts,
+            name: r.name,
+            log_name: r.log_name,
+            local_port: r.local_port,
+            remote_address: r.remote_address,
+            remote_port: r.remote_port,
+            client_addr: r.client_addr,
+            bytes_from_to: r.bytes_from_to,
+            bytes_to_from: r.bytes_to_from,
+            session_id: r.session_id,
+            upstream_ip: r.upstream_ip,
+            error: r.error,
+            close_reason: r.close_reason,
+            duration_ms: r.duration_ms,
+            instance_id: r.instance_id,
+        }
+    }
+}
+
+const CONNECTION_COLUMNS: &[&str] = &[
+    "id",
+    "ts",
+    "name",
+    "log_name",
+    "local_port",
+    "remote_address",
+    "remote_port",
+    "client_addr",
+    "bytes_from_to",
+    "bytes_to_from",
+    "session_id",
+    "upstream_ip",
+    "error",
+    "close_reason",
+    "duration_ms",
+    "instance_id",
+];
+
+const CLIENT_COLUMNS: &[&str] = &["client_addr", "bytes_from_to", "bytes_to_from"];
+
+/// Starts the export in the background and returns its output in chunks.
+/// An `Err` item means the export failed midway and the output is truncated.
+/// Dropping the receiver cancels the export.
+pub fn spawn_export(
+    storage: SharedStorage,
+    req: ExportRequest,
+) -> mpsc::Receiver<anyhow::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(4);
+    match req.format {
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => {
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = parquet_export::run(&storage, &req, &tx) {
+                    let _ = tx.blocking_send(Err(e));
+                }
+            });
+        }
+        _ => {
+            tokio::spawn(async move {
+                if let Err(e) = text_export(&storage, &req, &tx).await {
+                    let _ = tx.send(Err(e)).await;
+                }
+            });
+        }
+    }
+    rx
+}
+
+async fn text_export(
+    storage: &SharedStorage,
+    req: &ExportRequest,
+    tx: &mpsc::Sender<anyhow::Result<Bytes>>,
+) -> anyhow::Result<()> {
+    match req.kind {
+        ExportKind::Connections => {
+            let mut after = (req.start.timestamp(), 0);
+            let mut header = Some(CONNECTION_COLUMNS);
+            loop {
+                let page = storage
+                    .connections_page(req.start, req.end, req.name.clone(), after, EXPORT_PAGE)
+                    .await?;
+                let full = page.len() as u32 == EXPORT_PAGE;
+                if let Some(last) = page.last() {
+                    after = (last.row.ts, last.id);
+                }
+                let records = page.into_iter().map(ConnectionRecord::from);
+                let chunk = encode_text(req.format, header.take(), records)?;
+                if !chunk.is_empty() && tx.send(Ok(chunk)).await.is_err() {
+                    // Receiver is gone (client disconnected).
+                    return Ok(());
+                }
+                if !full {
+                    return Ok(());
+                }
+            }
+        }
+        ExportKind::Clients => {
+            let rows = storage
+                .traffic_by_client(req.start, req.end, req.name.clone())
+                .await?;
+            let chunk = encode_text(req.format, Some(CLIENT_COLUMNS), rows.into_iter())?;
+            let _ = tx.send(Ok(chunk)).await;
+            Ok(())
+        }
+    }
+}
+
+fn encode_text<T: Serialize>(
+    format: ExportFormat,
+    header: Option<&[&str]>,
+    records: impl Iterator<Item = T>,
+) -> anyhow::Result<Bytes> {
+    match format {
+        ExportFormat::Csv => {
+            let mut w = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(Vec::new());
+            if let Some(header) = header {
+                w.write_record(header)?;
+            }
+            for record in records {
+                w.serialize(record)?;
+            }
+            Ok(w.into_inner().map_err(|e| e.into_error())?.into())
+        }
+        _ => {
+            let mut buf = Vec::new();
+            for record in records {
+                serde_json::to_writer(&mut buf, &record)?;
+                buf.push(b'\n');
+            }
+            Ok(buf.into())
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_export {
+    use super::*;
+    use crate::db::ClientTraffic;
+    use arrow_array::{
+        ArrayRef, Int32Array, Int64Array, RecordBatch, StringArray, TimestampSecondArray,
+        UInt64Array,
+    };
+    use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+    use parquet::arrow::ArrowWriter;
+    use parquet::basic::Compression;
+    use parquet::file::properties::WriterProperties;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    /// Bytes buffered before a chunk is handed to the receiver.
+    const CHUNK_SIZE: usize = 256 * 1024;
+
+    /// `Write` adapter that forwards the Parquet file to the channel.
+    struct ChannelSink {
+        tx: mpsc::Sender<anyhow::Result<Bytes>>,
+        buf: Vec<u8>,
+    }
+
+    impl ChannelSink {
+        fn send(&mut self) -> std::io::Result<()> {
+            if self.buf.is_empty() {
+                return Ok(());
+            }
+            let chunk = Bytes::from(std::mem::take(&mut self.buf));
+            self.tx
+                .blocking_send(Ok(chunk))
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))
+        }
+    }
+
+    impl Write for ChannelSink {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.buf.extend_from_slice(data);
+            if self.buf.len() >= CHUNK_SIZE {
+                self.send()?;
+            }
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.send()
+        }
+    }
+
+    fn text(name: &str, nullable: bool) -> Field {
+        Field::new(name, DataType::Utf8, nullable)
+    }
+
+    fn connections_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new(
+                "ts",
+                DataType::Timestamp(TimeUnit::Second, Some("UTC".into())),
+                false,
+            ),
+            text("name", false),
+            text("log_name", false),
+            Field::new("local_port", DataType::Int32, false),
+            text("remote_address", false),
+            Field::new("remote_port", DataType::Int32, false),
+            text("client_addr", true),
+            Field::new("bytes_from_to", DataType::UInt64, false),
+            Field::new("bytes_to_from", DataType::UInt64, false),
+            Field::new("session_id", DataType::UInt64, false),
+            text("upstream_ip", true),
+            text("error", true),
+            text("close_reason", true),
+            Field::new("duration_ms", DataType::UInt64, true),
+            text("instance_id", true),
+        ]))
+    }
+
+    fn clients_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            text("client_addr", true),
+            Field::new("bytes_from_to", DataType::UInt64, false),
+            Field::new("bytes_to_from", DataType::UInt64, false),
+        ]))
+    }
+
+    fn connections_batch(
+        schema: &SchemaRef,
+        page: &[StoredConnection],
+    ) -> anyhow::Result<RecordBatch> {
+        let rows = || page.iter().map(|c| &c.row);
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(Int64Array::from_iter_values(page.iter().map(|c| c.id))),
+            Arc::new(
+                TimestampSecondArray::from_iter_values(rows().map(|r| r.ts)).with_timezone("UTC"),
+            ),
+            Arc::new(StringArray::from_iter_values(rows().map(|r| &r.name))),
+            Arc::new(StringArray::from_iter_values(rows().map(|r| &r.log_name))),
+            Arc::new(Int32Array::from_iter_values(
+                rows().map(|r| r.local_port as i32),
+            )),
+            Arc::new(StringArray::from_iter_values(
+                rows().map(|r| &r.remote_address),
+            )),
+            Arc::new(Int32Array::from_iter_values(
+                rows().map(|r| r.remote_port as i32),
+            )),
+            Arc::new(StringArray::from_iter(
+                rows().map(|r| r.client_addr.as_deref()),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                rows().map(|r| r.bytes_from_to),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                rows().map(|r| r.bytes_to_from),
+            )),
+            Arc::new(UInt64Array::from_iter_values(rows().map(|r| r.session_id))),
+            Arc::new(StringArray::from_iter(
+                rows().map(|r| r.upstream_ip.as_deref()),
+            )),
+            Arc::new(StringArray::from_iter(rows().map(|r| r.error.as_deref()))),
+            Arc::new(StringArray::from_iter(
+                rows().map(|r| r.close_reason.as_deref()),
+            )),
+            Arc::new(UInt64Array::from_iter(rows().map(|r| r.duration_ms))),
+            Arc::new(StringArray::from_iter(
+                rows().map(|r| r.instance_id.as_deref()),
+            )),
+        ];
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+
+    fn clients_batch(schema: &SchemaRef, rows: &[ClientTraffic]) -> anyhow::Result<RecordBatch> {
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter(
+                rows.iter().map(|r| r.client_addr.as_deref()),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                rows.iter().map(|r| r.bytes_from_to),
+            )),
+            Arc::new(UInt64Array::from_iter_values(
+                rows.iter().map(|r| r.bytes_to_from),
+            )),
+        ];
+        Ok(RecordBatch::try_new(schema.clone(), columns)?)
+    }
+
+    /// Runs on a blocking thread: the Parquet writer is synchronous, pages are
+    /// fetched through the runtime handle. Each page becomes one row group.
+    pub(super) fn run(
+        storage: &SharedStorage,
+        req: &ExportRequest,
+        tx: &mpsc::Sender<anyhow::Result<Bytes>>,
+    ) -> anyhow::Result<()> {
+        let rt = tokio::runtime::Handle::current();
+        let schema = match req.kind {
+            ExportKind::Connections => connections_schema(),
+            ExportKind::Clients => clients_schema(),
+        };
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let sink = ChannelSink {
+            tx: tx.clone(),
+            buf: Vec::new(),
+        };
+        let mut writer = ArrowWriter::try_new(sink, schema.clone(), Some(props))?;
+        match req.kind {
+            ExportKind::Connections => {
+                let mut after = (req.start.timestamp(), 0);
+                loop {
+                    let page = rt.block_on(storage.connections_page(
+                        req.start,
+                        req.end,
+                        req.name.clone(),
+                        after,
+                        EXPORT_PAGE,
+                    ))?;
+                    let Some(last) = page.last() else {
+                        break;
+                    };
+                    after = (last.row.ts, last.id);
+                    writer.write(&connections_batch(&schema, &page)?)?;
+                    writer.flush()?;
+                    if (page.len() as u32) < EXPORT_PAGE {
+                        break;
+                    }
+                }
+            }
+            ExportKind::Clients => {
+                let rows =
+                    rt.block_on(storage.traffic_by_client(req.start, req.end, req.name.clone()))?;
+                writer.write(&clients_batch(&schema, &rows)?)?;
+            }
+        }
+        let mut sink = writer.into_inner()?;
+        sink.flush()?;
+        Ok(())
+    }
+}
+
+/// Offline `export` subcommand: writes to `--output` (or a generated file
+/// name in the current directory) and reports where the data went.
+pub async fn run_cli(
+    storage: SharedStorage,
+    req: ExportRequest,
+    output: Option<String>,
+) -> anyhow::Result<()> {
+    let path = output.unwrap_or_else(|| req.file_name());
+    // Written next to the target and renamed at the end, so a failed export
+    // never leaves a truncated file under the final name.
+    let tmp = format!("{}.tmp", path);
+    match write_file(storage, req, &tmp).await {
+        Ok(written) => {
+            tokio::fs::rename(&tmp, &path).await?;
+            eprintln!("Exported {} bytes to {}", written, path);
+            Ok(())
+        }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            Err(e)
+        }
+    }
+}
+
+async fn write_file(storage: SharedStorage, req: ExportRequest, path: &str) -> anyhow::Result<u64> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut rx = spawn_export(storage, req);
+    let mut written: u64 = 0;
+    while let Some(chunk) = rx.recv().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+    file.flush().await?;
+    file.sync_all().await?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{ConnectionRow, SqliteOptions};
+    use crate::memory::MemoryStorage;
+    use crate::storage::{SqliteStorage, Storage};
+    use std::sync::Arc;
+
+    fn request() -> ExportRequest {
+        ExportRequest {
+            kind: ExportKind::Connections,
+            format: ExportFormat::Csv,
+            start: DateTime::from_timestamp(0, 0).unwrap(),
+            end: DateTime::from_timestamp(10_000, 0).unwrap(),
+            name: None,
+        }
+    }
+
+    fn row(ts: i64) -> ConnectionRow {
+        ConnectionRow {
+            ts,
+            name: "pg".to_string(),
+            log_name: "connection_closed".to_string(),
+            local_port: 6432,
+            remote_address: "db.internal".to_string(),
+            remote_port: 5432,
+            client_addr: Some("10.0.0.1".to_string()),
+            bytes_from_to: 1,
+            bytes_to_from: 2,
+            session_id: ts as u64,
+            instance_id: None,
+            upstream_ip: None,
+            error: None,
+            close_reason: Some("client_closed".to_string()),
+            duration_ms: Some(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn cli_export_renames_finished_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.csv");
+        let storage = MemoryStorage::new(100, 100_000);
+        storage
+            .insert_connection_rows(&[row(10), row(20)])
+            .await
+            .unwrap();
+        run_cli(
+            Arc::new(storage),
+            request(),
+            Some(path.display().to_string()),
+        )
+        .await
+        .unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("id,ts,name,"));
+        assert!(lines[1].contains(",10,pg,connection_closed,"));
+        assert!(!dir.path().join("out.csv.tmp").exists());
+    }
+
+    #[tokio::test]
+    async fn failed_cli_export_leaves_no_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("stats.db");
+        let storage = SqliteStorage::open(db_path.to_str().unwrap(), SqliteOptions::default())
+            .await
+            .unwrap();
+        // Reads fail once the table is gone.
+        rusqlite::Connection::open(&db_path)
+            .unwrap()
+            .execute_batch("DROP TABLE connections")
+            .unwrap();
+        let path = dir.path().join("out.csv");
+        std::fs::write(&path, "previous export").unwrap();
+        let result = run_cli(
+            Arc::new(storage),
+            request(),
+            Some(path.display().to_string()),
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous export");
+        assert!(!dir.path().join("out.csv.tmp").exists());
+    }
+}
@@ -0,0 +1,270 @@
+// Правила из подключаемых файлов (`include` в конфиге или `--config-dir`).
+// Каждый файл — список правил или объект с одним полем `connect_list`;
+// формат определяется по расширению, `${VAR}` подставляются так же, как в
+// основном конфиге. Файлы опрашиваются: добавленные, изменённые и удалённые
+// файлы применяются без перезапуска.
+use anyhow::Context;
+use log::{error, info};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::time::Duration;
+
+use crate::loader;
+use crate::rules::RuleManager;
+use crate::ConfigConnect;
+
+/// Как часто проверять подключаемые файлы на изменения.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+/// Расширения файлов, которые берутся из `--config-dir`.
+const DIR_EXTENSIONS: &[&str] = &["json", "yaml", "yml", "toml"];
+
+/// Шаблоны для всех поддерживаемых файлов директории.
+pub fn dir_patterns(dir: &str) -> Vec<String> {
+    DIR_EXTENSIONS
+        .iter()
+        .map(|ext| {
+            Path::new(dir)
+                .join(format!("*.{}", ext))
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect()
+}
+
+/// Файлы по шаблонам в порядке имён, без повторов. Относительные шаблоны
+/// берутся от `base`.
+fn expand(patterns: &[String], base: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = BTreeSet::new();
+    for pattern in patterns {
+        let full = base.join(pattern);
+        let full = full.to_string_lossy();
+        let paths =
+            glob::glob(&full).with_context(|| format!("include: invalid pattern '{}'", pattern))?;
+        for path in paths {
+            let path = path.with_context(|| format!("include: cannot read '{}'", pattern))?;
+            if path.is_file() {
+                files.insert(path);
+            }
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Правила одного файла с отметкой `source` и пути его неизвестных полей.
+fn load_file(path: &Path) -> anyhow::Result<(Vec<ConfigConnect>, Vec<String>)> {
+    let file = path.to_string_lossy().into_owned();
+    let list = match loader::read_value(&file)? {
+        list @ Value::Array(_) => list,
+        Value::Object(mut map) if map.len() == 1 && map.contains_key("connect_list") => {
+            map.remove("connect_list").unwrap_or_default()
+        }
+        _ => anyhow::bail!(
+            "invalid config file '{}': expected a list of rules or an object with only connect_list",
+            file
+        ),
+    };
+    let (mut rules, unknown_fields) =
+        loader::from_value::<Vec<ConfigConnect>>(list).map_err(|(at, e)| {
+            anyhow::anyhow!("invalid config file '{}': connect_list{}: {}", file, at, e)
+        })?;
+    for rule in &mut rules {
+        rule.source = Some(file.clone());
+    }
+    let unknown_fields = unknown_fields
+        .into_iter()
+        .map(|at| format!("{}: connect_list{}", file, at))
+        .collect();
+    Ok((rules, unknown_fields))
+}
+
+/// Правила всех подключаемых файлов; ошибки всех файлов сообщаются вместе.
+pub fn load_all(
+    patterns: &[String],
+    base: &Path,
+) -> anyhow::Result<(Vec<ConfigConnect>, Vec<String>)> {
+    let mut rules = Vec::new();
+    let mut unknown_fields = Vec::new();
+    let mut errors = Vec::new();
+    for path in expand(patterns, base)? {
+        match load_file(&path) {
+            Ok((file_rules, file_unknown)) => {
+                rules.extend(file_rules);
+                unknown_fields.extend(file_unknown);
+            }
+            Err(e) => errors.push(format!("{:#}", e)),
+        }
+    }
+    if !errors.is_empty() {
+        anyhow::bail!("{}", errors.join("\n"));
+    }
+    Ok((rules, unknown_fields))
+}
+
+/// Имена, время изменения и размеры файлов; `None`, если шаблоны не читаются.
+fn snapshot(patterns: &[String], base: &Path) -> Option<BTreeMap<PathBuf, (SystemTime, u64)>> {
+    let files = expand(patterns, base).ok()?;
+    Some(
+        files
+            .into_iter()
+            .filter_map(|path| {
+                let meta = std::fs::metadata(&path).ok()?;
+                Some((path, (meta.modified().ok()?, meta.len())))
+            })
+            .collect(),
+    )
+}
+
+/// Опрашивает подключаемые файлы и при любом изменении набора файлов или их
+/// содержимого заменяет подключённые правила. Если новый набор не читается
+/// или конфликтует с другими правилами, работают прежние правила, а ошибка
+/// печатается; исправленный файл подхватывается на следующем опросе. Пока
+/// последняя попытка не удалась, она повторяется на каждом опросе и без
+/// изменений файлов: конфликт может исчезнуть после правки правил через
+/// HTTP API. Повторяющаяся ошибка печатается один раз.
+pub async fn watch(rules: Arc<RuleManager>, patterns: Vec<String>, base: PathBuf) {
+    let mut last = snapshot(&patterns, &base);
+    let mut failure: Option<String> = None;
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = {
+            let (patterns, base) = (patterns.clone(), base.clone());
+            tokio::task::spawn_blocking(move || snapshot(&patterns, &base))
+                .await
+                .ok()
+                .flatten()
+        };
+        if current == last && failure.is_none() {
+            continue;
+        }
+        last = current;
+        let loaded = {
+            let (patterns, base) = (patterns.clone(), base.clone());
+            tokio::task::spawn_blocking(move || load_all(&patterns, &base)).await
+        };
+        let result = match loaded {
+            Ok(Ok((list, _))) => rules.replace_included(list).await,
+            Ok(Err(e)) => Err(format!("{:#}", e)),
+            Err(e) => Err(e.to_string()),
+        };
+        match result {
+            Ok(summary) => {
+                info!("Include files reloaded: {}", summary);
+                failure = None;
+            }
+            Err(e) => {
+                if failure.as_ref() != Some(&e) {
+                    error!("Include files not applied: {}", e);
+                }
+                failure = Some(e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, text: &str) {
+        std::fs::write(dir.join(name), text).unwrap();
+    }
+
+    #[test]
+    fn load_all_merges_files_in_name_order() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "b.yaml",
+            "connect_list:\n\
+             - name: b1\n  local_port: 8082\n  remote_address: 10.0.0.2\n  remote_port: 82\n  extra: 1\n",
+        );
+        write(
+            dir.path(),
+            "a.json",
+            r#"[{"name": "a1", "local_port": 8081, "remote_address": "10.0.0.1", "remote_port": 81}]"#,
+        );
+        write(
+            dir.path(),
+            "c.toml",
+            "[[connect_list]]\nname = \"c1\"\nlocal_port = 8083\nremote_address = \"10.0.0.3\"\nremote_port = 83\n",
+        );
+        write(dir.path(), "notes.txt", "not a rule file");
+
+        let patterns = dir_patterns(&dir.path().to_string_lossy());
+        // Файл, подходящий под два шаблона, читается один раз.
+        let mut twice = patterns.clone();
+        twice.push(dir.path().join("a.*").to_string_lossy().into_owned());
+        let (rules, unknown) = load_all(&twice, Path::new(".")).unwrap();
+
+        let names: Vec<&str> = rules.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["a1", "b1", "c1"]);
+        let b = dir.path().join("b.yaml").to_string_lossy().into_owned();
+        assert_eq!(rules[1].source.as_deref(), Some(b.as_str()));
+        assert_eq!(unknown, [format!("{}: connect_list[0].extra", b)]);
+
+        // Относительные шаблоны берутся от `base`.
+        let (rules, _) = load_all(&["*.json".to_string()], dir.path()).unwrap();
+        assert_eq!(rules.len(), 1);
+    }
+
+    #[test]
+    fn load_all_reports_every_bad_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "a.json",
+            r#"{"connect_list": [], "http_listen": "0.0.0.0:1"}"#,
+        );
+        write(
+            dir.path(),
+            "b.json",
+            r#"[{"name": "b1", "local_port": "x", "remote_address": "h", "remote_port": 1}]"#,
+        );
+        write(dir.path(), "c.json", "[{");
+        write(
+            dir.path(),
+            "d.json",
+            r#"[{"name": "d1", "local_port": 1, "remote_address": "h", "remote_port": 1}]"#,
+        );
+        let err = load_all(&dir_patterns(&dir.path().to_string_lossy()), Path::new("."))
+            .unwrap_err()
+            .to_string();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", err);
+        assert!(lines[0].contains("a.json") && lines[0].contains("only connect_list"));
+        assert!(lines[1].contains("b.json") && lines[1].contains("connect_list[0].local_port"));
+        assert!(lines[2].contains("c.json"));
+
+        assert!(load_all(&["[".to_string()], dir.path())
+            .unwrap_err()
+            .to_string()
+            .contains("invalid pattern"));
+    }
+
+    #[test]
+    fn conflicts_between_files_name_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let rule =
+            r#"[{"name": "web", "local_port": 8080, "remote_address": "h", "remote_port": 1}]"#;
+        write(dir.path(), "a.json", rule);
+        write(dir.path(), "b.json", rule);
+        let (rules, _) =
+            load_all(&dir_patterns(&dir.path().to_string_lossy()), Path::new(".")).unwrap();
+        let (mut config, _) = loader::from_value::<crate::Config>(serde_json::json!({})).unwrap();
+        config.connect_list = rules;
+        let problems: Vec<String> = crate::validate::rule_list_problems(&config)
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        let b = dir.path().join("b.json").to_string_lossy().into_owned();
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems
+            .iter()
+            .all(|p| p.starts_with(&format!("error: {}: connect_list[0].", b))));
+    }
+}
@@ -0,0 +1,171 @@
+// Живое состояние для веб-интерфейса: активные сессии и последние ошибки.
+// Заполняется подписчиком broadcast-канала `LogEvent`, БД не требуется.
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+use crate::events::LogEvent;
+
+/// Сколько последних ошибок и таймаутов хранить в памяти.
+const RECENT_ERRORS_CAPACITY: usize = 200;
+
+/// Открытая в данный момент сессия.
+#[derive(Clone, Debug, Serialize)]
+pub struct ActiveSession {
+    pub session_id: u64,
+    pub started: DateTime<Utc>,
+    pub name: String,
+    pub local_port: u16,
+    pub remote_address: String,
+    pub remote_port: u16,
+    pub client_addr: Option<String>,
+    pub upstream_ip: Option<String>,
+}
+
+/// Ошибка подключения или таймаут простоя.
+#[derive(Clone, Debug, Serialize)]
+pub struct RecentError {
+    pub ts: DateTime<Utc>,
+    pub session_id: u64,
+    /// `error` или `timeout`.
+    pub kind: &'static str,
+    pub name: String,
+    pub local_port: u16,
+    pub remote_address: String,
+    pub remote_port: u16,
+    pub client_addr: Option<String>,
+    pub error: String,
+}
+
+#[derive(Default)]
+struct Inner {
+    active: HashMap<u64, ActiveSession>,
+    recent_errors: VecDeque<RecentError>,
+}
+
+#[derive(Default)]
+pub struct LiveState {
+    inner: Mutex<Inner>,
+}
+
+impl LiveState {
+    /// Активные сессии, самые старые первыми.
+    pub fn active_sessions(&self) -> Vec<ActiveSession> {
+        let inner = self.inner.lock().unwrap();
+        let mut out: Vec<ActiveSession> = inner.active.values().cloned().collect();
+        out.sort_by_key(|s| (s.started, s.session_id));
+        out
+    }
+
+    /// Последние ошибки, самые свежие первыми.
+    pub fn recent_errors(&self) -> Vec<RecentError> {
+        let inner = self.inner.lock().unwrap();
+        inner.recent_errors.iter().rev().cloned().collect()
+    }
+
+    fn apply(&self, event: LogEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        match event {
+            LogEvent::ConnectionStarted {
+                ts,
+                session_id,
+                name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                upstream_ip,
+            } => {
+                inner.active.insert(
+                    session_id,
+                    ActiveSession {
+                        session_id,
+                        started: ts,
+                        name,
+                        local_port,
+                        remote_address,
+                        remote_port,
+                        client_addr,
+                        upstream_ip,
+                    },
+                );
+            }
+            LogEvent::ConnectionClosed { session_id, .. } => {
+                inner.active.remove(&session_id);
+            }
+            LogEvent::ConnectionError {
+                ts,
+                session_id,
+                name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                error,
+            } => push_error(
+                &mut inner.recent_errors,
+                RecentError {
+                    ts,
+                    session_id,
+                    kind: "error",
+                    name,
+                    local_port,
+                    remote_address,
+                    remote_port,
+                    client_addr,
+                    error,
+                },
+            ),
+            LogEvent::ConnectionTimeout {
+                ts,
+                session_id,
+                name,
+                local_port,
+                remote_address,
+                remote_port,
+                client_addr,
+                error,
+                ..
+            } => push_error(
+                &mut inner.recent_errors,
+                RecentError {
+                    ts,
+                    session_id,
+                    kind: "timeout",
+                    name,
+                    local_port,
+                    remote_address,
+                    remote_port,
+                    client_addr,
+                    error,
+                },
+            ),
+            LogEvent::RuleOpened { .. } | LogEvent::RuleClosed { .. } => {}
+        }
+    }
+
+    /// Читает события до закрытия канала. При отставании (`Lagged`) часть
+    /// событий `ConnectionClosed` может быть потеряна, поэтому такие сессии
+    /// останутся в списке активных до перезапуска — об этом пишем в лог.
+    pub async fn run(&self, mut rx: broadcast::Receiver<LogEvent>) {
+        loop {
+            match rx.recv().await {
+                Ok(event) => self.apply(event),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Live session tracker lagged, {} events dropped", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+fn push_error(list: &mut VecDeque<RecentError>, error: RecentError) {
+    if list.len() >= RECENT_ERRORS_CAPACITY {
+        list.pop_front();
+    }
+    list.push_back(error);
+}
@@ -0,0 +1,740 @@
+// Чтение файла конфигурации. Формат выбирается по расширению (`.yaml`/`.yml`,
+// `.toml`, иначе JSON); файл сначала разбирается в общее дерево значений,
+// в строках подставляются переменные окружения `${VAR}` / `${VAR:-default}`,
+// затем применяются переопределения `RSPF_<ПОЛЕ>` для полей верхнего уровня,
+// и только после этого дерево превращается в `Config`.
+use anyhow::Context;
+use log::info;
+use serde::de::{DeserializeOwned, Error as _};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::include;
+use crate::validate;
+use crate::{Config, ConfigConnect};
+
+/// Префикс переменных окружения, переопределяющих поля верхнего уровня.
+const ENV_PREFIX: &str = "RSPF_";
+/// `source` правил, заданных флагами `-L`.
+pub const CLI_SOURCE: &str = "command line";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &str) -> Self {
+        let ext = std::path::Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    fn parse(self, text: &str) -> anyhow::Result<Value> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::from_str(text)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(text)?,
+            ConfigFormat::Toml => toml::from_str(text)?,
+        })
+    }
+
+    /// Новый текст файла конфига, в котором заменён только `connect_list`.
+    /// Остальное остаётся как в файле: `${VAR}` не раскрываются, а `RSPF_*`
+    /// и флаги командной строки в файл не попадают. Неизменённые правила
+    /// переносятся из файла как есть — со ссылками на переменные, а в TOML
+    /// и с комментариями. `text` пуст, если файла ещё нет.
+    pub fn replace_connect_list(
+        self,
+        text: &str,
+        list: &[ConfigConnect],
+    ) -> anyhow::Result<String> {
+        let empty = text.trim().is_empty();
+        let raw = if empty {
+            Value::Object(Default::default())
+        } else {
+            self.parse(text)?
+        };
+        let Value::Object(mut map) = raw else {
+            anyhow::bail!("the top level must be an object");
+        };
+        let old = match map.get("connect_list") {
+            Some(Value::Array(items)) => items.clone(),
+            _ => Vec::new(),
+        };
+        let kept = unchanged_entries(&old, list);
+        Ok(match self {
+            ConfigFormat::Json => {
+                let items = list
+                    .iter()
+                    .zip(&kept)
+                    .map(|(rule, kept)| match kept {
+                        Some(index) => Ok(old[*index].clone()),
+                        None => serde_json::to_value(rule),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                map.insert("connect_list".to_string(), Value::Array(items));
+                serde_json::to_string_pretty(&map)?
+            }
+            ConfigFormat::Yaml => {
+                // Дерево YAML сохраняет порядок ключей и теги.
+                let mut doc: serde_yaml::Value = if empty {
+                    serde_yaml::Mapping::new().into()
+                } else {
+                    serde_yaml::from_str(text)?
+                };
+                let old = doc
+                    .get("connect_list")
+                    .and_then(|v| v.as_sequence())
+                    .cloned()
+                    .unwrap_or_default();
+                let items = list
+                    .iter()
+                    .zip(&kept)
+                    .map(|(rule, kept)| match kept.and_then(|index| old.get(index)) {
+                        Some(item) => Ok(item.clone()),
+                        None => serde_yaml::to_value(rule),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let Some(map) = doc.as_mapping_mut() else {
+                    anyhow::bail!("the top level must be an object");
+                };
+                map.insert("connect_list".into(), serde_yaml::Value::Sequence(items));
+                serde_yaml::to_string(&doc)?
+            }
+            ConfigFormat::Toml => {
+                let mut doc: toml_edit::DocumentMut = text.parse()?;
+                let old = doc
+                    .get("connect_list")
+                    .and_then(|item| item.as_array_of_tables())
+                    .cloned()
+                    .unwrap_or_default();
+                let mut items = toml_edit::ArrayOfTables::new();
+                for (rule, kept) in list.iter().zip(&kept) {
+                    items.push(match kept.and_then(|index| old.get(index)) {
+                        Some(table) => table.clone(),
+                        None => {
+                            let mut table = toml_edit::ser::to_document(rule)?.into_table();
+                            table.decor_mut().set_prefix("\n");
+                            table
+                        }
+                    });
+                }
+                doc["connect_list"] = toml_edit::Item::ArrayOfTables(items);
+                doc.to_string()
+            }
+        })
+    }
+}
+
+/// Для каждого правила — индекс записи `connect_list` в файле, которая после
+/// подстановки переменных даёт то же правило.
+fn unchanged_entries(old: &[Value], list: &[ConfigConnect]) -> Vec<Option<usize>> {
+    let old: Vec<Option<ConfigConnect>> = old
+        .iter()
+        .map(|entry| {
+            let mut entry = entry.clone();
+            let mut missing = Vec::new();
+            interpolate(&mut entry, String::new(), &mut missing);
+            if !missing.is_empty() {
+                return None;
+            }
+            from_value::<ConfigConnect>(entry)
+                .ok()
+                .map(|(rule, _)| rule)
+        })
+        .collect();
+    list.iter()
+        .map(|rule| old.iter().position(|entry| entry.as_ref() == Some(rule)))
+        .collect()
+}
+
+/// Задано ли поле верхнего уровня переменной `RSPF_<ПОЛЕ>`.
+pub fn env_overrides(field: &str) -> bool {
+    std::env::var_os(format!("{}{}", ENV_PREFIX, field.to_ascii_uppercase())).is_some()
+}
+
+/// Откуда читается конфиг: основной файл, необязательная директория
+/// с дополнительными файлами правил (`--config-dir`) и флаги командной строки.
+#[derive(Clone, Debug)]
+pub struct ConfigLocation {
+    /// Основной файл; `None`, если конфиг задан только флагами.
+    pub path: Option<String>,
+    /// `--config-dir`; относительный путь отсчитывается от текущей директории.
+    pub dir: Option<String>,
+    pub overrides: CliOverrides,
+}
+
+/// Правила и параметры из командной строки; применяются поверх файла.
+#[derive(Clone, Debug, Default)]
+pub struct CliOverrides {
+    /// Правила из `-L`.
+    pub forwards: Vec<ConfigConnect>,
+    /// `--http-listen`.
+    pub http_listen: Option<String>,
+    /// `--db`: путь к SQLite или строка подключения PostgreSQL.
+    pub db: Option<String>,
+}
+
+impl CliOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.forwards.is_empty() && self.http_listen.is_none() && self.db.is_none()
+    }
+
+    fn apply(&self, config: &mut Config) {
+        config.connect_list.extend(self.forwards.iter().cloned());
+        if let Some(addr) = &self.http_listen {
+            config.http_listen = Some(addr.clone());
+        }
+        if let Some(db) = &self.db {
+            if db.starts_with("postgres://") || db.starts_with("postgresql://") {
+                config.database_url = Some(db.clone());
+                config.database_path = None;
+            } else {
+                config.database_path = Some(db.clone());
+                config.database_url = None;
+            }
+        }
+    }
+}
+
+impl ConfigLocation {
+    /// Загружает основной файл, добавляет правила из подключаемых файлов и
+    /// применяет флаги командной строки.
+    pub fn load(&self) -> anyhow::Result<Config> {
+        let mut config = load_config(self.path.as_deref())?;
+        let (rules, unknown_fields) =
+            include::load_all(&self.include_patterns(&config), &self.base_dir())?;
+        config.connect_list.extend(rules);
+        config.unknown_fields.extend(unknown_fields);
+        self.overrides.apply(&mut config);
+        Ok(config)
+    }
+
+    /// Имя конфига для сообщений: путь к файлу или «command line».
+    pub fn name(&self) -> &str {
+        self.path.as_deref().unwrap_or(CLI_SOURCE)
+    }
+
+    /// Шаблоны подключаемых файлов: `include` из конфига и `--config-dir`.
+    pub fn include_patterns(&self, config: &Config) -> Vec<String> {
+        let mut patterns = config.include.clone().unwrap_or_default();
+        if let Some(dir) = &self.dir {
+            // Шаблоны `--config-dir` делаются абсолютными, чтобы `base_dir`
+            // применялся только к `include` из файла.
+            let dir = std::path::absolute(dir).unwrap_or_else(|_| PathBuf::from(dir));
+            patterns.extend(include::dir_patterns(&dir.to_string_lossy()));
+        }
+        patterns
+    }
+
+    /// Относительные шаблоны `include` отсчитываются от директории конфига.
+    pub fn base_dir(&self) -> PathBuf {
+        match self
+            .path
+            .as_deref()
+            .and_then(|path| Path::new(path).parent())
+        {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+}
+
+/// Загружает основной файл конфигурации (без подключаемых файлов). Ошибка
+/// разбора содержит путь к полю; неизвестные поля запоминаются в `unknown_fields`.
+/// Без файла конфиг начинается пустым, переменные `RSPF_*` применяются всё равно.
+fn load_config(file_path: Option<&str>) -> anyhow::Result<Config> {
+    let Some(file_path) = file_path else {
+        return from_tree(Value::Object(Default::default()), CLI_SOURCE);
+    };
+    info!("Use config: {:?}", file_path);
+
+    let value = match std::fs::read_to_string(file_path) {
+        Ok(text) => parse_text(file_path, &text)?,
+        // Без файла конфиг можно целиком задать переменными `RSPF_*` (Docker).
+        Err(e)
+            if e.kind() == std::io::ErrorKind::NotFound
+                && std::env::vars().any(|(var, _)| var.starts_with(ENV_PREFIX)) =>
+        {
+            info!(
+                "{} not found, using RSPF_* environment variables only",
+                file_path
+            );
+            Value::Object(Default::default())
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("cannot open config file '{}'", file_path))
+        }
+    };
+    from_tree(value, file_path)
+}
+
+/// Применяет `RSPF_*` к дереву и превращает его в `Config`.
+fn from_tree(mut value: Value, file_path: &str) -> anyhow::Result<Config> {
+    let overrides = apply_env_overrides(&mut value)
+        .with_context(|| format!("invalid config file '{}'", file_path))?;
+
+    let (mut config, mut unknown_fields) = from_value::<Config>(value).map_err(|(path, e)| {
+        match overrides
+            .iter()
+            .find(|(field, _)| path.starts_with(field.as_str()))
+        {
+            Some((_, var)) => anyhow::anyhow!("{}: {}: {}", var, path, e),
+            None => anyhow::anyhow!("invalid config file '{}': {}: {}", file_path, path, e),
+        }
+    })?;
+    for path in &mut unknown_fields {
+        if let Some((_, var)) = overrides.iter().find(|(field, _)| field == path) {
+            *path = format!("{} (from {})", path, var);
+        }
+    }
+    config.unknown_fields = unknown_fields;
+    Ok(config)
+}
+
+/// Читает файл любого формата в дерево значений и подставляет `${VAR}`.
+pub fn read_value(file_path: &str) -> anyhow::Result<Value> {
+    let text = std::fs::read_to_string(file_path)
+        .with_context(|| format!("cannot open config file '{}'", file_path))?;
+    parse_text(file_path, &text)
+}
+
+fn parse_text(file_path: &str, text: &str) -> anyhow::Result<Value> {
+    let mut value = ConfigFormat::from_path(file_path)
+        .parse(text)
+        .with_context(|| format!("invalid config file '{}'", file_path))?;
+    let mut missing = Vec::new();
+    interpolate(&mut value, String::new(), &mut missing);
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "invalid config file '{}': {}",
+            file_path,
+            missing.join("; ")
+        );
+    }
+    Ok(value)
+}
+
+/// Превращает дерево в `T`. Возвращает также пути неизвестных полей;
+/// при ошибке — путь к полю, на котором она произошла.
+pub fn from_value<T: DeserializeOwned>(
+    value: Value,
+) -> Result<(T, Vec<String>), (String, serde_json::Error)> {
+    let mut unknown_fields = Vec::new();
+    let mut track = serde_path_to_error::Track::new();
+    let result: Result<T, _> = serde_ignored::deserialize(
+        serde_path_to_error::Deserializer::new(value, &mut track),
+        |path| unknown_fields.push(validate::json_path(&path)),
+    );
+    match result {
+        Ok(value) => Ok((value, unknown_fields)),
+        Err(e) => Err((track.path().to_string(), e)),
+    }
+}
+
+/// Подставляет переменные окружения во все строки дерева. Ненайденные
+/// переменные без значения по умолчанию собираются в `missing`.
+fn interpolate(value: &mut Value, path: String, missing: &mut Vec<String>) {
+    match value {
+        Value::String(s) => match substitute(s) {
+            Ok(Some(replaced)) => *value = Value::String(replaced),
+            Ok(None) => {}
+            Err(e) => missing.push(format!("{}: {}", path, e)),
+        },
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                interpolate(item, format!("{}[{}]", path, index), missing);
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                interpolate(item, child, missing);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Раскрывает `${VAR}`, `${VAR:-default}` и `$$` (литерал `$`). `Ok(None)`,
+/// если подставлять нечего.
+fn substitute(s: &str) -> Result<Option<String>, String> {
+    if !s.contains('$') {
+        return Ok(None);
+    }
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("$$") {
+            out.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after
+                .find('}')
+                .ok_or_else(|| format!("unterminated '${{' in '{}'", s))?;
+            let expr = &after[..end];
+            let (name, default) = match expr.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (expr, None),
+            };
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("invalid variable name '{}'", name));
+            }
+            match (std::env::var(name).ok().filter(|v| !v.is_empty()), default) {
+                (Some(v), _) => out.push_str(&v),
+                (None, Some(default)) => out.push_str(default),
+                (None, None) => {
+                    return Err(format!("environment variable {} is not set", name));
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(Some(out))
+}
+
+/// `deserialize_with` для числовых и логических полей: принимает и само
+/// значение, и строку с ним. `${VAR}` и `RSPF_*` всегда дают строку, поэтому
+/// `"local_port": "${PORT}"` работает, а строковые поля (`http_admin_token`)
+/// не превращаются в числа.
+pub fn scalar<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned + FromStr,
+    T::Err: Display,
+{
+    match Option::<Value>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Value::String(text)) => text
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| D::Error::custom(format!("invalid value '{}': {}", text, e))),
+        Some(value) => T::deserialize(value).map(Some).map_err(D::Error::custom),
+    }
+}
+
+/// Применяет `RSPF_<ПОЛЕ>=значение` к полям верхнего уровня: `RSPF_HTTP_LISTEN`
+/// задаёт `http_listen`. Массив или объект (`RSPF_CONNECT_LIST`) читается как
+/// JSON, всё остальное берётся строкой. Возвращает пары `(поле, переменная)`.
+fn apply_env_overrides(value: &mut Value) -> anyhow::Result<Vec<(String, String)>> {
+    let Value::Object(map) = value else {
+        anyhow::bail!("the top level must be an object");
+    };
+    let mut applied: Vec<(String, String)> = Vec::new();
+    for (var, raw) in std::env::vars() {
+        let Some(field) = var.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let field = field.to_ascii_lowercase();
+        let parsed = serde_json::from_str::<Value>(&raw)
+            .ok()
+            .filter(|v| v.is_array() || v.is_object())
+            .unwrap_or(Value::String(raw));
+        info!("{} overrides {}", var, field);
+        map.insert(field.clone(), parsed);
+        applied.push((field, var));
+    }
+    applied.sort();
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn substitute_variables() {
+        std::env::set_var("LOADER_TEST_HOST", "db.internal");
+        std::env::set_var("LOADER_TEST_EMPTY", "");
+        assert_eq!(substitute("plain"), Ok(None));
+        assert_eq!(
+            substitute("${LOADER_TEST_HOST}:5432").unwrap().as_deref(),
+            Some("db.internal:5432")
+        );
+        assert_eq!(
+            substitute("${LOADER_TEST_UNSET:-fallback}")
+                .unwrap()
+                .as_deref(),
+            Some("fallback")
+        );
+        // Пустая переменная считается незаданной.
+        assert_eq!(
+            substitute("${LOADER_TEST_EMPTY:-fallback}")
+                .unwrap()
+                .as_deref(),
+            Some("fallback")
+        );
+        assert_eq!(
+            substitute("${LOADER_TEST_UNSET}").unwrap_err(),
+            "environment variable LOADER_TEST_UNSET is not set"
+        );
+        assert!(substitute("${LOADER_TEST_EMPTY}").is_err());
+        // `$$` — литерал `$`, в том числе перед `{`; одиночный `$` остаётся.
+        assert_eq!(
+            substitute("$${LOADER_TEST_HOST}").unwrap().as_deref(),
+            Some("${LOADER_TEST_HOST}")
+        );
+        assert_eq!(
+            substitute("pa$$$${LOADER_TEST_HOST}").unwrap().as_deref(),
+            Some("pa$${LOADER_TEST_HOST}")
+        );
+        assert_eq!(substitute("cost $5").unwrap().as_deref(), Some("cost $5"));
+        assert!(substitute("${LOADER_TEST_HOST")
+            .unwrap_err()
+            .contains("unterminated"));
+        assert!(substitute("${BAD-NAME}")
+            .unwrap_err()
+            .contains("invalid variable name"));
+    }
+
+    #[test]
+    fn substituted_values_stay_strings() {
+        std::env::set_var("LOADER_TEST_PORT", "8080");
+        std::env::set_var("LOADER_TEST_FLAG", "true");
+        let mut value = json!({
+            "port": "${LOADER_TEST_PORT}",
+            "flag": "${LOADER_TEST_FLAG}",
+            "text": "port ${LOADER_TEST_PORT}",
+            "escaped": "$${LOADER_TEST_PORT}",
+            "list": [{ "host": "${LOADER_TEST_MISSING}" }]
+        });
+        let mut missing = Vec::new();
+        interpolate(&mut value, String::new(), &mut missing);
+        assert_eq!(value["port"], json!("8080"));
+        assert_eq!(value["flag"], json!("true"));
+        assert_eq!(value["text"], json!("port 8080"));
+        assert_eq!(value["escaped"], json!("${LOADER_TEST_PORT}"));
+        assert_eq!(
+            missing,
+            ["list[0].host: environment variable LOADER_TEST_MISSING is not set"]
+        );
+    }
+
+    #[test]
+    fn numbers_and_flags_accept_strings() {
+        // Секрет из одних цифр остаётся строкой, а числовые поля и флаги
+        // принимают строки, которые дают `${VAR}` и `RSPF_*`.
+        std::env::set_var("LOADER_TEST_TOKEN", "123456");
+        std::env::set_var("LOADER_TEST_LOCAL_PORT", "8080");
+        let mut value = json!({
+            "http_admin_token": "${LOADER_TEST_TOKEN}",
+            "instance_id": "7",
+            "http_admin": "true",
+            "retention_days": 30,
+            "connect_list": [{
+                "name": "web", "local_port": "${LOADER_TEST_LOCAL_PORT}",
+                "remote_address": "10.0.0.5", "remote_port": 80,
+                "enabled": "false",
+                "schedule": { "windows": [], "close_sessions": "true" }
+            }]
+        });
+        let mut missing = Vec::new();
+        interpolate(&mut value, String::new(), &mut missing);
+        assert!(missing.is_empty());
+        let (config, unknown) = from_value::<Config>(value).unwrap();
+        assert!(unknown.is_empty());
+        assert_eq!(config.http_admin_token.as_deref(), Some("123456"));
+        assert_eq!(config.instance_id.as_deref(), Some("7"));
+        assert_eq!(config.http_admin, Some(true));
+        assert_eq!(config.retention_days, Some(30));
+        let rule = &config.connect_list[0];
+        assert_eq!((rule.local_port, rule.enabled), (Some(8080), Some(false)));
+        let printed = serde_json::to_value(rule).unwrap();
+        assert_eq!(printed["schedule"]["close_sessions"], json!(true));
+
+        let (path, e) = from_value::<Config>(json!({ "retention_days": "month" })).unwrap_err();
+        assert_eq!(path, "retention_days");
+        assert!(e.to_string().contains("invalid value 'month'"), "{e}");
+        let (path, _) = from_value::<Config>(json!({ "http_admin": 1 })).unwrap_err();
+        assert_eq!(path, "http_admin");
+    }
+
+    #[test]
+    fn env_overrides_top_level_fields() {
+        // Единственный тест, который задаёт `RSPF_*`: они видны всему процессу.
+        std::env::set_var("RSPF_LOADER_TEST_NUMBER", "42");
+        std::env::set_var("RSPF_LOADER_TEST_FLAG", "true");
+        std::env::set_var("RSPF_LOADER_TEST_LIST", "[1, 2]");
+        std::env::set_var("RSPF_LOADER_TEST_TEXT", "0.0.0.0:8080");
+        std::env::set_var("RSPF_LOADER_TEST_QUOTED", "\"quoted\"");
+        let mut value = json!({ "loader_test_number": 1, "kept": true });
+        let applied = apply_env_overrides(&mut value);
+        for var in [
+            "RSPF_LOADER_TEST_NUMBER",
+            "RSPF_LOADER_TEST_FLAG",
+            "RSPF_LOADER_TEST_LIST",
+            "RSPF_LOADER_TEST_TEXT",
+            "RSPF_LOADER_TEST_QUOTED",
+        ] {
+            std::env::remove_var(var);
+        }
+        let applied = applied.unwrap();
+        // Скаляры остаются строками, числовые поля принимают их сами.
+        assert_eq!(value["loader_test_number"], json!("42"));
+        assert_eq!(value["loader_test_flag"], json!("true"));
+        assert_eq!(value["loader_test_list"], json!([1, 2]));
+        assert_eq!(value["loader_test_text"], json!("0.0.0.0:8080"));
+        // Строка в кавычках берётся как есть, кавычки не снимаются.
+        assert_eq!(value["loader_test_quoted"], json!("\"quoted\""));
+        assert_eq!(value["kept"], json!(true));
+        assert!(applied.contains(&(
+            "loader_test_number".to_string(),
+            "RSPF_LOADER_TEST_NUMBER".to_string()
+        )));
+        assert!(apply_env_overrides(&mut json!([])).is_err());
+    }
+
+    fn rule(value: Value) -> ConfigConnect {
+        from_value::<ConfigConnect>(value).unwrap().0
+    }
+
+    /// Файл после записи: правило `kept` не изменилось, `added` — новое.
+    fn check_persisted(format: ConfigFormat, text: &str) -> String {
+        std::env::set_var("LOADER_TEST_UPSTREAM", "10.0.0.5");
+        let kept = rule(json!({
+            "name": "kept", "local_port": 8080,
+            "remote_address": "10.0.0.5", "remote_port": 80
+        }));
+        let added = rule(json!({
+            "name": "added", "local_port": 8081,
+            "remote_address": "10.0.0.6", "remote_port": 81
+        }));
+        let out = format
+            .replace_connect_list(text, &[kept.clone(), added.clone()])
+            .unwrap();
+        assert!(out.contains("${LOADER_TEST_SECRET}"), "{}", out);
+        assert!(out.contains("${LOADER_TEST_UPSTREAM}"), "{}", out);
+        assert!(!out.contains("removed"), "{}", out);
+
+        std::env::set_var("LOADER_TEST_SECRET", "secret");
+        let mut value = format.parse(&out).unwrap();
+        let mut missing = Vec::new();
+        interpolate(&mut value, String::new(), &mut missing);
+        assert!(missing.is_empty());
+        let (config, _) = from_value::<Config>(value).unwrap();
+        assert_eq!(config.connect_list, [kept, added]);
+        assert_eq!(config.http_admin_token.as_deref(), Some("secret"));
+        out
+    }
+
+    #[test]
+    fn persist_replaces_only_connect_list() {
+        let out = check_persisted(
+            ConfigFormat::Json,
+            r#"{
+                "http_admin_token": "${LOADER_TEST_SECRET}",
+                "connect_list": [
+                    {"name": "kept", "local_port": 8080,
+                     "remote_address": "${LOADER_TEST_UPSTREAM}", "remote_port": 80},
+                    {"name": "removed", "local_port": 9090,
+                     "remote_address": "10.0.0.7", "remote_port": 90}
+                ],
+                "aa_last": 1
+            }"#,
+        );
+        // Порядок ключей сохраняется и в корне, и в неизменённом правиле.
+        let at = |key: &str| out.find(&format!("\"{}\"", key)).unwrap();
+        assert!(at("http_admin_token") < at("connect_list"), "{}", out);
+        assert!(at("connect_list") < at("aa_last"), "{}", out);
+        assert!(at("name") < at("local_port"), "{}", out);
+
+        let out = check_persisted(
+            ConfigFormat::Yaml,
+            "http_admin_token: ${LOADER_TEST_SECRET}\n\
+             zz_last: 1\n\
+             connect_list:\n\
+             - name: kept\n  local_port: 8080\n  remote_address: ${LOADER_TEST_UPSTREAM}\n  remote_port: 80\n\
+             - name: removed\n  local_port: 9090\n  remote_address: 10.0.0.7\n  remote_port: 90\n",
+        );
+        // Порядок ключей сохраняется.
+        assert!(out.find("http_admin_token") < out.find("zz_last"));
+
+        let out = check_persisted(
+            ConfigFormat::Toml,
+            "# токен администратора\n\
+             http_admin_token = \"${LOADER_TEST_SECRET}\"\n\
+             \n\
+             # основной сервис\n\
+             [[connect_list]]\n\
+             name = \"kept\"\n\
+             local_port = 8080\n\
+             remote_address = \"${LOADER_TEST_UPSTREAM}\" # через VPN\n\
+             remote_port = 80\n\
+             \n\
+             [[connect_list]]\n\
+             name = \"removed\"\n\
+             local_port = 9090\n\
+             remote_address = \"10.0.0.7\"\n\
+             remote_port = 90\n",
+        );
+        assert!(out.contains("# токен администратора"), "{}", out);
+        assert!(out.contains("# основной сервис"), "{}", out);
+        assert!(out.contains("# через VPN"), "{}", out);
+    }
+
+    #[test]
+    fn config_dir_is_relative_to_cwd() {
+        let location = ConfigLocation {
+            path: Some("/etc/rs-port-forward/config.json".to_string()),
+            dir: Some("rules.d".to_string()),
+            overrides: CliOverrides::default(),
+        };
+        let (config, _) = from_value::<Config>(json!({ "include": ["extra/*.json"] })).unwrap();
+        let patterns = location.include_patterns(&config);
+        let base = location.base_dir();
+        assert_eq!(base, Path::new("/etc/rs-port-forward"));
+        assert_eq!(
+            base.join(&patterns[0]),
+            Path::new("/etc/rs-port-forward/extra/*.json")
+        );
+        let cwd = std::env::current_dir().unwrap().join("rules.d");
+        assert_eq!(patterns.len(), 1 + include::dir_patterns("").len());
+        for pattern in &patterns[1..] {
+            assert_eq!(Path::new(pattern).parent(), Some(cwd.as_path()));
+            assert_eq!(base.join(pattern), Path::new(pattern));
+        }
+    }
+
+    #[test]
+    fn persist_creates_missing_file() {
+        let added = rule(json!({
+            "name": "web", "local_port": 8080,
+            "remote_address": "10.0.0.5", "remote_port": 80
+        }));
+        for format in [ConfigFormat::Json, ConfigFormat::Yaml, ConfigFormat::Toml] {
+            let out = format
+                .replace_connect_list("", std::slice::from_ref(&added))
+                .unwrap();
+            let (config, _) = from_value::<Config>(format.parse(&out).unwrap()).unwrap();
+            assert_eq!(
+                config.connect_list,
+                std::slice::from_ref(&added),
+                "{:?}",
+                format
+            );
+        }
+    }
+}
@@ -0,0 +1,568 @@
+// In-process statistics for deployments without a database: a bounded ring
+// buffer of recent rows plus hourly per-rule/per-client totals that outlive
+// the ring. Fed straight from the `LogEvent` broadcast and answers the same
+// queries as the SQL backends.
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::warn;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+use crate::db::{
+    self, Breakdown, BreakdownRow, ClientTraffic, ConnectionRow, FailureReason, GroupBy,
+    PruneReport, RetentionPolicy, SessionInfo, SlotRow, StoredConnection, TimeseriesPoint, TopBy,
+    TrafficFilter,
+};
+use crate::events::LogEvent;
+use crate::storage::Storage;
+
+/// Raw rows kept when `memory_stats_rows` is not set.
+pub const DEFAULT_MEMORY_ROWS: usize = 100_000;
+/// Hourly totals are kept this long unless `rollup_retention_days` is set.
+pub const DEFAULT_TOTALS_DAYS: u64 = 30;
+const HOUR: i64 = 3600;
+/// The only rollup level kept in memory.
+const LEVELS: &[(i64, &str)] = &[(HOUR, "hourly")];
+
+/// Key of an hourly total, the in-memory counterpart of a `traffic_hourly` row.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TotalsKey {
+    bucket: i64,
+    name: String,
+    client_addr: Option<String>,
+    remote_address: String,
+    remote_port: u16,
+}
+
+impl TotalsKey {
+    /// Smallest key of a bucket, for range scans.
+    fn first_in(bucket: i64) -> Self {
+        TotalsKey {
+            bucket,
+            name: String::new(),
+            client_addr: None,
+            remote_address: String::new(),
+            remote_port: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Totals {
+    bytes_from_to: u64,
+    bytes_to_from: u64,
+    sessions: u64,
+    errors: u64,
+    timeouts: u64,
+}
+
+/// One row of traffic read either from the ring or from the hourly totals.
+struct TrafficRow<'a> {
+    ts: i64,
+    name: &'a str,
+    client_addr: Option<&'a str>,
+    remote_address: &'a str,
+    remote_port: u16,
+    bytes_from_to: u64,
+    bytes_to_from: u64,
+    sessions: u64,
+    errors: u64,
+    timeouts: u64,
+}
+
+impl TrafficRow<'_> {
+    fn matches(&self, filter: &TrafficFilter) -> bool {
+        filter.name.as_deref().is_none_or(|n| n == self.name)
+            && filter
+                .client_addr
+                .as_deref()
+                .is_none_or(|c| Some(c) == self.client_addr)
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    rows: VecDeque<StoredConnection>,
+    next_id: i64,
+    hourly: BTreeMap<TotalsKey, Totals>,
+}
+
+impl Inner {
+    /// Visits traffic in `[start, end)`, reading whole hours from the totals
+    /// (when `use_hourly`) and the partial edges from the ring, like
+    /// `db::traffic_source` does with the rollup tables.
+    fn for_each_traffic(
+        &self,
+        start: i64,
+        end: i64,
+        use_hourly: bool,
+        mut f: impl FnMut(TrafficRow<'_>),
+    ) {
+        let mut segments = Vec::new();
+        let levels = if use_hourly { LEVELS } else { &[] };
+        db::plan_segments(start, end, levels, "raw", &mut segments);
+        for (source, from, to) in segments {
+            if source == "raw" {
+                for stored in self
+                    .rows
+                    .iter()
+                    .filter(|s| s.row.ts >= from && s.row.ts < to)
+                {
+                    let r = &stored.row;
+                    f(TrafficRow {
+                        ts: r.ts,
+                        name: &r.name,
+                        client_addr: r.client_addr.as_deref(),
+                        remote_address: &r.remote_address,
+                        remote_port: r.remote_port,
+                        bytes_from_to: r.bytes_from_to,
+                        bytes_to_from: r.bytes_to_from,
+                        sessions: (r.log_name == "connection_started") as u64,
+                        errors: (r.log_name == "connection_error") as u64,
+                        timeouts: (r.log_name == "connection_timeout") as u64,
+                    });
+                }
+            } else {
+                let range = TotalsKey::first_in(from)..TotalsKey::first_in(to);
+                for (key, totals) in self.hourly.range(range) {
+                    f(TrafficRow {
+                        ts: key.bucket,
+                        name: &key.name,
+                        client_addr: key.client_addr.as_deref(),
+                        remote_address: &key.remote_address,
+                        remote_port: key.remote_port,
+                        bytes_from_to: totals.bytes_from_to,
+                        bytes_to_from: totals.bytes_to_from,
+                        sessions: totals.sessions,
+                        errors: totals.errors,
+                        timeouts: totals.timeouts,
+                    });
+                }
+            }
+        }
+    }
+
+    fn prune_rows(&mut self, cutoff: i64) -> u64 {
+        let before = self.rows.len();
+        self.rows.retain(|s| s.row.ts >= cutoff);
+        (before - self.rows.len()) as u64
+    }
+
+    fn prune_totals(&mut self, cutoff: i64) -> u64 {
+        let kept = self.hourly.split_off(&TotalsKey::first_in(cutoff));
+        let removed = self.hourly.len() as u64;
+        self.hourly = kept;
+        removed
+    }
+}
+
+pub struct MemoryStorage {
+    inner: Mutex<Inner>,
+    capacity: usize,
+    totals_days: u64,
+}
+
+impl MemoryStorage {
+    /// Keeps up to `capacity` raw rows and `totals_days` of hourly totals.
+    pub fn new(capacity: usize, totals_days: u64) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            capacity,
+            totals_days,
+        }
+    }
+
+    fn insert(&self, rows: &[ConnectionRow]) {
+        let mut inner = self.inner.lock().unwrap();
+        for row in rows {
+            let key = TotalsKey {
+                bucket: row.ts.div_euclid(HOUR) * HOUR,
+                name: row.name.clone(),
+                client_addr: row.client_addr.clone(),
+                remote_address: row.remote_address.clone(),
+                remote_port: row.remote_port,
+            };
+            let totals = inner.hourly.entry(key).or_default();
+            totals.bytes_from_to += row.bytes_from_to;
+            totals.bytes_to_from += row.bytes_to_from;
+            totals.sessions += (row.log_name == "connection_started") as u64;
+            totals.errors += (row.log_name == "connection_error") as u64;
+            totals.timeouts += (row.log_name == "connection_timeout") as u64;
+
+            inner.next_id += 1;
+            let id = inner.next_id;
+            if inner.rows.len() >= self.capacity {
+                inner.rows.pop_front();
+            }
+            inner.rows.push_back(StoredConnection {
+                id,
+                row: row.clone(),
+            });
+        }
+        let cutoff = Utc::now().timestamp() - (self.totals_days as i64) * 86400;
+        if inner
+            .hourly
+            .keys()
+            .next()
+            .is_some_and(|k| k.bucket < cutoff)
+        {
+            inner.prune_totals(cutoff);
+        }
+    }
+
+    /// Reads events until the channel is closed. Lagged events are lost for
+    /// the statistics, which is only reported.
+    pub async fn run(&self, mut rx: broadcast::Receiver<LogEvent>) {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    if let Some(row) = ConnectionRow::from_event(event) {
+                        self.insert(&[row]);
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("In-memory statistics lagged, {} events dropped", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    fn backend(&self) -> &'static str {
+        "memory"
+    }
+
+    async fn insert_connection_rows(&self, rows: &[ConnectionRow]) -> anyhow::Result<()> {
+        self.insert(rows);
+        Ok(())
+    }
+
+    async fn traffic_by_client(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        name: Option<String>,
+    ) -> anyhow::Result<Vec<ClientTraffic>> {
+        let filter = TrafficFilter {
+            name,
+            client_addr: None,
+        };
+        let mut clients: HashMap<Option<String>, (u64, u64)> = HashMap::new();
+        let inner = self.inner.lock().unwrap();
+        inner.for_each_traffic(start.timestamp(), end.timestamp(), true, |r| {
+            if r.matches(&filter) {
+                let entry = clients.entry(r.client_addr.map(String::from)).or_default();
+                entry.0 += r.bytes_from_to;
+                entry.1 += r.bytes_to_from;
+            }
+        });
+        let mut out: Vec<ClientTraffic> = clients
+            .into_iter()
+            .map(
+                |(client_addr, (bytes_from_to, bytes_to_from))| ClientTraffic {
+                    client_addr,
+                    bytes_from_to,
+                    bytes_to_from,
+                },
+            )
+            .collect();
+        out.sort_by_key(|c| std::cmp::Reverse(c.bytes_from_to + c.bytes_to_from));
+        Ok(out)
+    }
+
+    async fn traffic_timeseries(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        step_secs: i64,
+        tz: Tz,
+        group_by: Option<GroupBy>,
+        filter: TrafficFilter,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        db::check_timeseries_range(start, end, step_secs).map_err(anyhow::Error::msg)?;
+        let slot = db::gcd(step_secs, 900);
+        let use_hourly = db::usable_rollup_levels(start, end, step_secs, tz)
+            .iter()
+            .any(|(size, _)| *size == HOUR);
+        let mut slots: HashMap<(i64, Option<String>), (i64, i64, i64)> = HashMap::new();
+        {
+            let inner = self.inner.lock().unwrap();
+            inner.for_each_traffic(start.timestamp(), end.timestamp(), use_hourly, |r| {
+                if !r.matches(&filter) {
+                    return;
+                }
+                let key = group_by.and_then(|g| match g {
+                    GroupBy::Rule => Some(r.name.to_string()),
+                    GroupBy::Client => r.client_addr.map(String::from),
+                    GroupBy::Upstream => Some(format!("{}:{}", r.remote_address, r.remote_port)),
+                });
+                let entry = slots
+                    .entry((r.ts.div_euclid(slot) * slot, key))
+                    .or_default();
+                entry.0 += r.bytes_from_to as i64;
+                entry.1 += r.bytes_to_from as i64;
+                entry.2 += r.sessions as i64;
+            });
+        }
+        let slots = slots
+            .into_iter()
+            .map(
+                |((slot, key), (bytes_from_to, bytes_to_from, connections))| SlotRow {
+                    slot,
+                    key,
+                    bytes_from_to,
+                    bytes_to_from,
+                    connections,
+                },
+            )
+            .collect();
+        Ok(db::slots_to_points(slots, step_secs, tz))
+    }
+
+    async fn traffic_breakdown(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        by: Breakdown,
+        top: TopBy,
+        filter: TrafficFilter,
+        limit: u32,
+    ) -> anyhow::Result<Vec<BreakdownRow>> {
+        type Group = (Option<String>, Option<String>, Option<String>);
+        let mut groups: HashMap<Group, Totals> = HashMap::new();
+        {
+            let inner = self.inner.lock().unwrap();
+            inner.for_each_traffic(start.timestamp(), end.timestamp(), true, |r| {
+                if !r.matches(&filter) {
+                    return;
+                }
+                let name = Some(r.name.to_string());
+                let client_addr = r.client_addr.map(String::from);
+                let upstream = Some(format!("{}:{}", r.remote_address, r.remote_port));
+                let key = match by {
+                    Breakdown::Rule => (name, None, None),
+                    Breakdown::Client => (None, client_addr, None),
+                    Breakdown::Upstream => (None, None, upstream),
+                    Breakdown::ClientRule => (name, client_addr, None),
+                };
+                let totals = groups.entry(key).or_default();
+                totals.bytes_from_to += r.bytes_from_to;
+                totals.bytes_to_from += r.bytes_to_from;
+                totals.sessions += r.sessions;
+                totals.errors += r.errors;
+                totals.timeouts += r.timeouts;
+            });
+        }
+        let mut out: Vec<BreakdownRow> = groups
+            .into_iter()
+            .map(|((name, client_addr, upstream), t)| BreakdownRow {
+                name,
+                client_addr,
+                upstream,
+                bytes_from_to: t.bytes_from_to,
+                bytes_to_from: t.bytes_to_from,
+                connections: t.sessions,
+                errors: t.errors,
+                timeouts: t.timeouts,
+            })
+            .collect();
+        // Same order as `TopBy::sql_order`.
+        out.sort_by(|a, b| {
+            let rank = |r: &BreakdownRow| {
+                let bytes = r.bytes_from_to + r.bytes_to_from;
+                match top {
+                    TopBy::Bytes => (bytes, r.connections),
+                    TopBy::Connections => (r.connections, bytes),
+                }
+            };
+            rank(b).cmp(&rank(a)).then_with(|| {
+                (&a.name, &a.client_addr, &a.upstream).cmp(&(&b.name, &b.client_addr, &b.upstream))
+            })
+        });
+        out.truncate(limit as usize);
+        Ok(out)
+    }
+
+    async fn sessions(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: TrafficFilter,
+        failed_only: bool,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SessionInfo>> {
+        let (start_s, end_s) = (start.timestamp(), end.timestamp());
+        let inner = self.inner.lock().unwrap();
+        let wanted: HashSet<(Option<&str>, u64)> = inner
+            .rows
+            .iter()
+            .map(|s| &s.row)
+            .filter(|r| {
+                r.ts >= start_s
+                    && r.ts < end_s
+                    && filter.name.as_deref().is_none_or(|n| n == r.name)
+                    && filter
+                        .client_addr
+                        .as_deref()
+                        .is_none_or(|c| Some(c) == r.client_addr.as_deref())
+            })
+            .map(|r| (r.instance_id.as_deref(), r.session_id))
+            .collect();
+
+        // Same aggregation as the SQL query: the whole session, not only the
+        // rows inside the range.
+        let mut sessions: HashMap<(Option<String>, u64), SessionInfo> = HashMap::new();
+        for r in inner.rows.iter().map(|s| &s.row) {
+            if !wanted.contains(&(r.instance_id.as_deref(), r.session_id)) {
+                continue;
+            }
+            let key = (r.instance_id.clone(), r.session_id);
+            let s = sessions.entry(key).or_insert_with(|| SessionInfo {
+                session_id: r.session_id,
+                instance_id: r.instance_id.clone(),
+                started: r.ts,
+                closed: None,
+                name: None,
+                local_port: 0,
+                client_addr: None,
+                remote_address: None,
+                remote_port: 0,
+                upstream_ip: None,
+                bytes_from_to: 0,
+                bytes_to_from: 0,
+                duration_ms: None,
+                close_reason: None,
+                outcome: "connection_started".to_string(),
+                error: None,
+            });
+            s.started = s.started.min(r.ts);
+            if r.log_name == "connection_closed" || r.log_name == "connection_error" {
+                s.closed = s.closed.max(Some(r.ts));
+            }
+            s.name = s.name.take().max(Some(r.name.clone()));
+            s.client_addr = s.client_addr.take().max(r.client_addr.clone());
+            s.remote_address = s.remote_address.take().max(Some(r.remote_address.clone()));
+            s.local_port = s.local_port.max(r.local_port);
+            s.remote_port = s.remote_port.max(r.remote_port);
+            s.upstream_ip = s.upstream_ip.take().max(r.upstream_ip.clone());
+            s.bytes_from_to += r.bytes_from_to;
+            s.bytes_to_from += r.bytes_to_from;
+            s.duration_ms = s.duration_ms.max(r.duration_ms);
+            s.close_reason = s.close_reason.take().max(r.close_reason.clone());
+            s.error = s.error.take().max(r.error.clone());
+            // Error beats timeout beats closed beats started.
+            let rank = |outcome: &str| match outcome {
+                "connection_error" => 3,
+                "connection_timeout" => 2,
+                "connection_closed" => 1,
+                _ => 0,
+            };
+            if rank(&r.log_name) > rank(&s.outcome) {
+                s.outcome = r.log_name.clone();
+            }
+        }
+        drop(inner);
+
+        let mut out: Vec<SessionInfo> = sessions
+            .into_values()
+            .filter(|s| {
+                !failed_only
+                    || s.outcome == "connection_error"
+                    || s.outcome == "connection_timeout"
+                    || s.close_reason.as_deref() == Some("io_error")
+            })
+            .collect();
+        out.sort_by_key(|s| std::cmp::Reverse((s.started, s.session_id)));
+        out.truncate(limit as usize);
+        Ok(out)
+    }
+
+    async fn connections_page(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        name: Option<String>,
+        after: (i64, i64),
+        limit: u32,
+    ) -> anyhow::Result<Vec<StoredConnection>> {
+        let (start_s, end_s) = (start.timestamp(), end.timestamp());
+        let inner = self.inner.lock().unwrap();
+        let mut page: Vec<&StoredConnection> = inner
+            .rows
+            .iter()
+            .filter(|s| {
+                s.row.ts >= start_s
+                    && s.row.ts < end_s
+                    && (s.row.ts, s.id) > after
+                    && name.as_deref().is_none_or(|n| n == s.row.name)
+            })
+            .collect();
+        page.sort_by_key(|s| (s.row.ts, s.id));
+        page.truncate(limit as usize);
+        Ok(page.into_iter().cloned().collect())
+    }
+
+    async fn failure_reasons(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: TrafficFilter,
+    ) -> anyhow::Result<Vec<FailureReason>> {
+        let (start_s, end_s) = (start.timestamp(), end.timestamp());
+        let mut reasons: HashMap<(String, Option<String>, Option<String>), (u64, i64)> =
+            HashMap::new();
+        let inner = self.inner.lock().unwrap();
+        for r in inner.rows.iter().map(|s| &s.row) {
+            let failed = r.log_name == "connection_error"
+                || r.log_name == "connection_timeout"
+                || r.close_reason.as_deref() == Some("io_error");
+            if failed
+                && r.ts >= start_s
+                && r.ts < end_s
+                && filter.name.as_deref().is_none_or(|n| n == r.name)
+                && filter
+                    .client_addr
+                    .as_deref()
+                    .is_none_or(|c| Some(c) == r.client_addr.as_deref())
+            {
+                let key = (r.log_name.clone(), r.close_reason.clone(), r.error.clone());
+                let entry = reasons.entry(key).or_insert((0, r.ts));
+                entry.0 += 1;
+                entry.1 = entry.1.max(r.ts);
+            }
+        }
+        drop(inner);
+        let mut out: Vec<FailureReason> = reasons
+            .into_iter()
+            .map(
+                |((log_name, close_reason, error), (count, last_ts))| FailureReason {
+                    log_name,
+                    close_reason,
+                    error,
+                    count,
+                    last_ts,
+                },
+            )
+            .collect();
+        out.sort_by_key(|f| std::cmp::Reverse(f.count));
+        Ok(out)
+    }
+
+    async fn prune(&self, policy: RetentionPolicy) -> anyhow::Result<PruneReport> {
+        let now = Utc::now().timestamp();
+        let mut report = PruneReport::default();
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(days) = policy.raw_days {
+            report.raw_rows = inner.prune_rows(now - (days as i64) * 86400);
+        }
+        if let Some(days) = policy.rollup_days {
+            report.rollup_rows = inner.prune_totals(now - (days as i64) * 86400);
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,731 @@
+// PostgreSQL storage backend (`postgres` feature). Mirrors the SQLite schema
+// and queries so several instances can report into one central database.
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{info, warn};
+use rustls::pki_types::{pem::PemObject, CertificateDer};
+use std::sync::Arc;
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard};
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Client;
+use tokio_postgres_rustls::MakeRustlsConnect;
+
+use crate::db::{
+    check_timeseries_range, gcd, plan_segments, slots_to_points, usable_rollup_levels, Breakdown,
+    BreakdownRow, ClientTraffic, ConnectionRow, FailureReason, GroupBy, PruneReport,
+    RetentionPolicy, SchemaMismatch, SchemaTooNew, SessionInfo, SlotRow, StoredConnection,
+    TimeseriesPoint, TopBy, TrafficFilter, PRUNE_BATCH, ROLLUP_LEVELS, ROLLUP_TABLES,
+};
+use crate::storage::Storage;
+
+/// Ordered schema migrations, versioned independently of SQLite.
+const MIGRATIONS: &[&str] = &[
+    // 1: raw rows and rollups, equivalent to SQLite schema version 3.
+    r#"
+    CREATE TABLE connections (
+        id BIGSERIAL PRIMARY KEY,
+        ts BIGINT NOT NULL,
+        name TEXT,
+        log_name TEXT,
+        local_port INTEGER,
+        remote_address TEXT,
+        remote_port INTEGER,
+        client_addr TEXT,
+        bytes_from_to BIGINT,
+        bytes_to_from BIGINT,
+        session_id BIGINT,
+        upstream_ip TEXT,
+        error TEXT,
+        close_reason TEXT,
+        duration_ms BIGINT
+    );
+    CREATE INDEX idx_connections_ts ON connections(ts);
+    CREATE INDEX idx_connections_client_addr ON connections(client_addr);
+    CREATE INDEX idx_connections_session_id ON connections(session_id);
+    CREATE TABLE traffic_hourly (
+        bucket BIGINT NOT NULL,
+        name TEXT NOT NULL,
+        client_addr TEXT NOT NULL,
+        remote_address TEXT NOT NULL,
+        remote_port INTEGER NOT NULL,
+        bytes_from_to BIGINT NOT NULL DEFAULT 0,
+        bytes_to_from BIGINT NOT NULL DEFAULT 0,
+        sessions BIGINT NOT NULL DEFAULT 0,
+        errors BIGINT NOT NULL DEFAULT 0,
+        timeouts BIGINT NOT NULL DEFAULT 0,
+        PRIMARY KEY (bucket, name, client_addr, remote_address, remote_port)
+    );
+    CREATE TABLE traffic_daily (LIKE traffic_hourly INCLUDING ALL);
+    "#,
+    // 2: keyset pagination for exports walks (ts, id) in order.
+    r#"
+    CREATE INDEX idx_connections_ts_id ON connections(ts, id);
+    "#,
+    // 3: instance that wrote the row; session ids are only unique per instance.
+    r#"
+    ALTER TABLE connections ADD COLUMN instance_id TEXT;
+    "#,
+];
+
+pub struct PgStorage {
+    url: String,
+    tls: MakeRustlsConnect,
+    /// Reconnected lazily if the server drops the connection.
+    client: Mutex<Option<Client>>,
+}
+
+/// TLS for the connection, used as `sslmode` in the URL asks (`prefer` by
+/// default, `require`, or `disable`). Server certificates are checked against
+/// `ca_file` if given, otherwise against the bundled Mozilla roots.
+fn tls_connector(ca_file: Option<&str>) -> anyhow::Result<MakeRustlsConnect> {
+    let mut roots = rustls::RootCertStore::empty();
+    match ca_file {
+        Some(path) => {
+            let certs = CertificateDer::pem_file_iter(path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("failed to read database_ca_file {}", path))?;
+            let (added, _) = roots.add_parsable_certificates(certs);
+            if added == 0 {
+                anyhow::bail!("no usable CA certificates in database_ca_file {}", path);
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+async fn open(url: &str, tls: &MakeRustlsConnect) -> anyhow::Result<Client> {
+    let (client, connection) = tokio_postgres::connect(url, tls.clone()).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("PostgreSQL connection closed: {:#}", anyhow::Error::from(e));
+        }
+    });
+    Ok(client)
+}
+
+/// Applies pending migrations in one transaction. The advisory lock keeps
+/// instances that start at the same time from migrating concurrently.
+async fn migrate(client: &mut Client) -> anyhow::Result<()> {
+    let tx = client.transaction().await?;
+    tx.execute(
+        "SELECT pg_advisory_xact_lock(hashtext('rs-port-forward'))",
+        &[],
+    )
+    .await?;
+    tx.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            applied_at BIGINT NOT NULL
+        )",
+    )
+    .await?;
+    let current: i64 = tx
+        .query_one(
+            "SELECT COALESCE(MAX(version), 0)::BIGINT FROM schema_migrations",
+            &[],
+        )
+        .await?
+        .get(0);
+    let supported = MIGRATIONS.len() as i64;
+    if current > supported {
+        return Err(SchemaTooNew {
+            found: current,
+            supported,
+        }
+        .into());
+    }
+    let now = Utc::now().timestamp();
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(current as usize) {
+        tx.batch_execute(sql).await?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, applied_at) VALUES ($1, $2)",
+            &[&(index as i64 + 1), &now],
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    if current < supported {
+        info!(
+            "PostgreSQL schema migrated from version {} to {}",
+            current, supported
+        );
+    } else {
+        info!("PostgreSQL schema is up to date (version {})", current);
+    }
+    Ok(())
+}
+
+impl PgStorage {
+    pub async fn connect(url: &str, ca_file: Option<&str>) -> anyhow::Result<Self> {
+        let tls = tls_connector(ca_file)?;
+        let mut client = open(url, &tls).await?;
+        migrate(&mut client).await?;
+        Ok(Self {
+            url: url.to_string(),
+            tls,
+            client: Mutex::new(Some(client)),
+        })
+    }
+
+    /// Connects without migrating, for `stats` and `export`; fails with
+    /// [`SchemaMismatch`] unless the schema matches this binary.
+    pub async fn connect_existing(url: &str, ca_file: Option<&str>) -> anyhow::Result<Self> {
+        let tls = tls_connector(ca_file)?;
+        let client = open(url, &tls).await?;
+        let versioned: bool = client
+            .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+            .await?
+            .get(0);
+        let found: i64 = if versioned {
+            client
+                .query_one(
+                    "SELECT COALESCE(MAX(version), 0)::BIGINT FROM schema_migrations",
+                    &[],
+                )
+                .await?
+                .get(0)
+        } else {
+            0
+        };
+        let expected = MIGRATIONS.len() as i64;
+        if found != expected {
+            return Err(SchemaMismatch { found, expected }.into());
+        }
+        Ok(Self {
+            url: url.to_string(),
+            tls,
+            client: Mutex::new(Some(client)),
+        })
+    }
+
+    /// Exclusive access to the connection, like the single SQLite connection.
+    async fn client(&self) -> anyhow::Result<MappedMutexGuard<'_, Client>> {
+        let mut guard = self.client.lock().await;
+        if guard.as_ref().is_none_or(Client::is_closed) {
+            *guard = Some(open(&self.url, &self.tls).await?);
+        }
+        Ok(MutexGuard::map(guard, |c| {
+            c.as_mut().expect("connection was just opened")
+        }))
+    }
+}
+
+/// Positional parameters for dynamically built SQL.
+#[derive(Default)]
+struct Params(Vec<Box<dyn ToSql + Sync + Send>>);
+
+impl Params {
+    /// Adds a value and returns its placeholder, e.g. `$3`.
+    fn push(&mut self, value: impl ToSql + Sync + Send + 'static) -> String {
+        self.0.push(Box::new(value));
+        format!("${}", self.0.len())
+    }
+
+    fn refs(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.0
+            .iter()
+            .map(|v| v.as_ref() as &(dyn ToSql + Sync))
+            .collect()
+    }
+}
+
+/// Same shape as the SQLite `traffic_source`: whole buckets from rollups,
+/// raw rows at the edges.
+fn traffic_source(
+    start: i64,
+    end: i64,
+    levels: &[(i64, &'static str)],
+    params: &mut Params,
+) -> String {
+    let mut segments = Vec::new();
+    plan_segments(start, end, levels, "connections", &mut segments);
+    let parts: Vec<String> = segments
+        .into_iter()
+        .map(|(table, from, to)| {
+            let (from, to) = (params.push(from), params.push(to));
+            if table == "connections" {
+                format!(
+                    "SELECT ts, name, client_addr, remote_address, remote_port,
+                            bytes_from_to, bytes_to_from,
+                            (log_name = 'connection_started')::INT AS sessions,
+                            (log_name = 'connection_error')::INT AS errors,
+                            (log_name = 'connection_timeout')::INT AS timeouts
+                     FROM connections WHERE ts >= {from} AND ts < {to}"
+                )
+            } else {
+                format!(
+                    "SELECT bucket AS ts, NULLIF(name, '') AS name,
+                            NULLIF(client_addr, '') AS client_addr,
+                            NULLIF(remote_address, '') AS remote_address, remote_port,
+                            bytes_from_to, bytes_to_from, sessions, errors, timeouts
+                     FROM {table} WHERE bucket >= {from} AND bucket < {to}"
+                )
+            }
+        })
+        .collect();
+    parts.join(" UNION ALL ")
+}
+
+fn to_u64(v: i64) -> u64 {
+    v.max(0) as u64
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    fn backend(&self) -> &'static str {
+        "postgres"
+    }
+
+    async fn insert_connection_rows(&self, rows: &[ConnectionRow]) -> anyhow::Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let insert = tx
+            .prepare(
+                "INSERT INTO connections (ts, name, log_name, local_port, remote_address, remote_port, client_addr,
+                                          bytes_from_to, bytes_to_from, session_id, upstream_ip, error, close_reason, duration_ms,
+                                          instance_id)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15)",
+            )
+            .await?;
+        for r in rows {
+            tx.execute(
+                &insert,
+                &[
+                    &r.ts,
+                    &r.name,
+                    &r.log_name,
+                    &(r.local_port as i32),
+                    &r.remote_address,
+                    &(r.remote_port as i32),
+                    &r.client_addr,
+                    &(r.bytes_from_to as i64),
+                    &(r.bytes_to_from as i64),
+                    &(r.session_id as i64),
+                    &r.upstream_ip,
+                    &r.error,
+                    &r.close_reason,
+                    &r.duration_ms.map(|d| d as i64),
+                    &r.instance_id,
+                ],
+            )
+            .await?;
+        }
+        for (size, table) in ROLLUP_LEVELS {
+            let upsert = tx
+                .prepare(&format!(
+                    "INSERT INTO {table} AS t (bucket, name, client_addr, remote_address, remote_port,
+                                               bytes_from_to, bytes_to_from, sessions, errors, timeouts)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                     ON CONFLICT (bucket, name, client_addr, remote_address, remote_port) DO UPDATE SET
+                         bytes_from_to = t.bytes_from_to + EXCLUDED.bytes_from_to,
+                         bytes_to_from = t.bytes_to_from + EXCLUDED.bytes_to_from,
+                         sessions = t.sessions + EXCLUDED.sessions,
+                         errors = t.errors + EXCLUDED.errors,
+                         timeouts = t.timeouts + EXCLUDED.timeouts"
+                ))
+                .await?;
+            for r in rows {
+                tx.execute(
+                    &upsert,
+                    &[
+                        &(r.ts.div_euclid(*size) * size),
+                        &r.name,
+                        &r.client_addr.as_deref().unwrap_or(""),
+                        &r.remote_address,
+                        &(r.remote_port as i32),
+                        &(r.bytes_from_to as i64),
+                        &(r.bytes_to_from as i64),
+                        &((r.log_name == "connection_started") as i64),
+                        &((r.log_name == "connection_error") as i64),
+                        &((r.log_name == "connection_timeout") as i64),
+                    ],
+                )
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn traffic_by_client(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        name: Option<String>,
+    ) -> anyhow::Result<Vec<ClientTraffic>> {
+        let mut params = Params::default();
+        let source = traffic_source(
+            start.timestamp(),
+            end.timestamp(),
+            ROLLUP_LEVELS,
+            &mut params,
+        );
+        let name = params.push(name);
+        let sql = format!(
+            "SELECT client_addr,
+                    COALESCE(SUM(bytes_from_to), 0)::BIGINT,
+                    COALESCE(SUM(bytes_to_from), 0)::BIGINT
+             FROM ({source}) AS src
+             WHERE {name}::TEXT IS NULL OR name = {name}
+             GROUP BY client_addr
+             ORDER BY COALESCE(SUM(bytes_from_to), 0) + COALESCE(SUM(bytes_to_from), 0) DESC"
+        );
+        let client = self.client().await?;
+        let rows = client.query(&sql, &params.refs()).await?;
+        Ok(rows
+            .iter()
+            .map(|row| ClientTraffic {
+                client_addr: row.get(0),
+                bytes_from_to: to_u64(row.get(1)),
+                bytes_to_from: to_u64(row.get(2)),
+            })
+            .collect())
+    }
+
+    async fn traffic_timeseries(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        step_secs: i64,
+        tz: Tz,
+        group_by: Option<GroupBy>,
+        filter: TrafficFilter,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        check_timeseries_range(start, end, step_secs).map_err(anyhow::Error::msg)?;
+        let mut params = Params::default();
+        let slot = params.push(gcd(step_secs, 900));
+        let levels = usable_rollup_levels(start, end, step_secs, tz);
+        let source = traffic_source(start.timestamp(), end.timestamp(), &levels, &mut params);
+        let name = params.push(filter.name);
+        let client_addr = params.push(filter.client_addr);
+        let key_expr = group_by.map(GroupBy::sql_expr).unwrap_or("NULL::TEXT");
+        let sql = format!(
+            "SELECT (ts / {slot}) * {slot} AS slot,
+                    {key_expr} AS key,
+                    COALESCE(SUM(bytes_from_to), 0)::BIGINT,
+                    COALESCE(SUM(bytes_to_from), 0)::BIGINT,
+                    COALESCE(SUM(sessions), 0)::BIGINT
+             FROM ({source}) AS src
+             WHERE ({name}::TEXT IS NULL OR name = {name})
+               AND ({client_addr}::TEXT IS NULL OR client_addr = {client_addr})
+             GROUP BY 1, 2"
+        );
+        let client = self.client().await?;
+        let rows = client.query(&sql, &params.refs()).await?;
+        let slots = rows
+            .iter()
+            .map(|row| SlotRow {
+                slot: row.get(0),
+                key: row.get(1),
+                bytes_from_to: row.get(2),
+                bytes_to_from: row.get(3),
+                connections: row.get(4),
+            })
+            .collect();
+        Ok(slots_to_points(slots, step_secs, tz))
+    }
+
+    async fn traffic_breakdown(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        by: Breakdown,
+        top: TopBy,
+        filter: TrafficFilter,
+        limit: u32,
+    ) -> anyhow::Result<Vec<BreakdownRow>> {
+        let mut params = Params::default();
+        let source = traffic_source(
+            start.timestamp(),
+            end.timestamp(),
+            ROLLUP_LEVELS,
+            &mut params,
+        );
+        let name = params.push(filter.name);
+        let client_addr = params.push(filter.client_addr);
+        let limit = params.push(limit as i64);
+        let [name_col, client_col, upstream_col] = by.sql_columns();
+        let order = top.sql_order();
+        let sql = format!(
+            "SELECT {name_col}, {client_col}, {upstream_col},
+                    SUM(bytes_from_to)::BIGINT, SUM(bytes_to_from)::BIGINT, SUM(sessions)::BIGINT,
+                    SUM(errors)::BIGINT, SUM(timeouts)::BIGINT
+             FROM ({source}) AS src
+             WHERE ({name}::TEXT IS NULL OR name = {name})
+               AND ({client_addr}::TEXT IS NULL OR client_addr = {client_addr})
+             GROUP BY 1, 2, 3
+             ORDER BY {order}
+             LIMIT {limit}"
+        );
+        let client = self.client().await?;
+        let rows = client.query(&sql, &params.refs()).await?;
+        Ok(rows
+            .iter()
+            .map(|row| BreakdownRow {
+                name: row.get(0),
+                client_addr: row.get(1),
+                upstream: row.get(2),
+                bytes_from_to: to_u64(row.get(3)),
+                bytes_to_from: to_u64(row.get(4)),
+                connections: to_u64(row.get(5)),
+                errors: to_u64(row.get(6)),
+                timeouts: to_u64(row.get(7)),
+            })
+            .collect())
+    }
+
+    async fn sessions(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: TrafficFilter,
+        failed_only: bool,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SessionInfo>> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "WITH wanted AS (
+                     SELECT DISTINCT COALESCE(instance_id, '') AS instance_id, session_id
+                     FROM connections
+                     WHERE ts >= $1 AND ts < $2 AND session_id IS NOT NULL
+                       AND ($3::TEXT IS NULL OR name = $3)
+                       AND ($4::TEXT IS NULL OR client_addr = $4)
+                 )
+                 SELECT w.session_id,
+                    MIN(ts),
+                    MAX(CASE WHEN log_name IN ('connection_closed', 'connection_error') THEN ts END),
+                    MAX(name), MAX(client_addr), MAX(remote_address), MAX(remote_port),
+                    MAX(upstream_ip),
+                    COALESCE(SUM(bytes_from_to), 0)::BIGINT, COALESCE(SUM(bytes_to_from), 0)::BIGINT,
+                    MAX(duration_ms), MAX(close_reason),
+                    CASE
+                        WHEN COUNT(*) FILTER (WHERE log_name = 'connection_error') > 0 THEN 'connection_error'
+                        WHEN COUNT(*) FILTER (WHERE log_name = 'connection_timeout') > 0 THEN 'connection_timeout'
+                        WHEN COUNT(*) FILTER (WHERE log_name = 'connection_closed') > 0 THEN 'connection_closed'
+                        ELSE 'connection_started'
+                    END,
+                    MAX(error), MAX(local_port), NULLIF(w.instance_id, '')
+                 FROM wanted w
+                 JOIN connections c ON c.session_id = w.session_id
+                     AND COALESCE(c.instance_id, '') = w.instance_id
+                 GROUP BY w.instance_id, w.session_id
+                 HAVING NOT $5
+                    OR COUNT(*) FILTER (WHERE log_name IN ('connection_error', 'connection_timeout')) > 0
+                    OR MAX(close_reason) = 'io_error'
+                 ORDER BY MIN(ts) DESC, w.session_id DESC
+                 LIMIT $6",
+                &[
+                    &start.timestamp(),
+                    &end.timestamp(),
+                    &filter.name,
+                    &filter.client_addr,
+                    &failed_only,
+                    &(limit as i64),
+                ],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let session_id: i64 = row.get(0);
+                let remote_port: Option<i32> = row.get(6);
+                let duration_ms: Option<i64> = row.get(10);
+                let local_port: Option<i32> = row.get(14);
+                SessionInfo {
+                    session_id: session_id as u64,
+                    instance_id: row.get(15),
+                    started: row.get(1),
+                    closed: row.get(2),
+                    name: row.get(3),
+                    local_port: local_port.unwrap_or(0) as u16,
+                    client_addr: row.get(4),
+                    remote_address: row.get(5),
+                    remote_port: remote_port.unwrap_or(0) as u16,
+                    upstream_ip: row.get(7),
+                    bytes_from_to: to_u64(row.get(8)),
+                    bytes_to_from: to_u64(row.get(9)),
+                    duration_ms: duration_ms.map(to_u64),
+                    close_reason: row.get(11),
+                    outcome: row.get(12),
+                    error: row.get(13),
+                }
+            })
+            .collect())
+    }
+
+    async fn connections_page(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        name: Option<String>,
+        after: (i64, i64),
+        limit: u32,
+    ) -> anyhow::Result<Vec<StoredConnection>> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT id, ts, name, log_name, local_port, remote_address, remote_port, client_addr,
+                    bytes_from_to, bytes_to_from, session_id, upstream_ip, error, close_reason, duration_ms,
+                    instance_id
+                 FROM connections
+                 WHERE ts >= $1 AND ts < $2 AND (ts, id) > ($3, $4)
+                   AND ($5::TEXT IS NULL OR name = $5)
+                 ORDER BY ts, id
+                 LIMIT $6",
+                &[
+                    &start.timestamp(),
+                    &end.timestamp(),
+                    &after.0,
+                    &after.1,
+                    &name,
+                    &(limit as i64),
+                ],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| {
+                let local_port: Option<i32> = row.get(4);
+                let remote_port: Option<i32> = row.get(6);
+                let bytes_from_to: Option<i64> = row.get(8);
+                let bytes_to_from: Option<i64> = row.get(9);
+                let session_id: Option<i64> = row.get(10);
+                let duration_ms: Option<i64> = row.get(14);
+                StoredConnection {
+                    id: row.get(0),
+                    row: ConnectionRow {
+                        ts: row.get(1),
+                        name: row.get::<_, Option<String>>(2).unwrap_or_default(),
+                        log_name: row.get::<_, Option<String>>(3).unwrap_or_default(),
+                        local_port: local_port.unwrap_or(0) as u16,
+                        remote_address: row.get::<_, Option<String>>(5).unwrap_or_default(),
+                        remote_port: remote_port.unwrap_or(0) as u16,
+                        client_addr: row.get(7),
+                        bytes_from_to: to_u64(bytes_from_to.unwrap_or(0)),
+                        bytes_to_from: to_u64(bytes_to_from.unwrap_or(0)),
+                        session_id: session_id.unwrap_or(0) as u64,
+                        instance_id: row.get(15),
+                        upstream_ip: row.get(11),
+                        error: row.get(12),
+                        close_reason: row.get(13),
+                        duration_ms: duration_ms.map(to_u64),
+                    },
+                }
+            })
+            .collect())
+    }
+
+    async fn failure_reasons(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: TrafficFilter,
+    ) -> anyhow::Result<Vec<FailureReason>> {
+        let client = self.client().await?;
+        let rows = client
+            .query(
+                "SELECT log_name, close_reason, error, COUNT(*), MAX(ts)
+                 FROM connections
+                 WHERE ts >= $1 AND ts < $2
+                   AND (log_name IN ('connection_error', 'connection_timeout')
+                        OR close_reason = 'io_error')
+                   AND ($3::TEXT IS NULL OR name = $3)
+                   AND ($4::TEXT IS NULL OR client_addr = $4)
+                 GROUP BY log_name, close_reason, error
+                 ORDER BY COUNT(*) DESC",
+                &[
+                    &start.timestamp(),
+                    &end.timestamp(),
+                    &filter.name,
+                    &filter.client_addr,
+                ],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| FailureReason {
+                log_name: row.get(0),
+                close_reason: row.get(1),
+                error: row.get(2),
+                count: to_u64(row.get(3)),
+                last_ts: row.get(4),
+            })
+            .collect())
+    }
+
+    /// Batched deletes; PostgreSQL's autovacuum reclaims the space, so
+    /// `freed_pages` is always 0.
+    async fn prune(&self, policy: RetentionPolicy) -> anyhow::Result<PruneReport> {
+        let now = Utc::now().timestamp();
+        let mut targets: Vec<(&str, &str, i64, bool)> = Vec::new();
+        if let Some(days) = policy.raw_days {
+            targets.push(("connections", "ts", now - (days as i64) * 86400, true));
+        }
+        if let Some(days) = policy.rollup_days {
+            for (table, column) in ROLLUP_TABLES {
+                targets.push((table, column, now - (days as i64) * 86400, false));
+            }
+        }
+        let mut report = PruneReport::default();
+        for (table, column, cutoff, raw) in targets {
+            let sql = format!(
+                "DELETE FROM {table} WHERE ctid IN (SELECT ctid FROM {table} WHERE {column} < $1 LIMIT $2)"
+            );
+            loop {
+                // Lock per batch so the writer can interleave.
+                let deleted = self
+                    .client()
+                    .await?
+                    .execute(&sql, &[&cutoff, &PRUNE_BATCH])
+                    .await?;
+                if raw {
+                    report.raw_rows += deleted;
+                } else {
+                    report.rollup_rows += deleted;
+                }
+                if (deleted as i64) < PRUNE_BATCH {
+                    break;
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs the shared storage cases against `RSPF_TEST_PG_URL`. The test
+    /// empties the tables first, so point it at a scratch database.
+    #[tokio::test]
+    async fn postgres_backend() {
+        let Ok(url) = std::env::var("RSPF_TEST_PG_URL") else {
+            eprintln!("RSPF_TEST_PG_URL is not set, skipping");
+            return;
+        };
+        let ca_file = std::env::var("RSPF_TEST_PG_CA_FILE").ok();
+        let storage = PgStorage::connect(&url, ca_file.as_deref()).await.unwrap();
+        storage
+            .client()
+            .await
+            .unwrap()
+            .batch_execute("TRUNCATE connections, traffic_hourly, traffic_daily")
+            .await
+            .unwrap();
+        crate::storage::tests::check_backend(&storage).await;
+    }
+}
@@ -0,0 +1,810 @@
+// Управление правилами проброса во время работы: запуск/остановка слушателей,
+// сохранение конфига на диск и журнал аудита изменений.
+use log::{error, info, warn};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::events::LogEvent;
+use crate::loader::{self, ConfigFormat, CLI_SOURCE};
+use crate::{bind_listeners, port_forward, Config, ConfigConnect, RuleDefaults};
+use crate::{schedule, validate};
+
+/// Автор изменений в журнале аудита при перечитывании подключаемых файлов.
+const RELOAD_ACTOR: &str = "config-reload";
+
+/// Ошибка операции над правилом; преобразуется в HTTP‑статус в `web`.
+#[derive(Debug)]
+pub enum RuleError {
+    /// Правило с таким именем не найдено.
+    NotFound(String),
+    /// Имя или локальный порт уже заняты другим правилом.
+    Conflict(String),
+    /// Некорректное описание правила.
+    Invalid(String),
+    /// Не удалось открыть локальный порт.
+    Bind(std::io::Error),
+    /// Правило применено, но конфиг не удалось сохранить на диск.
+    Persist(std::io::Error),
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleError::NotFound(name) => write!(f, "rule '{}' not found", name),
+            RuleError::Conflict(msg) => write!(f, "{}", msg),
+            RuleError::Invalid(msg) => write!(f, "{}", msg),
+            RuleError::Bind(e) => write!(f, "failed to bind local {}", e),
+            RuleError::Persist(e) => write!(f, "rule applied, but config was not saved: {}", e),
+        }
+    }
+}
+
+/// Запись журнала аудита (одна строка JSON на изменение).
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    ts: chrono::DateTime<chrono::Utc>,
+    actor: &'a str,
+    action: &'a str,
+    name: &'a str,
+    before: Option<&'a ConfigConnect>,
+    after: Option<&'a ConfigConnect>,
+    diff: Value,
+}
+
+struct Rules {
+    /// Актуальный конфиг: `connect_list` отражает запущенные правила.
+    config: Config,
+    /// Задачи accept‑циклов по имени правила, по одной на локальный порт.
+    handles: HashMap<String, Vec<JoinHandle<()>>>,
+}
+
+/// Реестр запущенных правил. Все изменения сериализуются через один мьютекс,
+/// поэтому проверка конфликтов, запуск слушателя и запись конфига атомарны
+/// относительно друг друга.
+pub struct RuleManager {
+    rules: Mutex<Rules>,
+    log_tx: broadcast::Sender<LogEvent>,
+    /// Путь к файлу конфига; `Some`, если он есть и включено `persist_rule_changes`.
+    persist_path: Option<String>,
+    /// Путь к журналу аудита (`audit_log_path`).
+    audit_path: Option<String>,
+    /// Настройки правил по умолчанию (`defaults`); меняются только перезапуском.
+    defaults: RuleDefaults,
+    /// Адрес, на котором открываются порты всех правил (`--listen-override`).
+    listen_override: Option<IpAddr>,
+}
+
+impl RuleManager {
+    pub fn new(
+        config: Config,
+        config_path: Option<String>,
+        listen_override: Option<IpAddr>,
+        log_tx: broadcast::Sender<LogEvent>,
+    ) -> Self {
+        // Без файла конфига (только флаги командной строки) сохранять некуда.
+        let mut persist_path = config_path.filter(|_| config.persist_rule_changes.unwrap_or(false));
+        // Правила из `RSPF_CONNECT_LIST` не должны становиться частью файла.
+        if persist_path.is_some() && loader::env_overrides("connect_list") {
+            warn!("persist_rule_changes is ignored because RSPF_CONNECT_LIST is set");
+            persist_path = None;
+        }
+        let audit_path = config.audit_log_path.clone();
+        let defaults = config.rule_defaults();
+        RuleManager {
+            rules: Mutex::new(Rules {
+                config,
+                handles: HashMap::new(),
+            }),
+            log_tx,
+            persist_path,
+            audit_path,
+            defaults,
+            listen_override,
+        }
+    }
+
+    /// Запускает все правила из исходного конфига. Ошибки открытия портов
+    /// печатаются, остальные правила всё равно запускаются; возвращает число
+    /// правил, которые не удалось запустить.
+    pub async fn start_all(&self) -> usize {
+        let mut rules = self.rules.lock().await;
+        let list = rules.config.connect_list.clone();
+        let mut failed = 0;
+        for (index, item) in list.iter().enumerate() {
+            match self.spawn(item).await {
+                Ok(handles) => {
+                    rules.handles.insert(item.name.clone(), handles);
+                }
+                Err(e) => {
+                    error!(
+                        "connect_list[{}] ({}): cannot listen on {}",
+                        index, item.name, e
+                    );
+                    failed += 1;
+                }
+            }
+        }
+        failed
+    }
+
+    /// Текущий список правил в порядке конфига.
+    pub async fn list(&self) -> Vec<ConfigConnect> {
+        self.rules.lock().await.config.connect_list.clone()
+    }
+
+    pub async fn create(&self, rule: ConfigConnect, actor: &str) -> Result<(), RuleError> {
+        let mut rules = self.rules.lock().await;
+        check_rule(&rules.config, &rule, None)?;
+        let handles = self.spawn(&rule).await.map_err(RuleError::Bind)?;
+        rules.handles.insert(rule.name.clone(), handles);
+        rules.config.connect_list.push(rule.clone());
+        self.audit(actor, "create", &rule.name, None, Some(&rule))
+            .await;
+        self.persist(&rules.config).await
+    }
+
+    pub async fn update(
+        &self,
+        name: &str,
+        rule: ConfigConnect,
+        actor: &str,
+    ) -> Result<(), RuleError> {
+        let mut rules = self.rules.lock().await;
+        let index = find(&rules.config.connect_list, name)?;
+        check_not_included(&rules.config.connect_list[index])?;
+        check_rule(&rules.config, &rule, Some(index))?;
+        let before = rules.config.connect_list[index].clone();
+
+        // Старый слушатель нужно закрыть до открытия нового: порт может совпадать.
+        if let Some(handles) = rules.handles.remove(name) {
+            stop(handles).await;
+        }
+        let handles = match self.spawn(&rule).await {
+            Ok(handles) => handles,
+            Err(e) => {
+                // Возвращаем прежнее правило, чтобы неудачный PUT ничего не сломал.
+                if let Ok(handles) = self.spawn(&before).await {
+                    rules.handles.insert(before.name.clone(), handles);
+                }
+                return Err(RuleError::Bind(e));
+            }
+        };
+        rules.handles.insert(rule.name.clone(), handles);
+        rules.config.connect_list[index] = rule.clone();
+        self.audit(actor, "update", name, Some(&before), Some(&rule))
+            .await;
+        self.persist(&rules.config).await
+    }
+
+    pub async fn delete(&self, name: &str, actor: &str) -> Result<(), RuleError> {
+        let mut rules = self.rules.lock().await;
+        let index = find(&rules.config.connect_list, name)?;
+        check_not_included(&rules.config.connect_list[index])?;
+        if let Some(handles) = rules.handles.remove(name) {
+            stop(handles).await;
+        }
+        let before = rules.config.connect_list.remove(index);
+        self.audit(actor, "delete", name, Some(&before), None).await;
+        self.persist(&rules.config).await
+    }
+
+    /// Заменяет правила из подключаемых файлов новым набором: удалённые и
+    /// изменённые правила останавливаются, новые и изменённые запускаются.
+    /// Набор целиком проверяется вместе с правилами основного конфига; при
+    /// ошибке ничего не меняется.
+    pub async fn replace_included(&self, list: Vec<ConfigConnect>) -> Result<String, String> {
+        let mut rules = self.rules.lock().await;
+        let mut merged = rules.config.clone();
+        merged.connect_list.retain(|r| !r.is_included());
+        merged.connect_list.extend(list.iter().cloned());
+        let problems = validate::rule_list_problems(&merged);
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+            return Err(problems.join("; "));
+        }
+
+        let before: Vec<ConfigConnect> = rules
+            .config
+            .connect_list
+            .iter()
+            .filter(|r| r.is_included())
+            .cloned()
+            .collect();
+        let (mut added, mut updated, mut removed) = (0, 0, 0);
+        // Сначала закрываем старые слушатели: порт может перейти к другому правилу.
+        for old in &before {
+            if list.iter().any(|new| new == old) {
+                continue;
+            }
+            if let Some(handles) = rules.handles.remove(&old.name) {
+                stop(handles).await;
+            }
+            if !list.iter().any(|new| new.name == old.name) {
+                removed += 1;
+                self.audit(RELOAD_ACTOR, "delete", &old.name, Some(old), None)
+                    .await;
+            }
+        }
+        for new in &list {
+            let old = before.iter().find(|old| old.name == new.name);
+            if old == Some(new) {
+                continue;
+            }
+            match self.spawn(new).await {
+                Ok(handles) => {
+                    rules.handles.insert(new.name.clone(), handles);
+                }
+                Err(e) => error!(
+                    "{}: rule '{}' cannot listen on {}",
+                    new.source.as_deref().unwrap_or_default(),
+                    new.name,
+                    e
+                ),
+            }
+            let action = if old.is_some() { "update" } else { "create" };
+            if old.is_some() {
+                updated += 1;
+            } else {
+                added += 1;
+            }
+            self.audit(RELOAD_ACTOR, action, &new.name, old, Some(new))
+                .await;
+        }
+        rules.config.connect_list = merged.connect_list;
+        Ok(format!(
+            "{} added, {} updated, {} removed",
+            added, updated, removed
+        ))
+    }
+
+    /// Открывает порты правила и запускает accept‑цикл на каждом. Порты
+    /// открываются до `spawn`, чтобы ошибка (например, порт занят) вернулась
+    /// вызывающему, а правило не запустилось наполовину. Выключенное правило
+    /// не запускается; правило с расписанием получает одну задачу, которая
+    /// открывает и закрывает порты по окнам.
+    async fn spawn(&self, rule: &ConfigConnect) -> std::io::Result<Vec<JoinHandle<()>>> {
+        if !rule.is_enabled() {
+            info!("Proxy {} is disabled", rule.name);
+            return Ok(Vec::new());
+        }
+        let settings = rule.settings(&self.defaults, self.listen_override);
+        if let Some(schedule) = &rule.schedule {
+            let ports = if schedule.is_open(chrono::Utc::now()) {
+                Some(bind_listeners(rule, &settings).await?)
+            } else {
+                None
+            };
+            let task = schedule::run(
+                rule.clone(),
+                schedule.clone(),
+                settings,
+                ports,
+                self.log_tx.clone(),
+            );
+            return Ok(vec![tokio::spawn(task)]);
+        }
+        let ports = bind_listeners(rule, &settings).await?;
+        info!(
+            "Proxy start {} at {} to {}:{}",
+            rule.name,
+            rule.local_ports_text(),
+            rule.remote_address,
+            rule.remote_ports_text()
+        );
+        Ok(ports
+            .into_iter()
+            .map(|port| {
+                let rule = rule.clone();
+                let log_tx = self.log_tx.clone();
+                tokio::spawn(async move {
+                    port_forward(port, &rule, settings, None, log_tx).await;
+                })
+            })
+            .collect())
+    }
+
+    /// Атомарно записывает `connect_list` в файл конфига: во временный файл
+    /// рядом, затем `rename`. Остальные поля файла не меняются.
+    async fn persist(&self, config: &Config) -> Result<(), RuleError> {
+        let Some(path) = &self.persist_path else {
+            return Ok(());
+        };
+        // В файл попадают только его собственные правила, без подключаемых и `-L`.
+        let list: Vec<ConfigConnect> = config
+            .connect_list
+            .iter()
+            .filter(|r| r.source.is_none())
+            .cloned()
+            .collect();
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || write_config(&path, &list))
+            .await
+            .map_err(|e| RuleError::Persist(std::io::Error::other(e)))?
+            .map_err(RuleError::Persist)
+    }
+
+    async fn audit(
+        &self,
+        actor: &str,
+        action: &str,
+        name: &str,
+        before: Option<&ConfigConnect>,
+        after: Option<&ConfigConnect>,
+    ) {
+        let record = AuditRecord {
+            ts: chrono::Utc::now(),
+            actor,
+            action,
+            name,
+            before,
+            after,
+            diff: diff(before, after),
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit record: {}", e);
+                return;
+            }
+        };
+        info!("Audit: {}", line);
+        let Some(path) = &self.audit_path else {
+            return;
+        };
+        let write = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(format!("{}\n", line).as_bytes()).await
+        };
+        if let Err(e) = write.await {
+            error!("Failed to write audit log '{}': {}", path, e);
+        }
+    }
+}
+
+/// Заменяет `connect_list` в файле `path`. Символьная ссылка остаётся на
+/// месте, меняется файл, на который она указывает. Новый файл создаётся
+/// с недоступным другим пользователям случайным именем и получает права
+/// прежнего: в конфиге бывают токены и пароли.
+fn write_config(path: &str, list: &[ConfigConnect]) -> std::io::Result<()> {
+    let target = match std::fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => PathBuf::from(path),
+        Err(e) => return Err(e),
+    };
+    let (text, permissions) = match std::fs::read_to_string(&target) {
+        Ok(text) => (text, Some(std::fs::metadata(&target)?.permissions())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (String::new(), None),
+        Err(e) => return Err(e),
+    };
+    // Файл перезаписывается в своём формате (JSON, YAML или TOML).
+    let data = ConfigFormat::from_path(path)
+        .replace_connect_list(&text, list)
+        .map_err(std::io::Error::other)?;
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    tmp.write_all(data.as_bytes())?;
+    if let Some(permissions) = permissions {
+        tmp.as_file().set_permissions(permissions)?;
+    }
+    tmp.as_file().sync_all()?;
+    tmp.persist(&target).map_err(|e| e.error)?;
+    Ok(())
+}
+
+async fn stop(handles: Vec<JoinHandle<()>>) {
+    for handle in &handles {
+        handle.abort();
+    }
+    // Дожидаемся отмены, чтобы слушатели были закрыты и порты освободились.
+    for handle in handles {
+        let _ = handle.await;
+    }
+}
+
+fn find(list: &[ConfigConnect], name: &str) -> Result<usize, RuleError> {
+    list.iter()
+        .position(|c| c.name == name)
+        .ok_or_else(|| RuleError::NotFound(name.to_string()))
+}
+
+/// Правила из подключаемых файлов меняются только правкой этих файлов,
+/// правила из `-L` — только перезапуском.
+fn check_not_included(rule: &ConfigConnect) -> Result<(), RuleError> {
+    match rule.source.as_deref() {
+        Some(CLI_SOURCE) => Err(RuleError::Conflict(format!(
+            "rule '{}' comes from the command line, restart to change it",
+            rule.name
+        ))),
+        Some(file) => Err(RuleError::Conflict(format!(
+            "rule '{}' comes from '{}', edit that file instead",
+            rule.name, file
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Проверяет правило так же, как конфиг при запуске: сначала его поля
+/// (`Invalid`), затем весь список вместе с ним — повторы имён, пересечения
+/// портов между правилами и с `http_listen` (`Conflict`). В ответе все
+/// найденные проблемы. `replace` — индекс обновляемого правила.
+fn check_rule(
+    config: &Config,
+    rule: &ConfigConnect,
+    replace: Option<usize>,
+) -> Result<(), RuleError> {
+    let problems: Vec<String> = validate::rule_problems(rule)
+        .iter()
+        .map(|(field, message)| format!("{} {}", field, message))
+        .collect();
+    if !problems.is_empty() {
+        return Err(RuleError::Invalid(problems.join("; ")));
+    }
+    let mut candidate = config.clone();
+    match replace {
+        Some(index) => candidate.connect_list[index] = rule.clone(),
+        None => candidate.connect_list.push(rule.clone()),
+    }
+    let problems: Vec<String> = validate::rule_list_problems(&candidate)
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+    if !problems.is_empty() {
+        return Err(RuleError::Conflict(problems.join("; ")));
+    }
+    Ok(())
+}
+
+/// Поле‑за‑полем разница между версиями правила: `{ field: { before, after } }`.
+fn diff(before: Option<&ConfigConnect>, after: Option<&ConfigConnect>) -> Value {
+    let to_map = |c: Option<&ConfigConnect>| match c.map(serde_json::to_value) {
+        Some(Ok(Value::Object(map))) => map,
+        _ => Map::new(),
+    };
+    let before = to_map(before);
+    let after = to_map(after);
+    let mut out = Map::new();
+    for key in before
+        .keys()
+        .chain(after.keys().filter(|k| !before.contains_key(*k)))
+    {
+        let b = before.get(key).cloned().unwrap_or(Value::Null);
+        let a = after.get(key).cloned().unwrap_or(Value::Null);
+        if a != b {
+            out.insert(key.clone(), json!({ "before": b, "after": a }));
+        }
+    }
+    Value::Object(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::{CliOverrides, ConfigLocation};
+
+    fn rule(value: Value) -> ConfigConnect {
+        loader::from_value::<ConfigConnect>(value).unwrap().0
+    }
+
+    /// Менеджер без файла конфига; `audit` — путь журнала аудита.
+    fn manager(list: Vec<ConfigConnect>, audit: Option<&Path>) -> RuleManager {
+        let mut value = json!({});
+        if let Some(audit) = audit {
+            value["audit_log_path"] = json!(audit.to_string_lossy());
+        }
+        let (mut config, _) = loader::from_value::<Config>(value).unwrap();
+        // `source` не читается из JSON, поэтому список задаётся напрямую.
+        config.connect_list = list;
+        let (log_tx, _) = broadcast::channel(16);
+        RuleManager::new(config, None, None, log_tx)
+    }
+
+    fn disabled(name: &str, port: u16) -> ConfigConnect {
+        rule(json!({
+            "name": name, "local_port": port, "remote_address": "h", "remote_port": 80,
+            "enabled": false
+        }))
+    }
+
+    /// Свободный порт на 127.0.0.1 (слушатель сразу закрывается).
+    async fn free_port() -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn names(list: &[ConfigConnect]) -> Vec<&str> {
+        list.iter().map(|r| r.name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn create_update_delete() {
+        let manager = manager(Vec::new(), None);
+        manager.create(disabled("a", 18601), "t").await.unwrap();
+        manager.create(disabled("b", 18602), "t").await.unwrap();
+        assert_eq!(names(&manager.list().await), ["a", "b"]);
+
+        // Переименование сохраняет позицию правила в списке.
+        manager
+            .update("a", disabled("a2", 18603), "t")
+            .await
+            .unwrap();
+        let list = manager.list().await;
+        assert_eq!(names(&list), ["a2", "b"]);
+        assert_eq!(list[0].local_port, Some(18603));
+
+        manager.delete("b", "t").await.unwrap();
+        assert_eq!(names(&manager.list().await), ["a2"]);
+        assert!(matches!(
+            manager.delete("b", "t").await,
+            Err(RuleError::NotFound(name)) if name == "b"
+        ));
+        assert!(matches!(
+            manager.update("b", disabled("b", 18602), "t").await,
+            Err(RuleError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn invalid_and_conflicting_rules() {
+        let manager = manager(Vec::new(), None);
+        manager.create(disabled("a", 18611), "t").await.unwrap();
+
+        let mut bad = disabled("", 0);
+        bad.remote_address = String::new();
+        match manager.create(bad, "t").await {
+            Err(RuleError::Invalid(message)) => assert_eq!(
+                message,
+                "name must not be empty; local_port must not be 0; remote_address must not be empty"
+            ),
+            other => panic!("{:?}", other.err()),
+        }
+        match manager.create(disabled("a", 18612), "t").await {
+            Err(RuleError::Conflict(message)) => {
+                assert!(message.contains("duplicate name 'a'"), "{}", message)
+            }
+            other => panic!("{:?}", other.err()),
+        }
+        match manager.create(disabled("b", 18611), "t").await {
+            Err(RuleError::Conflict(message)) => {
+                assert!(
+                    message.contains("duplicate local_port 18611"),
+                    "{}",
+                    message
+                )
+            }
+            other => panic!("{:?}", other.err()),
+        }
+        // Обновление может оставить правилу его же имя и порт.
+        let mut same = disabled("a", 18611);
+        same.remote_port = Some(81);
+        manager.update("a", same, "t").await.unwrap();
+        assert_eq!(names(&manager.list().await), ["a"]);
+
+        // Правила из `-L` и подключаемых файлов через API не меняются.
+        let mut cli = disabled("cli", 18613);
+        cli.source = Some(CLI_SOURCE.to_string());
+        let mut included = disabled("inc", 18614);
+        included.source = Some("d/inc.json".to_string());
+        let manager = self::manager(vec![cli, included], None);
+        for name in ["cli", "inc"] {
+            assert!(matches!(
+                manager.delete(name, "t").await,
+                Err(RuleError::Conflict(_))
+            ));
+            assert!(matches!(
+                manager.update(name, disabled(name, 18615), "t").await,
+                Err(RuleError::Conflict(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_update_restores_old_listener() {
+        let old_port = free_port().await;
+        let busy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let busy_port = busy.local_addr().unwrap().port();
+        let listening = |port: u16| {
+            rule(json!({
+                "name": "web", "local_port": port, "remote_address": "127.0.0.1",
+                "remote_port": 1, "bind_address": "127.0.0.1"
+            }))
+        };
+        let manager = manager(Vec::new(), None);
+        manager.create(listening(old_port), "t").await.unwrap();
+
+        let result = manager.update("web", listening(busy_port), "t").await;
+        assert!(
+            matches!(result, Err(RuleError::Bind(_))),
+            "{:?}",
+            result.err()
+        );
+        assert_eq!(manager.list().await[0].local_port, Some(old_port));
+        // Прежний порт снова принимает подключения.
+        tokio::net::TcpStream::connect(("127.0.0.1", old_port))
+            .await
+            .unwrap();
+        manager.delete("web", "t").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn audit_records_every_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let manager = manager(Vec::new(), Some(&path));
+        manager.create(disabled("a", 18621), "alice").await.unwrap();
+        let mut changed = disabled("a", 18622);
+        changed.remote_port = Some(81);
+        manager.update("a", changed, "bob").await.unwrap();
+        manager.delete("a", "carol").await.unwrap();
+        // Отклонённые изменения в журнал не попадают.
+        assert!(manager.delete("a", "dave").await.is_err());
+
+        let records: Vec<Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        let fields = |r: &Value| {
+            (
+                r["actor"].as_str().unwrap().to_string(),
+                r["action"].as_str().unwrap().to_string(),
+                r["name"].as_str().unwrap().to_string(),
+            )
+        };
+        assert_eq!(
+            fields(&records[0]),
+            ("alice".into(), "create".into(), "a".into())
+        );
+        assert_eq!(
+            fields(&records[1]),
+            ("bob".into(), "update".into(), "a".into())
+        );
+        assert_eq!(
+            fields(&records[2]),
+            ("carol".into(), "delete".into(), "a".into())
+        );
+        assert!(records[0]["ts"].as_str().is_some());
+        assert_eq!(records[0]["before"], Value::Null);
+        assert_eq!(records[0]["after"]["local_port"], json!(18621));
+        assert_eq!(
+            records[1]["diff"],
+            json!({
+                "local_port": { "before": 18621, "after": 18622 },
+                "remote_port": { "before": 80, "after": 81 }
+            })
+        );
+        assert_eq!(records[2]["after"], Value::Null);
+        assert_eq!(
+            records[2]["diff"]["name"],
+            json!({ "before": "a", "after": null })
+        );
+    }
+
+    #[test]
+    fn diff_lists_changed_fields() {
+        let before = disabled("a", 8080);
+        let mut after = before.clone();
+        assert_eq!(diff(Some(&before), Some(&after)), json!({}));
+
+        after.enabled = None;
+        after.bind_address = Some("127.0.0.1".parse().unwrap());
+        assert_eq!(
+            diff(Some(&before), Some(&after)),
+            json!({
+                "enabled": { "before": false, "after": null },
+                "bind_address": { "before": null, "after": "127.0.0.1" }
+            })
+        );
+        let created = diff(None, Some(&before));
+        assert_eq!(created["name"], json!({ "before": null, "after": "a" }));
+        assert_eq!(
+            created["local_port"],
+            json!({ "before": null, "after": 8080 })
+        );
+        assert_eq!(diff(None, None), json!({}));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn persist_keeps_permissions_and_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("real.json");
+        std::fs::write(
+            &real,
+            r#"{"persist_rule_changes": true, "http_admin_token": "secret", "connect_list": []}"#,
+        )
+        .unwrap();
+        std::fs::set_permissions(&real, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let link = dir.path().join("config.json");
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        let link = link.to_string_lossy().into_owned();
+
+        let (config, _) = loader::from_value::<Config>(
+            serde_json::from_str(&std::fs::read_to_string(&real).unwrap()).unwrap(),
+        )
+        .unwrap();
+        let (log_tx, _) = broadcast::channel(16);
+        let manager = RuleManager::new(config, Some(link.clone()), None, log_tx);
+        let api = rule(json!({
+            "name": "api", "local_port": 8081, "remote_address": "h", "remote_port": 81,
+            "enabled": false
+        }));
+        manager.create(api, "test").await.unwrap();
+
+        let meta = std::fs::symlink_metadata(&link).unwrap();
+        assert!(meta.file_type().is_symlink());
+        let mode = std::fs::metadata(&real).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+        assert!(std::fs::read_to_string(&real).unwrap().contains("\"api\""));
+        // Временных файлов рядом не остаётся.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn cli_overrides_are_not_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(
+            &path,
+            r#"{"persist_rule_changes": true, "http_listen": "127.0.0.1:18080", "connect_list": []}"#,
+        )
+        .unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let mut forward = rule(json!({
+            "name": "8080:h:80", "local_port": 8080, "remote_address": "h", "remote_port": 80,
+            "enabled": false
+        }));
+        forward.source = Some(CLI_SOURCE.to_string());
+        let location = ConfigLocation {
+            path: Some(path.clone()),
+            dir: None,
+            overrides: CliOverrides {
+                forwards: vec![forward],
+                http_listen: Some("0.0.0.0:9999".to_string()),
+                db: Some("/tmp/adhoc.db".to_string()),
+            },
+        };
+        let config = location.load().unwrap();
+        assert_eq!(config.http_listen.as_deref(), Some("0.0.0.0:9999"));
+
+        let (log_tx, _) = broadcast::channel(16);
+        let manager = RuleManager::new(config, Some(path.clone()), None, log_tx);
+        // Выключенное правило не открывает порт.
+        let api = rule(json!({
+            "name": "api", "local_port": 8081, "remote_address": "h", "remote_port": 81,
+            "enabled": false
+        }));
+        manager.create(api, "test").await.unwrap();
+
+        // Сравнивается текст, поэтому проверяется и порядок ключей.
+        let saved = std::fs::read_to_string(&path).unwrap();
+        let expected = serde_json::to_string_pretty(&json!({
+            "persist_rule_changes": true,
+            "http_listen": "127.0.0.1:18080",
+            "connect_list": [{
+                "name": "api", "local_port": 8081, "remote_port": 81,
+                "remote_address": "h", "enabled": false
+            }]
+        }))
+        .unwrap();
+        assert_eq!(saved, expected);
+        // Правило из `-L` по-прежнему работает, но только в памяти.
+        assert_eq!(manager.list().await.len(), 2);
+    }
+}
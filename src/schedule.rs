@@ -0,0 +1,525 @@
+// Расписание доступности правила: окна по дням недели и времени суток в
+// заданном часовом поясе. Вне окон порты правила закрыты; задача `run`
+// открывает и закрывает слушатели на границах окон и сообщает о каждом
+// переходе событиями `rule_opened` / `rule_closed`.
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDate, Utc, Weekday};
+use chrono_tz::Tz;
+use log::{error, info};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::sync::{broadcast, watch};
+use tokio::time::Duration;
+
+use crate::events::LogEvent;
+use crate::{bind_listeners, db, port_forward, BoundPort, ConfigConnect, RuleSettings};
+
+/// Как часто перепроверять расписание, даже если до границы окна далеко:
+/// системные часы могут быть переведены. С тем же интервалом повторяется
+/// открытие портов, если в начале окна они были заняты.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Расписание правила (`schedule`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Schedule {
+    /// Часовой пояс окон, имя IANA (`Europe/Moscow`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(extend("default" = "UTC"))]
+    timezone: Option<String>,
+    /// Окна, в которые правило доступно; вне их порты закрыты.
+    windows: Vec<Window>,
+    /// Закрывать открытые сессии в конце окна. По умолчанию закрываются только
+    /// порты, начатые сессии доживают сами.
+    #[serde(
+        default,
+        deserialize_with = "crate::loader::scalar",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[schemars(extend("default" = false))]
+    close_sessions: Option<bool>,
+}
+
+/// Окно доступности: с `from` до `to` в каждый из дней `days`. Если `to` не
+/// позже `from`, окно переходит через полночь и заканчивается на следующий день.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct Window {
+    /// Дни недели, в которые окно начинается; по умолчанию — каждый день.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days: Option<Vec<Day>>,
+    /// Начало окна по местному времени, `"HH:MM"`.
+    from: TimeOfDay,
+    /// Конец окна, `"HH:MM"`; `"24:00"` — до конца суток.
+    to: TimeOfDay,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Day {
+    fn weekday(self) -> Weekday {
+        match self {
+            Day::Mon => Weekday::Mon,
+            Day::Tue => Weekday::Tue,
+            Day::Wed => Weekday::Wed,
+            Day::Thu => Weekday::Thu,
+            Day::Fri => Weekday::Fri,
+            Day::Sat => Weekday::Sat,
+            Day::Sun => Weekday::Sun,
+        }
+    }
+}
+
+/// Время суток `"HH:MM"` с точностью до минуты; допускается `"24:00"`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    /// Минуты от полуночи, `0..=1440`.
+    minutes: u16,
+}
+
+impl JsonSchema for TimeOfDay {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "TimeOfDay".into()
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "description": "Время суток \"HH:MM\"; допускается \"24:00\".",
+            "type": "string",
+            "pattern": "^(([01]?[0-9]|2[0-3]):[0-5][0-9]|24:00)$"
+        })
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid time '{}', expected \"HH:MM\"", text);
+        let (hours, minutes) = text.split_once(':').ok_or_else(invalid)?;
+        let hours: u16 = hours.trim().parse().map_err(|_| invalid())?;
+        let minutes: u16 = minutes.trim().parse().map_err(|_| invalid())?;
+        if minutes > 59 || hours > 24 || (hours == 24 && minutes > 0) {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+impl std::fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+impl Schedule {
+    /// Часовой пояс окон; неизвестное имя отсеивается проверкой конфига.
+    fn tz(&self) -> Tz {
+        self.timezone
+            .as_deref()
+            .and_then(|name| name.parse().ok())
+            .unwrap_or(Tz::UTC)
+    }
+
+    /// Открыто ли правило в момент `now`.
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        self.intervals(now)
+            .iter()
+            .any(|(start, end)| *start <= now && now < *end)
+    }
+
+    /// Ближайший момент после `now`, когда правило откроется или закроется;
+    /// `None`, если состояние не меняется никогда (окна покрывают всю неделю
+    /// или не покрывают ничего).
+    pub fn next_change(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let open = self.is_open(now);
+        let mut edges: Vec<DateTime<Utc>> = self
+            .intervals(now)
+            .into_iter()
+            .flat_map(|(start, end)| [start, end])
+            .filter(|edge| *edge > now)
+            .collect();
+        edges.sort();
+        // Соседние или перекрывающиеся окна дают границы без смены состояния.
+        edges.into_iter().find(|edge| self.is_open(*edge) != open)
+    }
+
+    /// Окна в UTC, начавшиеся со вчерашнего дня по местному времени и на
+    /// неделю с запасом вперёд: этого хватает, чтобы найти следующую смену.
+    fn intervals(&self, now: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let tz = self.tz();
+        let today = now.with_timezone(&tz).date_naive();
+        let mut out = Vec::new();
+        for offset in -1..=8 {
+            let date = today + ChronoDuration::days(offset);
+            for window in &self.windows {
+                let starts_today = window
+                    .days
+                    .as_ref()
+                    .is_none_or(|days| days.iter().any(|d| d.weekday() == date.weekday()));
+                if !starts_today {
+                    continue;
+                }
+                let start = local_time(tz, date, window.from);
+                let end_date = if window.to <= window.from {
+                    date + ChronoDuration::days(1)
+                } else {
+                    date
+                };
+                let end = local_time(tz, end_date, window.to);
+                if start < end {
+                    out.push((start, end));
+                }
+            }
+        }
+        out
+    }
+
+    /// Проблемы расписания для проверки конфига: `(поле, описание)`.
+    pub fn problems(&self) -> Vec<(&'static str, &'static str)> {
+        let mut problems = Vec::new();
+        if let Some(name) = &self.timezone {
+            if name.parse::<Tz>().is_err() {
+                problems.push((
+                    "schedule.timezone",
+                    "unknown time zone, use an IANA name like Europe/Moscow",
+                ));
+            }
+        }
+        if self.windows.is_empty() {
+            problems.push((
+                "schedule.windows",
+                "must not be empty, use enabled: false to keep the rule closed",
+            ));
+        }
+        if self.windows.iter().any(|w| w.from == w.to) {
+            problems.push((
+                "schedule.windows",
+                "a window must not start and end at the same time, use 00:00-24:00 for a whole day",
+            ));
+        }
+        if self.windows.iter().any(|w| w.from.minutes == 24 * 60) {
+            problems.push(("schedule.windows", "a window must not start at 24:00"));
+        }
+        if self
+            .windows
+            .iter()
+            .any(|w| w.days.as_ref().is_some_and(|days| days.is_empty()))
+        {
+            problems.push((
+                "schedule.windows",
+                "days must not be empty, omit it for every day",
+            ));
+        }
+        problems
+    }
+}
+
+/// Местное время `time` даты `date` в UTC (`"24:00"` — полночь следующего дня).
+fn local_time(tz: Tz, date: NaiveDate, time: TimeOfDay) -> DateTime<Utc> {
+    let naive =
+        date.and_time(chrono::NaiveTime::MIN) + ChronoDuration::minutes(i64::from(time.minutes));
+    db::resolve_local(tz, naive).with_timezone(&Utc)
+}
+
+/// Держит порты правила открытыми в окна расписания. `ports` — уже открытые
+/// порты, если правило было открыто в момент запуска (так ошибка открытия
+/// возвращается вызывающему). Отмена задачи сразу закрывает все слушатели.
+pub async fn run(
+    rule: ConfigConnect,
+    schedule: Schedule,
+    settings: RuleSettings,
+    mut ports: Option<Vec<BoundPort>>,
+    log_tx: broadcast::Sender<LogEvent>,
+) {
+    let close_sessions = schedule.close_sessions.unwrap_or(false);
+    let mut first = true;
+    loop {
+        if !schedule.is_open(Utc::now()) {
+            ports = None;
+            if first {
+                info!(
+                    "Proxy {} is closed by schedule until {}",
+                    rule.name,
+                    when(schedule.next_change(Utc::now()))
+                );
+            }
+            first = false;
+            wait_while(&schedule, false).await;
+            continue;
+        }
+        first = false;
+        let bound = match ports.take() {
+            Some(bound) => bound,
+            None => match bind_listeners(&rule, &settings).await {
+                Ok(bound) => bound,
+                Err(e) => {
+                    error!(
+                        "Proxy {}: schedule window opened, but cannot listen on {}, retrying",
+                        rule.name, e
+                    );
+                    tokio::time::sleep(RECHECK_INTERVAL).await;
+                    continue;
+                }
+            },
+        };
+        let next_change = schedule.next_change(Utc::now());
+        info!(
+            "Proxy {} opened by schedule at {} to {}:{} until {}",
+            rule.name,
+            rule.local_ports_text(),
+            rule.remote_address,
+            rule.remote_ports_text(),
+            when(next_change)
+        );
+        let _ = log_tx.send(LogEvent::RuleOpened {
+            ts: Utc::now(),
+            name: rule.name.clone(),
+            next_change,
+        });
+
+        let (closing_tx, closing_rx) = watch::channel(false);
+        let forwards = bound
+            .into_iter()
+            .map(|port| {
+                Box::pin(port_forward(
+                    port,
+                    &rule,
+                    settings,
+                    Some(closing_rx.clone()),
+                    log_tx.clone(),
+                )) as Pin<Box<dyn Future<Output = ()> + Send + '_>>
+            })
+            .collect();
+        tokio::select! {
+            _ = join_all(forwards) => {}
+            _ = wait_while(&schedule, true) => {}
+        }
+        // Слушатели закрыты вместе с `forwards`; открытые сессии — по настройке.
+        if close_sessions {
+            let _ = closing_tx.send(true);
+        }
+        let next_change = schedule.next_change(Utc::now());
+        info!(
+            "Proxy {} closed by schedule until {}",
+            rule.name,
+            when(next_change)
+        );
+        let _ = log_tx.send(LogEvent::RuleClosed {
+            ts: Utc::now(),
+            name: rule.name.clone(),
+            next_change,
+            close_sessions,
+        });
+    }
+}
+
+/// Завершается, когда окно расписания закрылось с `close_sessions`. Для
+/// правил без расписания (`None`) и окон, закрытых без обрыва сессий, не
+/// завершается никогда.
+pub async fn window_closed(closing: Option<watch::Receiver<bool>>) {
+    if let Some(mut closing) = closing {
+        if closing.wait_for(|closed| *closed).await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
+/// Ждёт, пока правило не перестанет быть открытым (`open`) или закрытым.
+async fn wait_while(schedule: &Schedule, open: bool) {
+    loop {
+        let now = Utc::now();
+        if schedule.is_open(now) != open {
+            return;
+        }
+        let wait = schedule
+            .next_change(now)
+            .and_then(|at| (at - now).to_std().ok())
+            .map_or(RECHECK_INTERVAL, |left| left.min(RECHECK_INTERVAL));
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Выполняет accept‑циклы всех портов правила внутри текущей задачи, чтобы
+/// при её отмене слушатели закрывались сразу, а не когда‑нибудь потом.
+async fn join_all<'a>(mut forwards: Vec<Pin<Box<dyn Future<Output = ()> + Send + 'a>>>) {
+    std::future::poll_fn(|cx| {
+        forwards.retain_mut(|forward| forward.as_mut().poll(cx).is_pending());
+        if forwards.is_empty() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+fn when(at: Option<DateTime<Utc>>) -> String {
+    match at {
+        Some(at) => at.to_rfc3339(),
+        None => String::from("further notice"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schedule(value: serde_json::Value) -> Schedule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn at(text: &str) -> DateTime<Utc> {
+        text.parse().unwrap()
+    }
+
+    fn time(text: &str) -> TimeOfDay {
+        TimeOfDay::try_from(text.to_string()).unwrap()
+    }
+
+    #[test]
+    fn window_crossing_midnight() {
+        // 2026-10-16 — пятница.
+        let s = schedule(json!({
+            "windows": [{ "days": ["fri"], "from": "22:00", "to": "02:00" }]
+        }));
+        assert!(!s.is_open(at("2026-10-16T21:00:00Z")));
+        assert_eq!(
+            s.next_change(at("2026-10-16T21:00:00Z")),
+            Some(at("2026-10-16T22:00:00Z"))
+        );
+        assert!(s.is_open(at("2026-10-16T23:00:00Z")));
+        // Окно, начавшееся в пятницу, продолжается в субботу после полуночи.
+        assert!(s.is_open(at("2026-10-17T01:59:00Z")));
+        assert_eq!(
+            s.next_change(at("2026-10-16T23:00:00Z")),
+            Some(at("2026-10-17T02:00:00Z"))
+        );
+        assert!(!s.is_open(at("2026-10-17T02:00:00Z")));
+        // В субботу вечером окно не начинается: следующее — через неделю.
+        assert!(!s.is_open(at("2026-10-17T23:00:00Z")));
+        assert_eq!(
+            s.next_change(at("2026-10-17T03:00:00Z")),
+            Some(at("2026-10-23T22:00:00Z"))
+        );
+        // Окно до полуночи и окно после неё подряд — одно открытие.
+        let s = schedule(json!({
+            "windows": [
+                { "from": "20:00", "to": "24:00" },
+                { "from": "00:00", "to": "06:00" }
+            ]
+        }));
+        assert_eq!(
+            s.next_change(at("2026-10-16T21:00:00Z")),
+            Some(at("2026-10-17T06:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn local_time_in_dst_gap_and_overlap() {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        let date = |text: &str| NaiveDate::parse_from_str(text, "%Y-%m-%d").unwrap();
+        // Обычный день зимой: UTC+1.
+        assert_eq!(
+            local_time(tz, date("2026-03-28"), time("02:30")),
+            at("2026-03-28T01:30:00Z")
+        );
+        // 29 марта 02:00–03:00 не существует — сдвиг на первое время после скачка.
+        assert_eq!(
+            local_time(tz, date("2026-03-29"), time("02:30")),
+            at("2026-03-29T01:00:00Z")
+        );
+        assert_eq!(
+            local_time(tz, date("2026-03-29"), time("03:00")),
+            at("2026-03-29T01:00:00Z")
+        );
+        // 25 октября 02:00–03:00 бывает дважды — берётся первое (летнее) время.
+        assert_eq!(
+            local_time(tz, date("2026-10-25"), time("02:30")),
+            at("2026-10-25T00:30:00Z")
+        );
+        assert_eq!(
+            local_time(tz, date("2026-10-25"), time("24:00")),
+            at("2026-10-25T23:00:00Z")
+        );
+    }
+
+    #[test]
+    fn windows_across_dst_changes() {
+        let s = schedule(json!({
+            "timezone": "Europe/Berlin",
+            "windows": [{ "days": ["sun"], "from": "01:00", "to": "04:00" }]
+        }));
+        // Весной окно короче на час: 01:00 CET — 04:00 CEST.
+        assert_eq!(
+            s.next_change(at("2026-03-28T12:00:00Z")),
+            Some(at("2026-03-29T00:00:00Z"))
+        );
+        assert_eq!(
+            s.next_change(at("2026-03-29T00:30:00Z")),
+            Some(at("2026-03-29T02:00:00Z"))
+        );
+        // Осенью длиннее на час: 01:00 CEST — 04:00 CET, и повторный час
+        // 02:00–03:00 тоже внутри окна.
+        assert_eq!(
+            s.next_change(at("2026-10-24T12:00:00Z")),
+            Some(at("2026-10-24T23:00:00Z"))
+        );
+        assert!(s.is_open(at("2026-10-25T01:45:00Z")));
+        assert_eq!(
+            s.next_change(at("2026-10-25T00:00:00Z")),
+            Some(at("2026-10-25T03:00:00Z"))
+        );
+
+        // Окно через полночь в день перевода.
+        let s = schedule(json!({
+            "timezone": "Europe/Berlin",
+            "windows": [{ "days": ["sat"], "from": "23:00", "to": "02:30" }]
+        }));
+        assert_eq!(
+            s.next_change(at("2026-03-28T22:30:00Z")),
+            Some(at("2026-03-29T01:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn empty_and_whole_week_schedules() {
+        let empty = schedule(json!({
+            "windows": [{ "days": [], "from": "09:00", "to": "18:00" }]
+        }));
+        assert!(!empty.is_open(at("2026-10-16T12:00:00Z")));
+        assert_eq!(empty.next_change(at("2026-10-16T12:00:00Z")), None);
+        assert!(empty
+            .problems()
+            .iter()
+            .any(|(_, message)| message.starts_with("days must not be empty")));
+
+        let always = schedule(json!({ "windows": [{ "from": "00:00", "to": "24:00" }] }));
+        assert!(always.is_open(at("2026-10-16T12:00:00Z")));
+        assert_eq!(always.next_change(at("2026-10-16T12:00:00Z")), None);
+        assert!(always.problems().is_empty());
+    }
+}
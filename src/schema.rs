@@ -0,0 +1,164 @@
+// JSON Schema конфига, построенная по типам `Config`/`ConfigConnect`:
+// описания полей берутся из doc‑комментариев, значения по умолчанию — из тех же
+// констант, что использует программа. Копия схемы лежит в репозитории
+// (`rs-port-forward.config.schema.json`) для редакторов и CI; тест ниже следит,
+// чтобы она не расходилась с типами.
+use serde_json::Value;
+
+use crate::Config;
+
+/// Схема основного конфига (`schema`, `GET /config/schema`). Правила
+/// подключаемых файлов описаны в ней же: `#/$defs/ConfigConnect`.
+pub fn config_schema() -> Value {
+    let mut schema = schemars::schema_for!(Config);
+    schema.insert("title".into(), "rs-port-forward config".into());
+    schema.to_value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{loader, memory, DEFAULT_BUFFER_SIZE, DEFAULT_IDLE_TIMEOUT_SECS};
+    use serde_json::json;
+
+    const SCHEMA_FILE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/rs-port-forward.config.schema.json"
+    );
+    const SAMPLE_FILE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/rs-port-forward.config.sample.json"
+    );
+
+    /// Подсхема с раскрытой ссылкой `$ref` и без варианта `null` у `Option`.
+    fn resolve<'a>(schema: &'a Value, root: &'a Value) -> &'a Value {
+        if let Some(name) = schema["$ref"]
+            .as_str()
+            .and_then(|r| r.strip_prefix("#/$defs/"))
+        {
+            return resolve(&root["$defs"][name], root);
+        }
+        if let Some(variants) = schema["anyOf"].as_array() {
+            if let Some(variant) = variants.iter().find(|v| v["type"] != "null") {
+                return resolve(variant, root);
+            }
+        }
+        schema
+    }
+
+    /// Пути ключей `value`, которых нет в схеме.
+    fn unknown_keys(value: &Value, schema: &Value, root: &Value, path: &str) -> Vec<String> {
+        let schema = resolve(schema, root);
+        match value {
+            Value::Object(map) => map
+                .iter()
+                .flat_map(|(key, item)| {
+                    let at = format!("{}.{}", path, key);
+                    match schema["properties"].get(key) {
+                        Some(field) => unknown_keys(item, field, root, &at),
+                        None => vec![at],
+                    }
+                })
+                .collect(),
+            Value::Array(items) => items
+                .iter()
+                .enumerate()
+                .flat_map(|(i, item)| {
+                    unknown_keys(item, &schema["items"], root, &format!("{}[{}]", path, i))
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn committed_schema_is_up_to_date() {
+        let committed: Value = serde_json::from_str(
+            &std::fs::read_to_string(SCHEMA_FILE).expect("schema file is missing"),
+        )
+        .expect("schema file is not JSON");
+        assert!(
+            committed == config_schema(),
+            "{} is out of date, regenerate it with \
+             `cargo run -- schema > rs-port-forward.config.schema.json`",
+            SCHEMA_FILE
+        );
+    }
+
+    #[test]
+    fn sample_config_matches_schema() {
+        let sample = loader::read_value(SAMPLE_FILE).unwrap();
+        let schema = config_schema();
+        assert_eq!(
+            unknown_keys(&sample, &schema, &schema, ""),
+            Vec::<String>::new()
+        );
+        let (_, unknown) = loader::from_value::<Config>(sample).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+    }
+
+    #[test]
+    fn every_field_is_described() {
+        let schema = config_schema();
+        let mut structs = vec![("Config", &schema)];
+        for name in ["ConfigConnect", "RuleDefaults", "Schedule", "Window"] {
+            structs.push((name, &schema["$defs"][name]));
+        }
+        for (name, schema) in structs {
+            for (field, field_schema) in schema["properties"].as_object().unwrap() {
+                assert!(
+                    field_schema["description"].is_string(),
+                    "{}.{} has no doc comment",
+                    name,
+                    field
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn defaults_match_resolved_config() {
+        let (config, _) = loader::from_value::<Config>(json!({
+            "database_path": "stats.db",
+            "connect_list": [
+                {"name": "a", "local_port": 1, "remote_address": "h", "remote_port": 2}
+            ]
+        }))
+        .unwrap();
+        let resolved = serde_json::to_value(config.resolved(None)).unwrap();
+        let schema = config_schema();
+        let mut checked = 0;
+        for (field, field_schema) in schema["properties"].as_object().unwrap() {
+            let (Some(default), Some(value)) = (field_schema.get("default"), resolved.get(field))
+            else {
+                continue;
+            };
+            if field != "connect_list" {
+                assert_eq!(default, value, "default of {}", field);
+                checked += 1;
+            }
+        }
+        assert!(checked >= 7, "only {} defaults checked", checked);
+
+        let rule = &resolved["connect_list"][0];
+        let defaults = &schema["$defs"]["RuleDefaults"]["properties"];
+        for field in ["idle_timeout_seconds", "buffer_size", "bind_address"] {
+            assert_eq!(
+                defaults[field]["default"], rule[field],
+                "default of {}",
+                field
+            );
+        }
+        assert_eq!(rule["idle_timeout_seconds"], DEFAULT_IDLE_TIMEOUT_SECS);
+        assert_eq!(rule["buffer_size"], DEFAULT_BUFFER_SIZE);
+
+        // Без БД вместо настроек записи подставляется размер статистики в памяти.
+        let (config, _) = loader::from_value::<Config>(json!({})).unwrap();
+        let resolved = serde_json::to_value(config.resolved(None)).unwrap();
+        assert_eq!(
+            schema["properties"]["memory_stats_rows"]["default"],
+            resolved["memory_stats_rows"]
+        );
+        assert_eq!(resolved["memory_stats_rows"], memory::DEFAULT_MEMORY_ROWS);
+    }
+}
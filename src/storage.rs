@@ -0,0 +1,722 @@
+// Storage backends for connection statistics. SQLite is always available;
+// PostgreSQL is compiled in with the `postgres` cargo feature.
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use log::{error, info};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::db::{
+    self, Breakdown, BreakdownRow, ClientTraffic, ConnectionRow, FailureReason, GroupBy,
+    PruneReport, RetentionPolicy, SessionInfo, SharedDb, SqliteOptions, StoredConnection,
+    TimeseriesPoint, TopBy, TrafficFilter,
+};
+
+/// Everything the writer, retention task and HTTP API need from a database.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Short backend name for logs, e.g. `sqlite`.
+    fn backend(&self) -> &'static str;
+
+    /// Stores a batch of rows and updates the rollups atomically.
+    async fn insert_connection_rows(&self, rows: &[ConnectionRow]) -> anyhow::Result<()>;
+
+    /// Bytes per client in `[start, end)`, optionally for one rule.
+    async fn traffic_by_client(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        name: Option<String>,
+    ) -> anyhow::Result<Vec<ClientTraffic>>;
+
+    /// See [`db::query_traffic_timeseries`] for bucket semantics.
+    async fn traffic_timeseries(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        step_secs: i64,
+        tz: Tz,
+        group_by: Option<GroupBy>,
+        filter: TrafficFilter,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>>;
+
+    /// Top `limit` groups of traffic in `[start, end)`; see [`db::query_traffic_breakdown`].
+    async fn traffic_breakdown(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        by: Breakdown,
+        top: TopBy,
+        filter: TrafficFilter,
+        limit: u32,
+    ) -> anyhow::Result<Vec<BreakdownRow>>;
+
+    async fn sessions(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: TrafficFilter,
+        failed_only: bool,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SessionInfo>>;
+
+    /// See [`db::query_connections_page`]; pass `(start, 0)` for the first page.
+    async fn connections_page(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        name: Option<String>,
+        after: (i64, i64),
+        limit: u32,
+    ) -> anyhow::Result<Vec<StoredConnection>>;
+
+    async fn failure_reasons(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: TrafficFilter,
+    ) -> anyhow::Result<Vec<FailureReason>>;
+
+    /// One-time setup before periodic pruning starts.
+    async fn prepare_retention(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Deletes data older than the policy allows.
+    async fn prune(&self, policy: RetentionPolicy) -> anyhow::Result<PruneReport>;
+
+    /// Writes a consistent copy of the database to a private temporary file
+    /// while writes go on; the file is deleted when the path is dropped.
+    async fn backup_to_temp(&self) -> anyhow::Result<tempfile::TempPath> {
+        anyhow::bail!(
+            "online backup is not supported by the {} backend",
+            self.backend()
+        )
+    }
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// The default backend: a single SQLite file in WAL mode with one writer
+/// connection and a small pool of read-only connections for queries.
+pub struct SqliteStorage {
+    db: SharedDb,
+    readers: Vec<SharedDb>,
+    next_reader: AtomicUsize,
+    path: String,
+    options: SqliteOptions,
+}
+
+impl SqliteStorage {
+    pub async fn open(path: &str, options: SqliteOptions) -> anyhow::Result<Self> {
+        let db = db::init_db(path, &options).await?;
+        let readers = db::open_readers(path, &options).await?;
+        Ok(Self {
+            db,
+            readers,
+            next_reader: AtomicUsize::new(0),
+            path: path.to_string(),
+            options,
+        })
+    }
+
+    /// Opens an existing database for `stats` and `export`: read-only, without
+    /// creating or migrating it. Inserts fail.
+    pub async fn open_read_only(path: &str, options: SqliteOptions) -> anyhow::Result<Self> {
+        let readers = db::open_existing(path, &options).await?;
+        Ok(Self {
+            db: readers[0].clone(),
+            readers,
+            next_reader: AtomicUsize::new(0),
+            path: path.to_string(),
+            options,
+        })
+    }
+
+    /// Read connections are handed out round-robin.
+    fn reader(&self) -> &SharedDb {
+        let index = self.next_reader.fetch_add(1, Ordering::Relaxed) % self.readers.len();
+        &self.readers[index]
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    fn backend(&self) -> &'static str {
+        "sqlite"
+    }
+
+    async fn insert_connection_rows(&self, rows: &[ConnectionRow]) -> anyhow::Result<()> {
+        db::insert_connection_rows(&self.db, rows).await
+    }
+
+    async fn traffic_by_client(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        name: Option<String>,
+    ) -> anyhow::Result<Vec<ClientTraffic>> {
+        db::query_traffic_by_client_filtered(self.reader(), start, end, name).await
+    }
+
+    async fn traffic_timeseries(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        step_secs: i64,
+        tz: Tz,
+        group_by: Option<GroupBy>,
+        filter: TrafficFilter,
+    ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+        db::query_traffic_timeseries(self.reader(), start, end, step_secs, tz, group_by, filter)
+            .await
+    }
+
+    async fn traffic_breakdown(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        by: Breakdown,
+        top: TopBy,
+        filter: TrafficFilter,
+        limit: u32,
+    ) -> anyhow::Result<Vec<BreakdownRow>> {
+        db::query_traffic_breakdown(self.reader(), start, end, by, top, filter, limit).await
+    }
+
+    async fn sessions(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: TrafficFilter,
+        failed_only: bool,
+        limit: u32,
+    ) -> anyhow::Result<Vec<SessionInfo>> {
+        db::query_sessions(self.reader(), start, end, filter, failed_only, limit).await
+    }
+
+    async fn connections_page(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        name: Option<String>,
+        after: (i64, i64),
+        limit: u32,
+    ) -> anyhow::Result<Vec<StoredConnection>> {
+        db::query_connections_page(self.reader(), start, end, name, after, limit).await
+    }
+
+    async fn failure_reasons(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        filter: TrafficFilter,
+    ) -> anyhow::Result<Vec<FailureReason>> {
+        db::query_failure_reasons(self.reader(), start, end, filter).await
+    }
+
+    async fn prepare_retention(&self) -> anyhow::Result<()> {
+        db::enable_incremental_vacuum(&self.db).await
+    }
+
+    async fn prune(&self, policy: RetentionPolicy) -> anyhow::Result<PruneReport> {
+        db::prune(&self.db, policy).await
+    }
+
+    async fn backup_to_temp(&self) -> anyhow::Result<tempfile::TempPath> {
+        // Next to the database: same filesystem and permissions as the data
+        // itself, rather than a shared temp directory.
+        let dir = match Path::new(&self.path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let src = self.path.clone();
+        let busy_timeout = self.options.busy_timeout;
+        tokio::task::spawn_blocking(move || db::backup_to_temp(&src, &dir, busy_timeout)).await?
+    }
+}
+
+/// Opens the configured backend: `database_url` (PostgreSQL) takes precedence
+/// over `database_path` (SQLite). `Ok(None)` when neither is set.
+pub async fn open_storage(
+    database_url: Option<&str>,
+    database_ca_file: Option<&str>,
+    database_path: Option<&str>,
+    sqlite: SqliteOptions,
+) -> anyhow::Result<Option<SharedStorage>> {
+    if let Some(url) = database_url {
+        return open_url(url, database_ca_file).await.map(Some);
+    }
+    match database_path {
+        Some(path) => Ok(Some(Arc::new(SqliteStorage::open(path, sqlite).await?))),
+        None => Ok(None),
+    }
+}
+
+/// Like [`open_storage`], but only for reading an existing database: nothing
+/// is created or migrated, and a different schema version is an error.
+pub async fn open_storage_read_only(
+    database_url: Option<&str>,
+    database_ca_file: Option<&str>,
+    database_path: Option<&str>,
+    sqlite: SqliteOptions,
+) -> anyhow::Result<Option<SharedStorage>> {
+    if let Some(url) = database_url {
+        return open_url_read_only(url, database_ca_file).await.map(Some);
+    }
+    match database_path {
+        Some(path) => Ok(Some(Arc::new(
+            SqliteStorage::open_read_only(path, sqlite).await?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "postgres")]
+async fn open_url_read_only(url: &str, ca_file: Option<&str>) -> anyhow::Result<SharedStorage> {
+    Ok(Arc::new(
+        crate::pg::PgStorage::connect_existing(url, ca_file).await?,
+    ))
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn open_url_read_only(_url: &str, _ca_file: Option<&str>) -> anyhow::Result<SharedStorage> {
+    anyhow::bail!("database_url needs a build with the `postgres` feature")
+}
+
+#[cfg(feature = "postgres")]
+async fn open_url(url: &str, ca_file: Option<&str>) -> anyhow::Result<SharedStorage> {
+    Ok(Arc::new(crate::pg::PgStorage::connect(url, ca_file).await?))
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn open_url(_url: &str, _ca_file: Option<&str>) -> anyhow::Result<SharedStorage> {
+    anyhow::bail!("database_url needs a build with the `postgres` feature")
+}
+
+/// Background retention loop: prunes once shortly after startup and then
+/// every `interval`.
+pub async fn run_retention(
+    storage: SharedStorage,
+    policy: RetentionPolicy,
+    interval: std::time::Duration,
+) {
+    if let Err(e) = storage.prepare_retention().await {
+        error!(
+            "Failed to prepare {} for pruning: {:#}",
+            storage.backend(),
+            e
+        );
+    }
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match storage.prune(policy).await {
+            Ok(report) => {
+                if report.raw_rows > 0 || report.rollup_rows > 0 || report.freed_pages > 0 {
+                    info!(
+                        "Pruned {} raw rows and {} rollup rows, freed {} pages",
+                        report.raw_rows, report.rollup_rows, report.freed_pages
+                    );
+                }
+            }
+            Err(e) => error!("Failed to prune {}: {:#}", storage.backend(), e),
+        }
+    }
+}
+
+/// The same cases run against every backend, so they agree on what the
+/// HTTP API and the CLI return. PostgreSQL runs them from `pg::tests` when
+/// `RSPF_TEST_PG_URL` is set.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::memory::MemoryStorage;
+    use chrono::TimeZone;
+
+    const DAY: i64 = 86400;
+
+    struct Row {
+        offset: i64,
+        log_name: &'static str,
+        session_id: u64,
+        instance: &'static str,
+        name: &'static str,
+        client: &'static str,
+        bytes: (u64, u64),
+        close_reason: Option<&'static str>,
+        error: Option<&'static str>,
+    }
+
+    fn row(r: Row, base: i64) -> ConnectionRow {
+        ConnectionRow {
+            ts: base + r.offset,
+            name: r.name.to_string(),
+            log_name: r.log_name.to_string(),
+            local_port: 6432,
+            remote_address: "db.internal".to_string(),
+            remote_port: 5432,
+            client_addr: Some(r.client.to_string()),
+            bytes_from_to: r.bytes.0,
+            bytes_to_from: r.bytes.1,
+            session_id: r.session_id,
+            instance_id: Some(r.instance.to_string()),
+            upstream_ip: Some("192.0.2.1".to_string()),
+            error: r.error.map(String::from),
+            close_reason: r.close_reason.map(String::from),
+            duration_ms: r.close_reason.map(|_| 60_000),
+        }
+    }
+
+    fn started(
+        offset: i64,
+        session_id: u64,
+        instance: &'static str,
+        name: &'static str,
+        client: &'static str,
+    ) -> Row {
+        Row {
+            offset,
+            log_name: "connection_started",
+            session_id,
+            instance,
+            name,
+            client,
+            bytes: (0, 0),
+            close_reason: None,
+            error: None,
+        }
+    }
+
+    fn closed(
+        offset: i64,
+        session_id: u64,
+        instance: &'static str,
+        name: &'static str,
+        client: &'static str,
+        bytes: (u64, u64),
+    ) -> Row {
+        Row {
+            offset,
+            log_name: "connection_closed",
+            session_id,
+            instance,
+            name,
+            client,
+            bytes,
+            close_reason: Some("client_closed"),
+            error: None,
+        }
+    }
+
+    fn at(ts: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(ts, 0).unwrap()
+    }
+
+    /// Runs every case against an empty backend.
+    pub(crate) async fn check_backend(storage: &dyn Storage) {
+        let now = Utc::now().timestamp();
+        // Three whole UTC days ending at the last midnight.
+        let day = now.div_euclid(DAY) * DAY - 3 * DAY;
+        let old = now - 20 * DAY;
+        let rows = vec![
+            row(started(3610, 1, "a", "pg", "10.0.0.1"), day),
+            row(closed(3670, 1, "a", "pg", "10.0.0.1", (100, 200)), day),
+            row(
+                Row {
+                    offset: 7205,
+                    log_name: "connection_error",
+                    session_id: 2,
+                    instance: "a",
+                    name: "pg",
+                    client: "10.0.0.2",
+                    bytes: (0, 0),
+                    close_reason: None,
+                    error: Some("connection refused"),
+                },
+                day,
+            ),
+            // Same session id on another instance sharing the database.
+            row(started(DAY + 100, 1, "b", "web", "10.0.0.4"), day),
+            row(closed(DAY + 160, 1, "b", "web", "10.0.0.4", (1, 1)), day),
+            row(started(2 * DAY + 10, 3, "a", "web", "10.0.0.1"), day),
+            row(
+                closed(2 * DAY + 20, 3, "a", "web", "10.0.0.1", (1000, 2000)),
+                day,
+            ),
+            row(started(0, 4, "a", "pg", "10.0.0.3"), old),
+            row(closed(1, 4, "a", "pg", "10.0.0.3", (5, 5)), old),
+        ];
+        storage.insert_connection_rows(&rows).await.unwrap();
+        let (start, end) = (at(day), at(day + 3 * DAY));
+
+        check_clients(storage, start, end).await;
+        check_timeseries(storage, day).await;
+        check_breakdown(storage, start, end).await;
+        check_sessions(storage, start, end).await;
+        check_export_pages(storage, start, end).await;
+        check_prune(storage, old, end).await;
+    }
+
+    fn client_totals(rows: Vec<ClientTraffic>) -> Vec<(String, u64, u64)> {
+        rows.into_iter()
+            .map(|c| {
+                (
+                    c.client_addr.unwrap_or_default(),
+                    c.bytes_from_to,
+                    c.bytes_to_from,
+                )
+            })
+            .collect()
+    }
+
+    async fn check_clients(storage: &dyn Storage, start: DateTime<Utc>, end: DateTime<Utc>) {
+        let all = storage.traffic_by_client(start, end, None).await.unwrap();
+        assert_eq!(
+            client_totals(all),
+            vec![
+                ("10.0.0.1".to_string(), 1100, 2200),
+                ("10.0.0.4".to_string(), 1, 1),
+                ("10.0.0.2".to_string(), 0, 0),
+            ]
+        );
+        let pg = storage
+            .traffic_by_client(start, end, Some("pg".to_string()))
+            .await
+            .unwrap();
+        let mut pg = client_totals(pg);
+        pg.sort();
+        assert_eq!(
+            pg,
+            vec![
+                ("10.0.0.1".to_string(), 100, 200),
+                ("10.0.0.2".to_string(), 0, 0)
+            ]
+        );
+    }
+
+    async fn check_timeseries(storage: &dyn Storage, day: i64) {
+        let (start, end) = (at(day), at(day + 3 * DAY));
+        let points = |group_by| async move {
+            let mut points: Vec<_> = storage
+                .traffic_timeseries(start, end, DAY, Tz::UTC, group_by, TrafficFilter::default())
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|p| {
+                    (
+                        p.bucket_ts - day,
+                        p.key,
+                        p.bytes_from_to,
+                        p.bytes_to_from,
+                        p.connections,
+                    )
+                })
+                .collect();
+            points.sort();
+            points
+        };
+        assert_eq!(
+            points(None).await,
+            vec![
+                (0, None, 100, 200, 1),
+                (DAY, None, 1, 1, 1),
+                (2 * DAY, None, 1000, 2000, 1),
+            ]
+        );
+        let web = Some("web".to_string());
+        assert_eq!(
+            points(Some(GroupBy::Rule)).await,
+            vec![
+                (0, Some("pg".to_string()), 100, 200, 1),
+                (DAY, web.clone(), 1, 1, 1),
+                (2 * DAY, web, 1000, 2000, 1),
+            ]
+        );
+        // Hourly steps inside the first day read the same traffic.
+        let hourly = storage
+            .traffic_timeseries(
+                start,
+                at(day + DAY),
+                3600,
+                Tz::UTC,
+                None,
+                TrafficFilter::default(),
+            )
+            .await
+            .unwrap();
+        let hourly: Vec<_> = hourly
+            .iter()
+            .map(|p| (p.bucket_ts - day, p.bytes_from_to, p.connections))
+            .collect();
+        assert_eq!(hourly, vec![(3600, 100, 1), (7200, 0, 0)]);
+    }
+
+    async fn check_breakdown(storage: &dyn Storage, start: DateTime<Utc>, end: DateTime<Utc>) {
+        let rows = storage
+            .traffic_breakdown(
+                start,
+                end,
+                Breakdown::Rule,
+                TopBy::Bytes,
+                TrafficFilter::default(),
+                10,
+            )
+            .await
+            .unwrap();
+        let rows: Vec<_> = rows
+            .into_iter()
+            .map(|r| {
+                (
+                    r.name,
+                    r.bytes_from_to,
+                    r.bytes_to_from,
+                    r.connections,
+                    r.errors,
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                (Some("web".to_string()), 1001, 2001, 2, 0),
+                (Some("pg".to_string()), 100, 200, 1, 1),
+            ]
+        );
+    }
+
+    async fn check_sessions(storage: &dyn Storage, start: DateTime<Utc>, end: DateTime<Utc>) {
+        let sessions = storage
+            .sessions(start, end, TrafficFilter::default(), false, 100)
+            .await
+            .unwrap();
+        let got: Vec<_> = sessions
+            .iter()
+            .map(|s| {
+                (
+                    s.instance_id.clone().unwrap_or_default(),
+                    s.session_id,
+                    s.outcome.as_str(),
+                    s.bytes_from_to,
+                    s.closed.map(|closed| closed - s.started),
+                )
+            })
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                ("a".to_string(), 3, "connection_closed", 1000, Some(10)),
+                ("b".to_string(), 1, "connection_closed", 1, Some(60)),
+                ("a".to_string(), 2, "connection_error", 0, Some(0)),
+                ("a".to_string(), 1, "connection_closed", 100, Some(60)),
+            ]
+        );
+        let first = &sessions[3];
+        assert_eq!(first.close_reason.as_deref(), Some("client_closed"));
+        assert_eq!(first.duration_ms, Some(60_000));
+        assert_eq!(first.upstream_ip.as_deref(), Some("192.0.2.1"));
+
+        let failed = storage
+            .sessions(start, end, TrafficFilter::default(), true, 100)
+            .await
+            .unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].error.as_deref(), Some("connection refused"));
+
+        let filter = TrafficFilter {
+            name: Some("web".to_string()),
+            client_addr: None,
+        };
+        let web = storage
+            .sessions(start, end, filter, false, 1)
+            .await
+            .unwrap();
+        assert_eq!(web.len(), 1);
+        assert_eq!(web[0].session_id, 3);
+
+        let reasons = storage
+            .failure_reasons(start, end, TrafficFilter::default())
+            .await
+            .unwrap();
+        assert_eq!(reasons.len(), 1);
+        assert_eq!(reasons[0].error.as_deref(), Some("connection refused"));
+        assert_eq!(reasons[0].count, 1);
+    }
+
+    async fn check_export_pages(storage: &dyn Storage, start: DateTime<Utc>, end: DateTime<Utc>) {
+        let mut after = (start.timestamp(), 0);
+        let mut seen = Vec::new();
+        loop {
+            let page = storage
+                .connections_page(start, end, None, after, 3)
+                .await
+                .unwrap();
+            let Some(last) = page.last() else { break };
+            after = (last.row.ts, last.id);
+            seen.extend(page.into_iter().map(|c| c.row.ts));
+        }
+        assert_eq!(seen.len(), 7);
+        assert!(seen.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    async fn check_prune(storage: &dyn Storage, old: i64, end: DateTime<Utc>) {
+        // The range starts well before the old session, so its hour is whole.
+        let start = at(old - DAY);
+        let has_old_client = |rows: Vec<ClientTraffic>| {
+            rows.iter()
+                .any(|c| c.client_addr.as_deref() == Some("10.0.0.3") && c.bytes_from_to == 5)
+        };
+        assert!(has_old_client(
+            storage.traffic_by_client(start, end, None).await.unwrap()
+        ));
+
+        let report = storage
+            .prune(RetentionPolicy {
+                raw_days: Some(10),
+                rollup_days: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.raw_rows, 2);
+        assert_eq!(report.rollup_rows, 0);
+        // Rollups still cover the pruned rows.
+        assert!(has_old_client(
+            storage.traffic_by_client(start, end, None).await.unwrap()
+        ));
+
+        let report = storage
+            .prune(RetentionPolicy {
+                raw_days: Some(10),
+                rollup_days: Some(10),
+            })
+            .await
+            .unwrap();
+        assert_eq!(report.raw_rows, 0);
+        assert!(report.rollup_rows > 0);
+        assert!(!has_old_client(
+            storage.traffic_by_client(start, end, None).await.unwrap()
+        ));
+        let sessions = storage
+            .sessions(at(old - DAY), end, TrafficFilter::default(), false, 100)
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn sqlite_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.db");
+        let storage = SqliteStorage::open(path.to_str().unwrap(), SqliteOptions::default())
+            .await
+            .unwrap();
+        check_backend(&storage).await;
+    }
+
+    #[tokio::test]
+    async fn memory_backend() {
+        check_backend(&MemoryStorage::new(1000, 30)).await;
+    }
+}
@@ -0,0 +1,205 @@
+// Live `LogEvent` stream over Server-Sent Events and WebSocket.
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use serde::Deserialize;
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::events::LogEvent;
+use crate::web::AppState;
+
+/// Query-string filters; every given filter must match.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StreamQuery {
+    /// Rule name.
+    pub rule: Option<String>,
+    /// Client IP.
+    pub client: Option<String>,
+    /// Comma-separated event types, e.g. `connection_error,connection_timeout`.
+    /// The `connection_` prefix may be omitted.
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+}
+
+impl StreamQuery {
+    fn matches(&self, event: &LogEvent) -> bool {
+        if let Some(rule) = &self.rule {
+            if event.name() != rule {
+                return false;
+            }
+        }
+        if let Some(client) = &self.client {
+            if event.client_addr() != Some(client.as_str()) {
+                return false;
+            }
+        }
+        if let Some(kinds) = &self.kind {
+            let kind = event.kind();
+            let short = kind.trim_start_matches("connection_");
+            if !kinds
+                .split(',')
+                .map(str::trim)
+                .any(|k| k == kind || k == short)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Sent instead of the events a slow consumer missed.
+fn lagged_json(dropped: u64) -> String {
+    serde_json::json!({ "type": "lagged", "dropped": dropped }).to_string()
+}
+
+/// Matching events of `rx` as `(event type, JSON)`, with a `lagged` item in
+/// place of the events a slow consumer missed. Ends when the sender is dropped.
+fn filtered(
+    rx: broadcast::Receiver<LogEvent>,
+    filter: StreamQuery,
+) -> impl Stream<Item = (&'static str, String)> {
+    BroadcastStream::new(rx).filter_map(move |item| match item {
+        Ok(event) if filter.matches(&event) => {
+            Some((event.kind(), serde_json::to_string(&event).ok()?))
+        }
+        Ok(_) => None,
+        Err(BroadcastStreamRecvError::Lagged(dropped)) => Some(("lagged", lagged_json(dropped))),
+    })
+}
+
+pub async fn sse_handler(
+    State(state): State<AppState>,
+    Query(filter): Query<StreamQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = filtered(state.log_tx.subscribe(), filter)
+        .map(|(kind, data)| Ok(Event::default().event(kind).data(data)));
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+pub async fn ws_handler(
+    State(state): State<AppState>,
+    Query(filter): Query<StreamQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let rx = state.log_tx.subscribe();
+    ws.on_upgrade(move |socket| ws_loop(socket, rx, filter))
+}
+
+async fn ws_loop(mut socket: WebSocket, rx: broadcast::Receiver<LogEvent>, filter: StreamQuery) {
+    let events = filtered(rx, filter);
+    tokio::pin!(events);
+    loop {
+        tokio::select! {
+            next = events.next() => {
+                let Some((_, text)) = next else { break };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => {
+                // The client only reads; anything but a close frame is ignored.
+                match incoming {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn error(name: &str, client: &str) -> LogEvent {
+        LogEvent::ConnectionError {
+            ts: Utc::now(),
+            session_id: 1,
+            name: name.into(),
+            local_port: 8080,
+            remote_address: "10.0.0.1".into(),
+            remote_port: 80,
+            client_addr: Some(client.into()),
+            error: "connection refused".into(),
+        }
+    }
+
+    fn opened(name: &str) -> LogEvent {
+        LogEvent::RuleOpened {
+            ts: Utc::now(),
+            name: name.into(),
+            next_change: None,
+        }
+    }
+
+    fn query(rule: Option<&str>, client: Option<&str>, kind: Option<&str>) -> StreamQuery {
+        StreamQuery {
+            rule: rule.map(Into::into),
+            client: client.map(Into::into),
+            kind: kind.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn filters() {
+        let event = error("web", "192.0.2.7");
+        assert!(query(None, None, None).matches(&event));
+        assert!(query(Some("web"), Some("192.0.2.7"), None).matches(&event));
+        assert!(!query(Some("db"), None, None).matches(&event));
+        assert!(!query(None, Some("192.0.2.8"), None).matches(&event));
+
+        // Full and short type names, lists with spaces.
+        assert!(query(None, None, Some("connection_error")).matches(&event));
+        assert!(query(None, None, Some("error")).matches(&event));
+        assert!(query(None, None, Some("timeout, error")).matches(&event));
+        assert!(!query(None, None, Some("timeout")).matches(&event));
+        assert!(!query(None, None, Some("err")).matches(&event));
+
+        // Rule events have no client and no `connection_` prefix.
+        assert!(query(None, None, Some("rule_opened")).matches(&opened("web")));
+        assert!(!query(None, None, Some("opened")).matches(&opened("web")));
+        assert!(!query(None, Some("192.0.2.7"), None).matches(&opened("web")));
+    }
+
+    #[tokio::test]
+    async fn lagged_consumer_is_told_and_continues() {
+        let (tx, rx) = broadcast::channel(2);
+        let events = filtered(rx, query(None, None, Some("error")));
+        tokio::pin!(events);
+        for i in 0..5 {
+            tx.send(error(&format!("r{i}"), "192.0.2.7")).unwrap();
+        }
+
+        let (kind, data) = events.next().await.unwrap();
+        assert_eq!(kind, "lagged");
+        let lagged: serde_json::Value = serde_json::from_str(&data).unwrap();
+        assert_eq!(
+            lagged,
+            serde_json::json!({ "type": "lagged", "dropped": 3 })
+        );
+
+        // The two newest events survive, then the stream keeps going.
+        let names = |data: &str| {
+            let v: serde_json::Value = serde_json::from_str(data).unwrap();
+            v["name"].as_str().unwrap().to_owned()
+        };
+        for expected in ["r3", "r4"] {
+            let (kind, data) = events.next().await.unwrap();
+            assert_eq!(kind, "connection_error");
+            assert_eq!(names(&data), expected);
+        }
+        tx.send(opened("r5")).unwrap();
+        tx.send(error("r6", "192.0.2.7")).unwrap();
+        let (_, data) = events.next().await.unwrap();
+        assert_eq!(names(&data), "r6");
+
+        drop(tx);
+        assert!(events.next().await.is_none());
+    }
+}
@@ -0,0 +1,638 @@
+// Проверка конфига целиком: все проблемы собираются за один проход и
+// привязываются к JSON‑пути поля (`connect_list[2].local_port`), чтобы их
+// можно было исправить за один заход. Ошибки не дают запуститься,
+// предупреждения только печатаются.
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
+
+use crate::loader::CLI_SOURCE;
+use crate::{db, Config, ConfigConnect, RuleDefaults};
+
+/// Сколько ждать ответа DNS для одного `remote_address`.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Одна найденная проблема: путь к полю в конфиге и описание.
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, path.into(), message.into());
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, path.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, path: String, message: String) {
+        self.0.push(Problem {
+            severity,
+            path,
+            message,
+        });
+    }
+}
+
+/// Число ошибок (без предупреждений).
+pub fn error_count(problems: &[Problem]) -> usize {
+    problems
+        .iter()
+        .filter(|p| p.severity == Severity::Error)
+        .count()
+}
+
+/// Полная проверка конфига, включая разрешение имён удалённых хостов.
+pub async fn validate_config(config: &Config) -> Vec<Problem> {
+    let mut problems = Problems::default();
+    for path in &config.unknown_fields {
+        problems.warning(path.clone(), "unknown field, ignored");
+    }
+    if let Some(defaults) = &config.defaults {
+        for (field, message) in settings_problems(defaults) {
+            problems.error(format!("defaults.{}", field), message);
+        }
+    }
+    check_rules(config, &mut problems);
+    if let Some(patterns) = &config.include {
+        for (index, pattern) in patterns.iter().enumerate() {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                problems.error(format!("include[{}]", index), e.to_string());
+            }
+        }
+    }
+    check_storage(config, &mut problems);
+    check_http(config, &mut problems);
+    problems.0.extend(resolve_hosts(&config.connect_list).await);
+    problems.0
+}
+
+/// Проблемы одного правила: `(поле, описание)`. Используется и при правке
+/// правил через HTTP API.
+pub fn rule_problems(rule: &ConfigConnect) -> Vec<(&'static str, &'static str)> {
+    let mut problems = Vec::new();
+    if rule.name.trim().is_empty() {
+        problems.push(("name", "must not be empty"));
+    }
+    match (rule.local_port, rule.local_port_range) {
+        (Some(0), _) => problems.push(("local_port", "must not be 0")),
+        (Some(_), Some(_)) => problems.push((
+            "local_port_range",
+            "must not be set together with local_port",
+        )),
+        (None, None) => problems.push(("local_port", "is required (or local_port_range)")),
+        _ => {}
+    }
+    match (rule.remote_port, rule.remote_port_range) {
+        (Some(0), _) => problems.push(("remote_port", "must not be 0")),
+        (Some(_), Some(_)) => problems.push((
+            "remote_port_range",
+            "must not be set together with remote_port",
+        )),
+        (None, None) => problems.push(("remote_port", "is required (or remote_port_range)")),
+        (None, Some(remote))
+            if rule.local_port_range.map(|local| local.len()) != Some(remote.len()) =>
+        {
+            problems.push((
+                "remote_port_range",
+                "must have as many ports as local_port_range, or use remote_port",
+            ))
+        }
+        _ => {}
+    }
+    let address = rule.remote_address.trim();
+    if address.is_empty() {
+        problems.push(("remote_address", "must not be empty"));
+    } else if address.contains(':') && address.parse::<IpAddr>().is_err() {
+        problems.push((
+            "remote_address",
+            "must be a host name or IP without a port, use remote_port",
+        ));
+    }
+    problems.extend(settings_problems(&RuleDefaults {
+        idle_timeout_seconds: rule.idle_timeout_seconds,
+        client_idle_timeout_seconds: rule.client_idle_timeout_seconds,
+        upstream_idle_timeout_seconds: rule.upstream_idle_timeout_seconds,
+        max_session_duration_seconds: rule.max_session_duration_seconds,
+        buffer_size: rule.buffer_size,
+        bind_address: rule.bind_address,
+    }));
+    if let Some(schedule) = &rule.schedule {
+        problems.extend(schedule.problems());
+    }
+    problems
+}
+
+/// Проблемы настроек, которые задаются и в правиле, и в `defaults`.
+fn settings_problems(settings: &RuleDefaults) -> Vec<(&'static str, &'static str)> {
+    let mut problems = Vec::new();
+    let timeouts = [
+        ("idle_timeout_seconds", settings.idle_timeout_seconds),
+        (
+            "client_idle_timeout_seconds",
+            settings.client_idle_timeout_seconds,
+        ),
+        (
+            "upstream_idle_timeout_seconds",
+            settings.upstream_idle_timeout_seconds,
+        ),
+        (
+            "max_session_duration_seconds",
+            settings.max_session_duration_seconds,
+        ),
+    ];
+    for (field, value) in timeouts {
+        if value == Some(0) {
+            problems.push((field, "must not be 0"));
+        }
+    }
+    if settings.buffer_size == Some(0) {
+        problems.push(("buffer_size", "must not be 0"));
+    }
+    problems
+}
+
+/// Ошибки списка правил: поля правил и повторы имён и портов. Этим же
+/// проверяется новый набор при перечитывании подключаемых файлов.
+pub fn rule_list_problems(config: &Config) -> Vec<Problem> {
+    let mut problems = Problems::default();
+    check_rules(config, &mut problems);
+    problems.0
+}
+
+/// Путь к правилу: `connect_list[3]` для основного файла,
+/// `<файл>: connect_list[1]` для подключённого (индекс внутри файла) или
+/// `-L <спецификация>` для правила из командной строки.
+fn rule_path(list: &[ConfigConnect], index: usize) -> String {
+    let source = &list[index].source;
+    if source.as_deref() == Some(CLI_SOURCE) {
+        return format!("-L {}", list[index].name);
+    }
+    let position = list[..index]
+        .iter()
+        .filter(|rule| rule.source == *source)
+        .count();
+    match source {
+        Some(file) => format!("{}: connect_list[{}]", file, position),
+        None => format!("connect_list[{}]", position),
+    }
+}
+
+/// Могут ли два слушателя на одном порту помешать друг другу: один и тот же
+/// адрес, или один из них слушает все адреса. `::` в Linux по умолчанию
+/// принимает и IPv4, а `0.0.0.0` не мешает конкретному IPv6‑адресу.
+fn addresses_overlap(a: IpAddr, b: IpAddr) -> bool {
+    let covers =
+        |wide: IpAddr, other: IpAddr| wide.is_unspecified() && (wide.is_ipv6() || other.is_ipv4());
+    a == b || covers(a, b) || covers(b, a)
+}
+
+fn check_rules(config: &Config, problems: &mut Problems) {
+    let defaults = config.rule_defaults();
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    // Занятые адреса на каждом порту и правило, которое их заняло.
+    let mut ports: HashMap<u16, Vec<(IpAddr, usize)>> = HashMap::new();
+    let http_listen = config
+        .http_listen
+        .as_deref()
+        .and_then(|addr| addr.parse::<SocketAddr>().ok());
+    for (index, rule) in config.connect_list.iter().enumerate() {
+        let at = rule_path(&config.connect_list, index);
+        for (field, message) in rule_problems(rule) {
+            problems.error(format!("{}.{}", at, field), message);
+        }
+        if let Some(&first) = names.get(rule.name.as_str()) {
+            problems.error(
+                format!("{}.name", at),
+                format!(
+                    "duplicate name '{}', already used by {}",
+                    rule.name,
+                    rule_path(&config.connect_list, first)
+                ),
+            );
+        } else {
+            names.insert(&rule.name, index);
+        }
+        let field = if rule.local_port_range.is_some() && rule.local_port.is_none() {
+            "local_port_range"
+        } else {
+            "local_port"
+        };
+        let address = rule.settings(&defaults, None).bind_address.value;
+        let local_ports: Vec<u16> = rule
+            .local_ports()
+            .into_iter()
+            .filter(|&port| port != 0)
+            .collect();
+        // Для пересекающихся диапазонов достаточно первого общего порта.
+        let duplicate = local_ports.iter().find_map(|port| {
+            ports
+                .get(port)?
+                .iter()
+                .find(|(other, _)| addresses_overlap(address, *other))
+                .map(|&(other, first)| (port, other, first))
+        });
+        if let Some((port, other, first)) = duplicate {
+            problems.error(
+                format!("{}.{}", at, field),
+                format!(
+                    "duplicate local_port {} on {}, already used by {} ('{}') on {}",
+                    port,
+                    address,
+                    rule_path(&config.connect_list, first),
+                    config.connect_list[first].name,
+                    other
+                ),
+            );
+        } else {
+            for &port in &local_ports {
+                ports.entry(port).or_default().push((address, index));
+            }
+        }
+        if let Some(http) = http_listen.filter(|http| {
+            local_ports.contains(&http.port()) && addresses_overlap(address, http.ip())
+        }) {
+            problems.error(
+                format!("{}.{}", at, field),
+                format!(
+                    "{} is also used by http_listen",
+                    SocketAddr::new(address, http.port())
+                ),
+            );
+        }
+    }
+}
+
+fn check_storage(config: &Config, problems: &mut Problems) {
+    let has_db = config.database_url.is_some() || config.database_path.is_some();
+    let sqlite = config.database_url.is_none() && config.database_path.is_some();
+    if config.database_url.is_some() {
+        if !cfg!(feature = "postgres") {
+            problems.error("database_url", "needs a build with the `postgres` feature");
+        }
+        if config.database_path.is_some() {
+            problems.warning("database_path", "ignored because database_url is set");
+        }
+    }
+    if let Some(path) = &config.database_ca_file {
+        if config.database_url.is_none() {
+            problems.warning(
+                "database_ca_file",
+                "ignored because database_url is not set",
+            );
+        } else if !Path::new(path).is_file() {
+            problems.error(
+                "database_ca_file",
+                format!("file '{}' does not exist", path),
+            );
+        }
+    }
+    if config
+        .instance_id
+        .as_deref()
+        .is_some_and(|id| id.trim().is_empty())
+    {
+        problems.error("instance_id", "must not be empty");
+    }
+    if let Some(mode) = &config.sqlite_synchronous {
+        if !db::SYNCHRONOUS_MODES.contains(&mode.as_str()) {
+            problems.error(
+                "sqlite_synchronous",
+                format!(
+                    "'{}' is not one of {}",
+                    mode,
+                    db::SYNCHRONOUS_MODES.join(", ")
+                ),
+            );
+        }
+    }
+    if config.sqlite_read_connections == Some(0) {
+        problems.error("sqlite_read_connections", "must be at least 1");
+    }
+    if config.sqlite_busy_timeout_ms == Some(0) {
+        problems.warning(
+            "sqlite_busy_timeout_ms",
+            "0 makes every write fail while a backup or reader holds a lock",
+        );
+    }
+    if !sqlite {
+        let sqlite_fields = [
+            ("sqlite_synchronous", config.sqlite_synchronous.is_some()),
+            (
+                "sqlite_cache_size_mb",
+                config.sqlite_cache_size_mb.is_some(),
+            ),
+            (
+                "sqlite_busy_timeout_ms",
+                config.sqlite_busy_timeout_ms.is_some(),
+            ),
+            (
+                "sqlite_read_connections",
+                config.sqlite_read_connections.is_some(),
+            ),
+        ];
+        for (field, _) in sqlite_fields.iter().filter(|(_, set)| *set) {
+            problems.warning(*field, "ignored because SQLite is not used");
+        }
+    }
+    if config.db_buffer_time_sec == Some(0) {
+        problems.error("db_buffer_time_sec", "must be at least 1");
+    }
+    if config.max_buffer_count == Some(0) {
+        problems.error("max_buffer_count", "must be at least 1");
+    }
+    if config.db_spool_max_mb == Some(0) {
+        problems.warning(
+            "db_spool_max_mb",
+            "0 disables the journal, rows are dropped while the database is down",
+        );
+    }
+    if !has_db {
+        let writer_fields = [
+            ("db_buffer_time_sec", config.db_buffer_time_sec.is_some()),
+            ("max_buffer_count", config.max_buffer_count.is_some()),
+            ("db_spool_path", config.db_spool_path.is_some()),
+            ("db_spool_max_mb", config.db_spool_max_mb.is_some()),
+        ];
+        for (field, _) in writer_fields.iter().filter(|(_, set)| *set) {
+            problems.warning(*field, "ignored because no database is configured");
+        }
+    } else if config.memory_stats_rows.is_some() {
+        problems.warning(
+            "memory_stats_rows",
+            "ignored because a database is configured",
+        );
+    }
+    for (field, days) in [
+        ("retention_days", config.retention_days),
+        ("rollup_retention_days", config.rollup_retention_days),
+    ] {
+        if days == Some(0) {
+            problems.error(field, "must be at least 1, omit it to keep data forever");
+        }
+    }
+    if let (Some(raw), Some(rollup)) = (config.retention_days, config.rollup_retention_days) {
+        if rollup < raw {
+            problems.warning(
+                "rollup_retention_days",
+                format!(
+                    "shorter than retention_days ({}), long-range charts will lose data first",
+                    raw
+                ),
+            );
+        }
+    }
+}
+
+fn check_http(config: &Config, problems: &mut Problems) {
+    if let Some(addr) = &config.http_listen {
+        if addr.parse::<SocketAddr>().is_err() {
+            problems.error(
+                "http_listen",
+                format!("'{}' is not an address like 127.0.0.1:8080", addr),
+            );
+        }
+    }
+    let admin = config.http_admin.unwrap_or(false);
+    let token = config
+        .http_admin_token
+        .as_deref()
+        .filter(|t| !t.trim().is_empty());
+    if admin && token.is_none() {
+        problems.error("http_admin_token", "is required when http_admin is enabled");
+    }
+    if !admin && config.http_admin_token.is_some() {
+        problems.warning(
+            "http_admin_token",
+            "ignored because http_admin is not enabled",
+        );
+    }
+    match (&config.http_tls_cert, &config.http_tls_key) {
+        (Some(_), None) => problems.error("http_tls_key", "must be set with http_tls_cert"),
+        (None, Some(_)) => problems.error("http_tls_cert", "must be set with http_tls_key"),
+        _ => {}
+    }
+    for (field, path) in [
+        ("http_tls_cert", &config.http_tls_cert),
+        ("http_tls_key", &config.http_tls_key),
+    ] {
+        let Some(path) = path else { continue };
+        if config.http_listen.is_none() {
+            problems.warning(field, "ignored because http_listen is not set");
+        } else if !Path::new(path).is_file() {
+            problems.error(field, format!("file '{}' does not exist", path));
+        }
+    }
+}
+
+/// Имена удалённых хостов, которые не разрешаются, — только предупреждение:
+/// DNS может заработать позже, адрес разрешается заново при каждом подключении.
+async fn resolve_hosts(list: &[ConfigConnect]) -> Vec<Problem> {
+    let mut lookups = JoinSet::new();
+    for (index, rule) in list.iter().enumerate() {
+        let host = rule.remote_address.trim().to_string();
+        let invalid = rule_problems(rule)
+            .iter()
+            .any(|(field, _)| *field == "remote_address");
+        if invalid || host.parse::<IpAddr>().is_ok() {
+            continue;
+        }
+        // Для разрешения имени порт не важен, берётся первый из правила.
+        let Some((_, port)) = rule.port_pairs().first().copied() else {
+            continue;
+        };
+        lookups.spawn(async move {
+            let result = timeout(
+                RESOLVE_TIMEOUT,
+                tokio::net::lookup_host((host.as_str(), port)),
+            )
+            .await;
+            let message = match result {
+                Ok(Ok(mut addrs)) => match addrs.next() {
+                    Some(_) => return None,
+                    None => format!("'{}' resolves to no addresses", host),
+                },
+                Ok(Err(e)) => format!("cannot resolve '{}': {}", host, e),
+                Err(_) => format!(
+                    "cannot resolve '{}': no answer in {:?}",
+                    host, RESOLVE_TIMEOUT
+                ),
+            };
+            Some((index, message))
+        });
+    }
+    let mut failed = Vec::new();
+    while let Some(result) = lookups.join_next().await {
+        if let Ok(Some(found)) = result {
+            failed.push(found);
+        }
+    }
+    failed.sort();
+    failed
+        .into_iter()
+        .map(|(index, message)| Problem {
+            severity: Severity::Warning,
+            path: format!("{}.remote_address", rule_path(list, index)),
+            message,
+        })
+        .collect()
+}
+
+/// Путь в формате `connect_list[1].field` для поля, пропущенного при разборе.
+pub fn json_path(path: &serde_ignored::Path) -> String {
+    use serde_ignored::Path;
+    match path {
+        Path::Root => String::new(),
+        Path::Seq { parent, index } => format!("{}[{}]", json_path(parent), index),
+        Path::Map { parent, key } => match json_path(parent) {
+            parent if parent.is_empty() => key.clone(),
+            parent => format!("{}.{}", parent, key),
+        },
+        Path::Some { parent }
+        | Path::NewtypeStruct { parent }
+        | Path::NewtypeVariant { parent } => json_path(parent),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader;
+    use serde_json::{json, Value};
+
+    fn list_problems(config: Value) -> Vec<String> {
+        let (config, _) = loader::from_value::<Config>(config).unwrap();
+        rule_list_problems(&config)
+            .iter()
+            .map(|p| p.to_string())
+            .collect()
+    }
+
+    fn rule(name: &str, bind_address: Option<&str>, local_port: u16) -> Value {
+        let mut rule = json!({
+            "name": name,
+            "local_port": local_port,
+            "remote_address": "127.0.0.1",
+            "remote_port": 80
+        });
+        if let Some(address) = bind_address {
+            rule["bind_address"] = json!(address);
+        }
+        rule
+    }
+
+    #[test]
+    fn same_port_on_different_addresses() {
+        assert!(list_problems(json!({ "connect_list": [
+            rule("a", Some("127.0.0.1"), 8080),
+            rule("b", Some("127.0.0.2"), 8080),
+            rule("c", Some("::1"), 8080),
+        ]}))
+        .is_empty());
+
+        let problems = list_problems(json!({ "connect_list": [
+            rule("a", Some("127.0.0.1"), 8080),
+            rule("b", Some("127.0.0.1"), 8080),
+        ]}));
+        assert_eq!(
+            problems,
+            [
+                "error: connect_list[1].local_port: duplicate local_port 8080 on 127.0.0.1, \
+              already used by connect_list[0] ('a') on 127.0.0.1"
+            ]
+        );
+    }
+
+    #[test]
+    fn wildcard_address_overlaps() {
+        // Адрес по умолчанию — 0.0.0.0, он пересекается с любым IPv4.
+        let problems = list_problems(json!({ "connect_list": [
+            rule("a", Some("127.0.0.1"), 8080),
+            rule("b", None, 8080),
+        ]}));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("on 0.0.0.0, already used by connect_list[0]"));
+
+        // `defaults.bind_address` тоже учитывается.
+        assert!(list_problems(json!({
+            "defaults": { "bind_address": "127.0.0.1" },
+            "connect_list": [rule("a", None, 8080), rule("b", Some("127.0.0.2"), 8080)]
+        }))
+        .is_empty());
+
+        assert!(list_problems(json!({ "connect_list": [
+            rule("a", Some("0.0.0.0"), 8080),
+            rule("b", Some("::1"), 8080),
+        ]}))
+        .is_empty());
+        assert_eq!(
+            list_problems(json!({ "connect_list": [
+                rule("a", Some("127.0.0.1"), 8080),
+                rule("b", Some("::"), 8080),
+            ]}))
+            .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn http_listen_address_and_port() {
+        let problems = list_problems(json!({
+            "http_listen": "127.0.0.1:8080",
+            "connect_list": [
+                rule("a", Some("127.0.0.2"), 8080),
+                rule("b", Some("127.0.0.1"), 8080),
+            ]
+        }));
+        assert_eq!(
+            problems,
+            ["error: connect_list[1].local_port: 127.0.0.1:8080 is also used by http_listen"]
+        );
+        let problems = list_problems(json!({
+            "http_listen": "127.0.0.1:8080",
+            "connect_list": [rule("a", None, 8080)]
+        }));
+        assert_eq!(
+            problems,
+            ["error: connect_list[0].local_port: 0.0.0.0:8080 is also used by http_listen"]
+        );
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut bad = rule("b", None, 0);
+        bad["remote_port"] = json!(0);
+        let problems = list_problems(json!({ "connect_list": [
+            rule("a", None, 8080),
+            bad,
+            rule("a", None, 8080),
+        ]}));
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+    }
+}
@@ -1,28 +1,88 @@
-use axum::response::Html;
+use axum::body::Body;
+use axum::response::{IntoResponse, Response};
 use axum::{
-    extract::{Query, State},
-    routing::get,
+    extract::{ConnectInfo as PeerAddr, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    routing::{get, post, put},
     Json, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
 use chrono::{DateTime, TimeZone, Utc};
+use log::{error, info};
 use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+use tokio::time::Duration;
+use tokio_stream::wrappers::ReceiverStream;
 
-use crate::db::{query_traffic_by_client, ClientTraffic, SharedDb};
+use crate::db::{
+    check_timeseries_range, Breakdown, BreakdownRow, ClientTraffic, FailureReason, GroupBy,
+    PruneReport, RetentionPolicy, SessionInfo, TimeseriesPoint, TopBy, TrafficFilter,
+};
+use crate::events::LogEvent;
+use crate::export::{spawn_export, ExportFormat, ExportKind, ExportRequest};
+use crate::live::{ActiveSession, LiveState, RecentError};
+use crate::rules::{RuleError, RuleManager};
+use crate::schema;
+use crate::storage::SharedStorage;
+use crate::stream::{sse_handler, ws_handler};
+use crate::writer::{WriterStats, WriterStatsSnapshot};
+use crate::ConfigConnect;
 
 #[derive(Clone, serde::Serialize)]
 pub struct ConnectInfo {
     pub name: String,
-    pub local_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_port_range: Option<String>,
     pub remote_address: String,
-    pub remote_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_port_range: Option<String>,
+    pub enabled: bool,
+    /// Whether the rule's ports are open right now (enabled and inside a schedule window).
+    pub open: bool,
+    /// When the schedule opens or closes the rule next.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_change: Option<DateTime<Utc>>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db: Option<SharedDb>,
-    pub connects: Vec<ConnectInfo>,
+    pub db: Option<SharedStorage>,
+    pub rules: Arc<RuleManager>,
+    pub live: Arc<LiveState>,
+    pub log_tx: tokio::sync::broadcast::Sender<LogEvent>,
+    pub retention: RetentionPolicy,
+    pub writer: Option<Arc<WriterStats>>,
+    pub admin: Arc<AdminAccess>,
+}
+
+/// Access to the endpoints that change rules or touch the database
+/// (`POST`/`PUT`/`DELETE /config/connects`, `/admin/*`).
+#[derive(Clone, Debug, Default)]
+pub struct AdminAccess {
+    /// Bearer token; `None` keeps these endpoints disabled.
+    pub token: Option<String>,
+    /// Reverse proxies whose `X-Actor` / `X-Forwarded-User` headers are trusted.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+/// Paths to the PEM certificate chain and private key used to serve HTTPS.
+#[derive(Clone, Debug)]
+pub struct TlsPaths {
+    pub cert: String,
+    pub key: String,
 }
 
+/// How often the certificate and key files are checked for changes.
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Deserialize)]
 pub struct StatsQuery {
     pub start: String,
@@ -30,7 +90,59 @@ pub struct StatsQuery {
     pub name: Option<String>,
 }
 
-fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+#[derive(Deserialize)]
+pub struct TimeseriesQuery {
+    pub start: String,
+    pub end: String,
+    /// `minute`, `hour`, `day` or a step in seconds. Defaults to `hour`.
+    pub step: Option<String>,
+    /// IANA timezone for bucket boundaries, e.g. `Europe/Moscow`. Defaults to UTC.
+    pub tz: Option<String>,
+    pub group_by: Option<GroupBy>,
+    pub name: Option<String>,
+    pub client: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SessionsQuery {
+    pub start: String,
+    pub end: String,
+    pub name: Option<String>,
+    pub client: Option<String>,
+    /// Only sessions that ended with an error, timeout or I/O error.
+    #[serde(default)]
+    pub failed: bool,
+    /// Defaults to 100, at most 1000.
+    pub limit: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct BreakdownQuery {
+    pub start: String,
+    pub end: String,
+    /// `rule`, `client`, `upstream` or `client_rule`.
+    pub by: Breakdown,
+    /// `bytes` (default) or `connections`.
+    #[serde(default)]
+    pub order: TopBy,
+    /// Defaults to 10, at most 1000.
+    pub limit: Option<u32>,
+    pub name: Option<String>,
+    pub client: Option<String>,
+}
+
+fn parse_step(s: &str) -> Result<i64, String> {
+    match s {
+        "minute" => Ok(60),
+        "hour" => Ok(3600),
+        "day" => Ok(86400),
+        other => other
+            .parse::<i64>()
+            .map_err(|_| "invalid step, use minute, hour, day or seconds".to_string()),
+    }
+}
+
+pub(crate) fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
     if let Ok(secs) = s.parse::<i64>() {
         return Utc
             .timestamp_opt(secs, 0)
@@ -49,15 +161,15 @@ async fn stats_clients_handler(
     let end = parse_time(&q.end).map_err(|e| (axum::http::StatusCode::BAD_REQUEST, e))?;
 
     if let Some(db) = state.db {
-        let rows = if let Some(name) = q.name.clone() {
-            crate::db::query_traffic_by_client_filtered(&db, start, end, Some(name))
-                .await
-                .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-        } else {
-            query_traffic_by_client(&db, start, end)
-                .await
-                .map_err(|e| (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
-        };
+        let rows = db
+            .traffic_by_client(start, end, q.name)
+            .await
+            .map_err(|e| {
+                (
+                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("{:#}", e),
+                )
+            })?;
         Ok(Json(rows))
     } else {
         Err((
@@ -67,165 +179,586 @@ async fn stats_clients_handler(
     }
 }
 
+async fn stats_timeseries_handler(
+    State(state): State<AppState>,
+    Query(q): Query<TimeseriesQuery>,
+) -> Result<Json<Vec<TimeseriesPoint>>, (StatusCode, String)> {
+    let start = parse_time(&q.start).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let end = parse_time(&q.end).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let step = parse_step(q.step.as_deref().unwrap_or("hour"))
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let tz: chrono_tz::Tz =
+        q.tz.as_deref()
+            .unwrap_or("UTC")
+            .parse()
+            .map_err(|_| (StatusCode::BAD_REQUEST, "unknown timezone".to_string()))?;
+    check_timeseries_range(start, end, step).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let db = db_or_unavailable(&state)?;
+    let filter = TrafficFilter {
+        name: q.name,
+        client_addr: q.client,
+    };
+    let points = db
+        .traffic_timeseries(start, end, step, tz, q.group_by, filter)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    Ok(Json(points))
+}
+
+fn db_or_unavailable(state: &AppState) -> Result<SharedStorage, (StatusCode, String)> {
+    state.db.clone().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "database is not configured".to_string(),
+    ))
+}
+
+async fn stats_breakdown_handler(
+    State(state): State<AppState>,
+    Query(q): Query<BreakdownQuery>,
+) -> Result<Json<Vec<BreakdownRow>>, (StatusCode, String)> {
+    let start = parse_time(&q.start).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let end = parse_time(&q.end).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let db = db_or_unavailable(&state)?;
+    let filter = TrafficFilter {
+        name: q.name,
+        client_addr: q.client,
+    };
+    let limit = q.limit.unwrap_or(10).min(1000);
+    let rows = db
+        .traffic_breakdown(start, end, q.by, q.order, filter, limit)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    Ok(Json(rows))
+}
+
+async fn stats_sessions_handler(
+    State(state): State<AppState>,
+    Query(q): Query<SessionsQuery>,
+) -> Result<Json<Vec<SessionInfo>>, (StatusCode, String)> {
+    let start = parse_time(&q.start).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let end = parse_time(&q.end).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let db = db_or_unavailable(&state)?;
+    let filter = TrafficFilter {
+        name: q.name,
+        client_addr: q.client,
+    };
+    let limit = q.limit.unwrap_or(100).min(1000);
+    let rows = db
+        .sessions(start, end, filter, q.failed, limit)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    Ok(Json(rows))
+}
+
+async fn stats_failures_handler(
+    State(state): State<AppState>,
+    Query(q): Query<SessionsQuery>,
+) -> Result<Json<Vec<FailureReason>>, (StatusCode, String)> {
+    let start = parse_time(&q.start).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let end = parse_time(&q.end).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let db = db_or_unavailable(&state)?;
+    let filter = TrafficFilter {
+        name: q.name,
+        client_addr: q.client,
+    };
+    let mut rows = db
+        .failure_reasons(start, end, filter)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    // Already ordered by count, so `limit` gives the top reasons.
+    if let Some(limit) = q.limit {
+        rows.truncate(limit.min(1000) as usize);
+    }
+    Ok(Json(rows))
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    pub start: String,
+    pub end: String,
+    pub name: Option<String>,
+    /// `csv` (default), `ndjson` or `parquet`.
+    pub format: Option<String>,
+}
+
+async fn export_connections_handler(
+    State(state): State<AppState>,
+    Query(q): Query<ExportQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    export_response(&state, ExportKind::Connections, q)
+}
+
+async fn export_clients_handler(
+    State(state): State<AppState>,
+    Query(q): Query<ExportQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    export_response(&state, ExportKind::Clients, q)
+}
+
+/// Streams the export as a download; errors after the first byte abort the
+/// response, so a truncated file is never mistaken for a complete one.
+fn export_response(
+    state: &AppState,
+    kind: ExportKind,
+    q: ExportQuery,
+) -> Result<Response, (StatusCode, String)> {
+    let start = parse_time(&q.start).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let end = parse_time(&q.end).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if end <= start {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "end must be after start".to_string(),
+        ));
+    }
+    let format: ExportFormat = q
+        .format
+        .as_deref()
+        .unwrap_or("csv")
+        .parse()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let db = db_or_unavailable(state)?;
+    let req = ExportRequest {
+        kind,
+        format,
+        start,
+        end,
+        name: q.name,
+    };
+    let disposition = format!("attachment; filename=\"{}\"", req.file_name());
+    let body = Body::from_stream(ReceiverStream::new(spawn_export(db, req)));
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Overrides for an on-demand prune; unset values fall back to the config.
+#[derive(Deserialize)]
+pub struct PruneQuery {
+    pub retention_days: Option<u64>,
+    pub rollup_retention_days: Option<u64>,
+}
+
+async fn admin_prune_handler(
+    State(state): State<AppState>,
+    Query(q): Query<PruneQuery>,
+) -> Result<Json<PruneReport>, (StatusCode, String)> {
+    let db = db_or_unavailable(&state)?;
+    let policy = RetentionPolicy {
+        raw_days: q.retention_days.or(state.retention.raw_days),
+        rollup_days: q.rollup_retention_days.or(state.retention.rollup_days),
+    };
+    let report = db
+        .prune(policy)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    info!(
+        "Pruned on demand: {} raw rows, {} rollup rows, freed {} pages",
+        report.raw_rows, report.rollup_rows, report.freed_pages
+    );
+    Ok(Json(report))
+}
+
+async fn admin_writer_handler(
+    State(state): State<AppState>,
+) -> Result<Json<WriterStatsSnapshot>, (StatusCode, String)> {
+    state.writer.as_ref().map(|w| Json(w.snapshot())).ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "database is not configured".to_string(),
+    ))
+}
+
+/// Chunk size used to stream a backup file.
+const BACKUP_CHUNK: usize = 64 * 1024;
+
+/// Takes an online backup into a temporary file and streams it as a download.
+/// The file is removed once sent or when the client goes away.
+async fn admin_backup_handler(
+    State(state): State<AppState>,
+) -> Result<Response, (StatusCode, String)> {
+    let db = db_or_unavailable(&state)?;
+    let now = Utc::now();
+    let tmp = db
+        .backup_to_temp()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    let file = tokio::fs::File::open(&tmp)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    info!("Streaming database backup ({})", tmp.display());
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<bytes::Bytes>>(4);
+    tokio::spawn(async move {
+        let mut file = file;
+        loop {
+            let mut buf = vec![0u8; BACKUP_CHUNK];
+            let chunk = match file.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Ok(bytes::Bytes::from(buf))
+                }
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+        drop(file);
+        // Deletes the copy.
+        drop(tmp);
+    });
+    let disposition = format!(
+        "attachment; filename=\"rs-port-forward-{}.sqlite\"",
+        now.format("%Y%m%d-%H%M%S")
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/vnd.sqlite3".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
+
 async fn connects_handler(State(state): State<AppState>) -> Json<Vec<ConnectInfo>> {
-    Json(state.connects.clone())
+    let now = Utc::now();
+    let connects = state
+        .rules
+        .list()
+        .await
+        .iter()
+        .map(|c| ConnectInfo {
+            name: c.name.clone(),
+            local_port: c.local_port,
+            local_port_range: c.local_port_range.map(|r| r.to_string()),
+            remote_address: c.remote_address.clone(),
+            remote_port: c.remote_port,
+            remote_port_range: c.remote_port_range.map(|r| r.to_string()),
+            enabled: c.is_enabled(),
+            open: c.is_open(now),
+            next_change: c
+                .schedule
+                .as_ref()
+                .filter(|_| c.is_enabled())
+                .and_then(|s| s.next_change(now)),
+        })
+        .collect();
+    Json(connects)
 }
 
-pub async fn run_http(addr: &str, state: AppState) -> anyhow::Result<()> {
+/// JSON Schema of the config file, same as `rs-port-forward schema`.
+async fn config_schema_handler() -> Json<serde_json::Value> {
+    Json(schema::config_schema())
+}
+
+/// Rejects the request unless the admin endpoints are enabled and it carries
+/// `Authorization: Bearer <http_admin_token>`.
+async fn require_admin(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(token) = &state.admin.token else {
+        return (
+            StatusCode::FORBIDDEN,
+            "admin API is disabled, set http_admin and http_admin_token".to_string(),
+        )
+            .into_response();
+    };
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if !given.is_some_and(|given| token_eq(given.as_bytes(), token.as_bytes())) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "missing or wrong bearer token".to_string(),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Compares tokens without stopping at the first differing byte.
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Who made a rule change: the client IP, or the `X-Actor` / `X-Forwarded-User`
+/// header when the request comes from one of `http_trusted_proxies`.
+fn actor(headers: &HeaderMap, peer: SocketAddr, admin: &AdminAccess) -> String {
+    if !admin.trusted_proxies.contains(&peer.ip()) {
+        return peer.ip().to_string();
+    }
+    ["x-actor", "x-forwarded-user"]
+        .iter()
+        .filter_map(|h| headers.get(*h).and_then(|v| v.to_str().ok()))
+        .find(|v| !v.is_empty())
+        .map(|v| format!("{} (via {})", v, peer.ip()))
+        .unwrap_or_else(|| peer.ip().to_string())
+}
+
+fn rule_error(e: RuleError) -> (StatusCode, String) {
+    let status = match e {
+        RuleError::NotFound(_) => StatusCode::NOT_FOUND,
+        RuleError::Conflict(_) => StatusCode::CONFLICT,
+        RuleError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        RuleError::Bind(_) => StatusCode::CONFLICT,
+        RuleError::Persist(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+async fn create_connect_handler(
+    State(state): State<AppState>,
+    PeerAddr(peer): PeerAddr<SocketAddr>,
+    headers: HeaderMap,
+    Json(rule): Json<ConfigConnect>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state
+        .rules
+        .create(rule, &actor(&headers, peer, &state.admin))
+        .await
+        .map_err(rule_error)?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn update_connect_handler(
+    State(state): State<AppState>,
+    PeerAddr(peer): PeerAddr<SocketAddr>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Json(rule): Json<ConfigConnect>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state
+        .rules
+        .update(&name, rule, &actor(&headers, peer, &state.admin))
+        .await
+        .map_err(rule_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_connect_handler(
+    State(state): State<AppState>,
+    PeerAddr(peer): PeerAddr<SocketAddr>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state
+        .rules
+        .delete(&name, &actor(&headers, peer, &state.admin))
+        .await
+        .map_err(rule_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn run_http(
+    listener: tokio::net::TcpListener,
+    state: AppState,
+    tls: Option<TlsPaths>,
+) -> anyhow::Result<()> {
     let app = Router::new()
         .route("/", get(index_handler))
+        .route("/assets/:file", get(asset_handler))
+        .route("/sessions/active", get(active_sessions_handler))
+        .route("/errors/recent", get(recent_errors_handler))
+        .route("/events/stream", get(sse_handler))
+        .route("/events/ws", get(ws_handler))
         .route("/stats/clients", get(stats_clients_handler))
+        .route("/stats/timeseries", get(stats_timeseries_handler))
+        .route("/stats/sessions", get(stats_sessions_handler))
+        .route("/stats/failures", get(stats_failures_handler))
+        .route("/stats/breakdown", get(stats_breakdown_handler))
+        .route("/export/connections", get(export_connections_handler))
+        .route("/export/clients", get(export_clients_handler))
         .route("/config/connects", get(connects_handler))
-        .with_state(state);
+        .route("/config/schema", get(config_schema_handler));
+    // Endpoints that change rules or expose the whole database need the token.
+    let admin = Router::new()
+        .route("/admin/prune", post(admin_prune_handler))
+        .route("/admin/writer", get(admin_writer_handler))
+        .route("/admin/db/backup", get(admin_backup_handler))
+        .route("/config/connects", post(create_connect_handler))
+        .route(
+            "/config/connects/:name",
+            put(update_connect_handler).delete(delete_connect_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin));
+    let app = app.merge(admin).with_state(state);
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    match tls {
+        Some(tls) => {
+            // Only the first call wins; later calls (e.g. in tests) are harmless.
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            let config = RustlsConfig::from_pem_file(&tls.cert, &tls.key).await?;
+            tokio::spawn(watch_tls_files(config.clone(), tls));
+            axum_server::from_tcp_rustls(listener.into_std()?, config)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await?;
+        }
+    }
     Ok(())
 }
 
-async fn index_handler() -> Html<&'static str> {
-    // Simple Bootstrap-based page with a date range form and table
-    Html(
-        r#"<!doctype html>
-<html lang="en">
-  <head>
-    <meta charset="utf-8">
-    <meta name="viewport" content="width=device-width, initial-scale=1">
-    <title>rs-port-forward — Client Traffic</title>
-    <link href="https://cdn.jsdelivr.net/npm/bootstrap@5.3.3/dist/css/bootstrap.min.css" rel="stylesheet" integrity="sha384-QWTKZyjpPEjISv5WaRU9OFeRpok6YctnYmDr5pNlyT2bRjXh0JMhjY6hW+ALEwIH" crossorigin="anonymous">
-  </head>
-  <body>
-    <div class="container py-4">
-      <h1 class="mb-4">Client traffic</h1>
-      <form id="range-form" class="row gy-2 gx-3 align-items-end mb-4">
-        <div class="col-auto">
-          <label for="start" class="form-label">Start</label>
-          <input type="datetime-local" id="start" class="form-control" required>
-        </div>
-        <div class="col-auto">
-          <label for="end" class="form-label">End</label>
-          <input type="datetime-local" id="end" class="form-control" required>
-        </div>
-        <div class="col-auto">
-          <label for="conn" class="form-label">Connection</label>
-          <select id="conn" class="form-select">
-            <option value="">All connections</option>
-          </select>
-        </div>
-        <div class="col-auto">
-          <button class="btn btn-primary" type="submit">Load</button>
-        </div>
-      </form>
-
-      <div id="alert" class="alert alert-danger d-none" role="alert"></div>
-
-      <div class="table-responsive">
-        <table class="table table-striped table-hover" id="stats-table">
-          <thead>
-            <tr>
-              <th scope="col">#</th>
-              <th scope="col">Client</th>
-              <th scope="col">Bytes client→remote</th>
-              <th scope="col">Bytes remote→client</th>
-              <th scope="col">Total</th>
-            </tr>
-          </thead>
-          <tbody></tbody>
-        </table>
-      </div>
-    </div>
-
-    <script>
-      function toLocalInputValue(d) {
-        const off = d.getTimezoneOffset();
-        const local = new Date(d.getTime() - off * 60000);
-        return local.toISOString().slice(0,16); // YYYY-MM-DDTHH:MM
-      }
-
-      function toISOStringFromInputValue(v) {
-        // v is 'YYYY-MM-DDTHH:MM' in local time
-        const d = new Date(v);
-        return d.toISOString();
-      }
-
-      function fmtBytes(n) {
-        let bytes = Number(n) || 0;
-        const units = ['B','KB','MB','GB','TB','PB'];
-        if (bytes < 1024) return `${bytes} B`;
-        let i = Math.floor(Math.log(bytes) / Math.log(1024));
-        i = Math.min(i, units.length - 1);
-        const val = bytes / Math.pow(1024, i);
-        const nf = new Intl.NumberFormat(undefined, { maximumFractionDigits: 2 });
-        return `${nf.format(val)} ${units[i]}`;
-      }
-
-      async function loadStats() {
-        const alertBox = document.getElementById('alert');
-        alertBox.classList.add('d-none');
-        const startVal = document.getElementById('start').value;
-        const endVal = document.getElementById('end').value;
-        const connVal = document.getElementById('conn').value;
-        if (!startVal || !endVal) return;
-        try {
-          const startIso = toISOStringFromInputValue(startVal);
-          const endIso = toISOStringFromInputValue(endVal);
-          const params = new URLSearchParams({ start: startIso, end: endIso });
-          if (connVal) params.set('name', connVal);
-          const res = await fetch(`/stats/clients?${params.toString()}`);
-          if (!res.ok) throw new Error(`HTTP ${res.status}`);
-          const data = await res.json();
-          const tbody = document.querySelector('#stats-table tbody');
-          tbody.innerHTML = '';
-          data.forEach((row, idx) => {
-            const total = (row.bytes_from_to || 0) + (row.bytes_to_from || 0);
-            const tr = document.createElement('tr');
-            tr.innerHTML = `
-              <th scope="row">${idx+1}</th>
-              <td>${row.client_addr ?? '<em>unknown</em>'}</td>
-              <td>${fmtBytes(row.bytes_from_to)}</td>
-              <td>${fmtBytes(row.bytes_to_from)}</td>
-              <td>${fmtBytes(total)}</td>
-            `;
-            tbody.appendChild(tr);
-          });
-        } catch (e) {
-          alertBox.textContent = 'Failed to load data: ' + e.message;
-          alertBox.classList.remove('d-none');
+async fn tls_files_mtime(tls: &TlsPaths) -> Option<(SystemTime, SystemTime)> {
+    let cert = tokio::fs::metadata(&tls.cert).await.ok()?.modified().ok()?;
+    let key = tokio::fs::metadata(&tls.key).await.ok()?.modified().ok()?;
+    Some((cert, key))
+}
+
+/// Polls the certificate and key files and hot-swaps the TLS config when either
+/// changes, so ACME/cert-manager renewals are picked up without a restart.
+/// A failed reload (e.g. the key was not written yet) keeps the previous
+/// certificate and is retried on the next tick.
+async fn watch_tls_files(config: RustlsConfig, tls: TlsPaths) {
+    let mut last = tls_files_mtime(&tls).await;
+    let mut interval = tokio::time::interval(TLS_RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = tls_files_mtime(&tls).await;
+        if current.is_none() || current == last {
+            continue;
         }
-      }
-
-      async function loadConnects() {
-        try {
-          const res = await fetch('/config/connects');
-          if (!res.ok) return; // ignore silently if not available
-          const list = await res.json();
-          const sel = document.getElementById('conn');
-          list.forEach((c) => {
-            const opt = document.createElement('option');
-            opt.value = c.name;
-            opt.textContent = `${c.name} — ${c.remote_address}:${c.remote_port}`;
-            sel.appendChild(opt);
-          });
-        } catch (e) {
-          // ignore
+        match config.reload_from_pem_file(&tls.cert, &tls.key).await {
+            Ok(()) => {
+                info!("Reloaded TLS certificate from '{}'", tls.cert);
+                last = current;
+            }
+            Err(e) => error!("Failed to reload TLS certificate '{}': {}", tls.cert, e),
+        }
+    }
+}
+
+/// A static file compiled into the binary.
+struct Asset {
+    content_type: &'static str,
+    cache_control: &'static str,
+    body: &'static [u8],
+    etag: u64,
+}
+
+impl Asset {
+    const fn new(
+        content_type: &'static str,
+        cache_control: &'static str,
+        body: &'static [u8],
+    ) -> Self {
+        Asset {
+            content_type,
+            cache_control,
+            body,
+            etag: fnv1a(body),
         }
-      }
-
-      document.addEventListener('DOMContentLoaded', () => {
-        const now = new Date();
-        const weekAgo = new Date(now.getTime() - 7*24*60*60*1000);
-        document.getElementById('start').value = toLocalInputValue(weekAgo);
-        document.getElementById('end').value = toLocalInputValue(now);
-        document.getElementById('range-form').addEventListener('submit', (e) => {
-          e.preventDefault();
-          loadStats();
-        });
-        loadConnects();
-        loadStats();
-      });
-    </script>
-  </body>
- </html>
-"#,
+    }
+}
+
+/// FNV-1a hash, evaluated at compile time to derive asset ETags.
+const fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+        i += 1;
+    }
+    hash
+}
+
+// The page itself is always revalidated so a new binary takes effect at once;
+// scripts and styles may be cached for an hour and are revalidated by ETag.
+static INDEX_HTML: Asset = Asset::new(
+    "text/html; charset=utf-8",
+    "no-cache",
+    include_bytes!("../assets/index.html"),
+);
+static APP_JS: Asset = Asset::new(
+    "text/javascript; charset=utf-8",
+    "public, max-age=3600",
+    include_bytes!("../assets/app.js"),
+);
+static APP_CSS: Asset = Asset::new(
+    "text/css; charset=utf-8",
+    "public, max-age=3600",
+    include_bytes!("../assets/app.css"),
+);
+
+fn serve_asset(asset: &'static Asset, headers: &HeaderMap) -> Response {
+    let etag = format!("\"{:016x}\"", asset.etag);
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == etag || t.trim() == "*"));
+    let common = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, asset.cache_control.to_string()),
+    ];
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, common).into_response();
+    }
+    (
+        common,
+        [(header::CONTENT_TYPE, asset.content_type)],
+        asset.body,
     )
+        .into_response()
+}
+
+async fn index_handler(headers: HeaderMap) -> Response {
+    serve_asset(&INDEX_HTML, &headers)
+}
+
+async fn asset_handler(Path(file): Path<String>, headers: HeaderMap) -> Response {
+    match file.as_str() {
+        "app.js" => serve_asset(&APP_JS, &headers),
+        "app.css" => serve_asset(&APP_CSS, &headers),
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn active_sessions_handler(State(state): State<AppState>) -> Json<Vec<ActiveSession>> {
+    Json(state.live.active_sessions())
+}
+
+async fn recent_errors_handler(State(state): State<AppState>) -> Json<Vec<RecentError>> {
+    Json(state.live.recent_errors())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwarded_actor_only_from_trusted_proxy() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-user", "alice".parse().unwrap());
+        let admin = AdminAccess {
+            token: None,
+            trusted_proxies: vec!["10.0.0.5".parse().unwrap()],
+        };
+        let proxy: SocketAddr = "10.0.0.5:4000".parse().unwrap();
+        let client: SocketAddr = "192.0.2.7:4000".parse().unwrap();
+        assert_eq!(actor(&headers, proxy, &admin), "alice (via 10.0.0.5)");
+        assert_eq!(actor(&headers, client, &admin), "192.0.2.7");
+        assert_eq!(actor(&HeaderMap::new(), proxy, &admin), "10.0.0.5");
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(token_eq(b"s3cret", b"s3cret"));
+        assert!(!token_eq(b"s3cret", b"s3creT"));
+        assert!(!token_eq(b"s3cret", b"s3cre"));
+        assert!(!token_eq(b"", b"s3cret"));
+    }
 }
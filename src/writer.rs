@@ -0,0 +1,605 @@
+// Background DB writer. Events are collected into batches; a batch that
+// fails to insert is retried with backoff and, if the database stays down,
+// appended to a bounded on-disk journal (NDJSON) that is replayed in order
+// once the database accepts writes again, including after a restart.
+use log::{error, info, warn};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
+
+use crate::db::ConnectionRow;
+use crate::events::LogEvent;
+use crate::storage::SharedStorage;
+
+/// Delays between insert attempts of one batch before it is spooled.
+const RETRY_DELAYS: &[Duration] = &[
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+];
+/// Replay backoff grows from the first to the second value.
+const REPLAY_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const REPLAY_BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Rows inserted per transaction while replaying the journal.
+const REPLAY_CHUNK: usize = 1000;
+/// Batches queued between the collector and the flusher.
+const BATCH_QUEUE: usize = 16;
+
+pub struct WriterConfig {
+    pub flush_every: Duration,
+    pub max_count: usize,
+    /// Journal file; `None` drops batches that cannot be written.
+    pub spool_path: Option<PathBuf>,
+    pub spool_max_bytes: u64,
+}
+
+/// Counters since startup, exposed at `GET /admin/writer`.
+#[derive(Default)]
+pub struct WriterStats {
+    lagged_events: AtomicU64,
+    written_rows: AtomicU64,
+    failed_attempts: AtomicU64,
+    spooled_rows: AtomicU64,
+    replayed_rows: AtomicU64,
+    dropped_rows: AtomicU64,
+    spool_bytes: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct WriterStatsSnapshot {
+    /// Events lost because the writer fell behind the broadcast channel.
+    pub lagged_events: u64,
+    pub written_rows: u64,
+    pub failed_attempts: u64,
+    pub spooled_rows: u64,
+    pub replayed_rows: u64,
+    /// Rows lost because the journal was full, disabled or unreadable.
+    pub dropped_rows: u64,
+    /// Current journal size.
+    pub spool_bytes: u64,
+}
+
+impl WriterStats {
+    pub fn snapshot(&self) -> WriterStatsSnapshot {
+        WriterStatsSnapshot {
+            lagged_events: self.lagged_events.load(Ordering::Relaxed),
+            written_rows: self.written_rows.load(Ordering::Relaxed),
+            failed_attempts: self.failed_attempts.load(Ordering::Relaxed),
+            spooled_rows: self.spooled_rows.load(Ordering::Relaxed),
+            replayed_rows: self.replayed_rows.load(Ordering::Relaxed),
+            dropped_rows: self.dropped_rows.load(Ordering::Relaxed),
+            spool_bytes: self.spool_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Starts the collector and flusher tasks.
+pub fn spawn(
+    storage: SharedStorage,
+    rx: broadcast::Receiver<LogEvent>,
+    config: WriterConfig,
+) -> Arc<WriterStats> {
+    let stats = Arc::new(WriterStats::default());
+    let (batch_tx, batch_rx) = mpsc::channel(BATCH_QUEUE);
+    tokio::spawn(collect(
+        rx,
+        batch_tx,
+        config.flush_every,
+        config.max_count,
+        stats.clone(),
+    ));
+    let flusher = Flusher {
+        storage,
+        spool_path: config.spool_path,
+        spool_max_bytes: config.spool_max_bytes,
+        stats: stats.clone(),
+    };
+    tokio::spawn(flusher.run(batch_rx));
+    stats
+}
+
+/// Turns events into batches by size or time. Lag is counted, not fatal.
+async fn collect(
+    mut rx: broadcast::Receiver<LogEvent>,
+    batch_tx: mpsc::Sender<Vec<ConnectionRow>>,
+    flush_every: Duration,
+    max_count: usize,
+    stats: Arc<WriterStats>,
+) {
+    let mut buf: Vec<ConnectionRow> = Vec::with_capacity(max_count);
+    let mut deadline = Instant::now() + flush_every;
+    loop {
+        let closed = tokio::select! {
+            event = rx.recv() => match event {
+                Ok(event) => {
+                    buf.extend(ConnectionRow::from_event(event));
+                    false
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    let total = stats.lagged_events.fetch_add(n, Ordering::Relaxed) + n;
+                    warn!("DB writer lagged, {} events dropped ({} total)", n, total);
+                    false
+                }
+                Err(broadcast::error::RecvError::Closed) => true,
+            },
+            _ = sleep_until(deadline) => false,
+        };
+        if !buf.is_empty() && (closed || buf.len() >= max_count || Instant::now() >= deadline) {
+            let batch = std::mem::replace(&mut buf, Vec::with_capacity(max_count));
+            if batch_tx.send(batch).await.is_err() {
+                return;
+            }
+        }
+        if Instant::now() >= deadline {
+            deadline = Instant::now() + flush_every;
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+struct Flusher {
+    storage: SharedStorage,
+    spool_path: Option<PathBuf>,
+    spool_max_bytes: u64,
+    stats: Arc<WriterStats>,
+}
+
+impl Flusher {
+    async fn run(self, mut batches: mpsc::Receiver<Vec<ConnectionRow>>) {
+        // A journal left over from a previous run is replayed first.
+        let leftover = match &self.spool_path {
+            Some(path) => tokio::fs::metadata(path)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0),
+            None => 0,
+        };
+        self.stats.spool_bytes.store(leftover, Ordering::Relaxed);
+        if leftover > 0 {
+            info!("DB writer: {} bytes in journal, replaying", leftover);
+        }
+        let mut backoff = REPLAY_BACKOFF_MIN;
+        let mut next_replay = Instant::now();
+        loop {
+            let spooled = self.stats.spool_bytes.load(Ordering::Relaxed) > 0;
+            tokio::select! {
+                batch = batches.recv() => {
+                    let Some(batch) = batch else { return };
+                    if spooled {
+                        // Keep order: new rows go behind the journal.
+                        self.spool(&batch).await;
+                    } else if !self.insert_with_retry(&batch).await {
+                        self.spool(&batch).await;
+                        next_replay = Instant::now() + backoff;
+                    }
+                }
+                _ = sleep_until(next_replay), if spooled => {
+                    match self.replay().await {
+                        Ok(rows) => {
+                            info!("DB writer: database is back, replayed {} rows from journal", rows);
+                            backoff = REPLAY_BACKOFF_MIN;
+                        }
+                        Err(e) => {
+                            error!("DB writer: journal replay failed, next try in {:?}: {:#}", backoff, e);
+                            next_replay = Instant::now() + backoff;
+                            backoff = (backoff * 2).min(REPLAY_BACKOFF_MAX);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn insert_with_retry(&self, batch: &[ConnectionRow]) -> bool {
+        let mut delays = RETRY_DELAYS.iter();
+        loop {
+            match self.storage.insert_connection_rows(batch).await {
+                Ok(()) => {
+                    self.stats
+                        .written_rows
+                        .fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    return true;
+                }
+                Err(e) => {
+                    self.stats.failed_attempts.fetch_add(1, Ordering::Relaxed);
+                    let Some(delay) = delays.next() else {
+                        error!(
+                            "Failed to batch write stats to {}, giving up on {} rows: {:#}",
+                            self.storage.backend(),
+                            batch.len(),
+                            e
+                        );
+                        return false;
+                    };
+                    warn!(
+                        "Failed to batch write stats to {}, retrying in {:?}: {:#}",
+                        self.storage.backend(),
+                        delay,
+                        e
+                    );
+                    sleep(*delay).await;
+                }
+            }
+        }
+    }
+
+    fn drop_rows(&self, rows: usize, reason: &str) {
+        let total = self
+            .stats
+            .dropped_rows
+            .fetch_add(rows as u64, Ordering::Relaxed)
+            + rows as u64;
+        warn!(
+            "DB writer: dropped {} rows, {} ({} total)",
+            rows, reason, total
+        );
+    }
+
+    /// Appends a batch to the journal unless that would exceed the size limit.
+    async fn spool(&self, batch: &[ConnectionRow]) {
+        let Some(path) = &self.spool_path else {
+            self.drop_rows(batch.len(), "journal is disabled");
+            return;
+        };
+        let mut data = Vec::new();
+        for row in batch {
+            if serde_json::to_writer(&mut data, row).is_ok() {
+                data.push(b'\n');
+            }
+        }
+        let size = self.stats.spool_bytes.load(Ordering::Relaxed);
+        if size + data.len() as u64 > self.spool_max_bytes {
+            self.drop_rows(batch.len(), "journal is full");
+            return;
+        }
+        let result = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?;
+            file.write_all(&data).await?;
+            file.sync_data().await
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                self.stats
+                    .spool_bytes
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+                self.stats
+                    .spooled_rows
+                    .fetch_add(batch.len() as u64, Ordering::Relaxed);
+            }
+            Err(e) => self.drop_rows(batch.len(), &format!("journal write failed: {}", e)),
+        }
+    }
+
+    /// Inserts the journal in chunks. On failure the rows already written
+    /// are cut off the journal so they are not inserted twice.
+    async fn replay(&self) -> anyhow::Result<u64> {
+        let Some(path) = &self.spool_path else {
+            return Ok(0);
+        };
+        let file = tokio::fs::File::open(path).await?;
+        let mut lines = BufReader::new(file).lines();
+        let mut chunk: Vec<ConnectionRow> = Vec::with_capacity(REPLAY_CHUNK);
+        // Bytes read so far and bytes whose rows are already in the database.
+        let (mut consumed, mut committed): (u64, u64) = (0, 0);
+        let mut replayed: u64 = 0;
+        loop {
+            let line = lines.next_line().await?;
+            let eof = line.is_none();
+            if let Some(line) = line {
+                consumed += line.len() as u64 + 1;
+                match serde_json::from_str(&line) {
+                    Ok(row) => chunk.push(row),
+                    // A torn write at the end of the file after a crash.
+                    Err(_) if !line.trim().is_empty() => {
+                        self.drop_rows(1, "journal line is corrupt")
+                    }
+                    Err(_) => {}
+                }
+            }
+            if chunk.len() >= REPLAY_CHUNK || (eof && !chunk.is_empty()) {
+                if let Err(e) = self.storage.insert_connection_rows(&chunk).await {
+                    self.stats.failed_attempts.fetch_add(1, Ordering::Relaxed);
+                    if committed > 0 {
+                        self.cut_journal(committed).await?;
+                    }
+                    return Err(e);
+                }
+                committed = consumed;
+                replayed += chunk.len() as u64;
+                for counter in [&self.stats.replayed_rows, &self.stats.written_rows] {
+                    counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+                chunk.clear();
+            }
+            if eof {
+                break;
+            }
+        }
+        tokio::fs::remove_file(path).await?;
+        self.stats.spool_bytes.store(0, Ordering::Relaxed);
+        Ok(replayed)
+    }
+
+    /// Drops the first `offset` bytes of the journal (atomic rewrite).
+    async fn cut_journal(&self, offset: u64) -> anyhow::Result<()> {
+        let Some(path) = &self.spool_path else {
+            return Ok(());
+        };
+        let data = tokio::fs::read(path).await?;
+        let rest = &data[(offset as usize).min(data.len())..];
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, rest).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        self.stats
+            .spool_bytes
+            .store(rest.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        Breakdown, BreakdownRow, ClientTraffic, FailureReason, GroupBy, PruneReport,
+        RetentionPolicy, SessionInfo, StoredConnection, TimeseriesPoint, TopBy, TrafficFilter,
+    };
+    use crate::storage::Storage;
+    use async_trait::async_trait;
+    use chrono::{DateTime, Utc};
+    use chrono_tz::Tz;
+    use std::sync::atomic::AtomicI64;
+    use std::sync::Mutex;
+
+    /// Accepts `allowed` inserts and then fails; a negative value never fails.
+    /// Only the writer's method is implemented.
+    struct FlakyStorage {
+        allowed: AtomicI64,
+        session_ids: Mutex<Vec<u64>>,
+    }
+
+    impl FlakyStorage {
+        fn new(allowed: i64) -> Arc<Self> {
+            Arc::new(Self {
+                allowed: AtomicI64::new(allowed),
+                session_ids: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn stored(&self) -> Vec<u64> {
+            self.session_ids.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl Storage for FlakyStorage {
+        fn backend(&self) -> &'static str {
+            "flaky"
+        }
+
+        async fn insert_connection_rows(&self, rows: &[ConnectionRow]) -> anyhow::Result<()> {
+            if self.allowed.fetch_sub(1, Ordering::SeqCst) == 0 {
+                self.allowed.store(0, Ordering::SeqCst);
+                anyhow::bail!("database is down");
+            }
+            let mut ids = self.session_ids.lock().unwrap();
+            ids.extend(rows.iter().map(|r| r.session_id));
+            Ok(())
+        }
+
+        async fn traffic_by_client(
+            &self,
+            _: DateTime<Utc>,
+            _: DateTime<Utc>,
+            _: Option<String>,
+        ) -> anyhow::Result<Vec<ClientTraffic>> {
+            unreachable!()
+        }
+
+        async fn traffic_timeseries(
+            &self,
+            _: DateTime<Utc>,
+            _: DateTime<Utc>,
+            _: i64,
+            _: Tz,
+            _: Option<GroupBy>,
+            _: TrafficFilter,
+        ) -> anyhow::Result<Vec<TimeseriesPoint>> {
+            unreachable!()
+        }
+
+        async fn traffic_breakdown(
+            &self,
+            _: DateTime<Utc>,
+            _: DateTime<Utc>,
+            _: Breakdown,
+            _: TopBy,
+            _: TrafficFilter,
+            _: u32,
+        ) -> anyhow::Result<Vec<BreakdownRow>> {
+            unreachable!()
+        }
+
+        async fn sessions(
+            &self,
+            _: DateTime<Utc>,
+            _: DateTime<Utc>,
+            _: TrafficFilter,
+            _: bool,
+            _: u32,
+        ) -> anyhow::Result<Vec<SessionInfo>> {
+            unreachable!()
+        }
+
+        async fn connections_page(
+            &self,
+            _: DateTime<Utc>,
+            _: DateTime<Utc>,
+            _: Option<String>,
+            _: (i64, i64),
+            _: u32,
+        ) -> anyhow::Result<Vec<StoredConnection>> {
+            unreachable!()
+        }
+
+        async fn failure_reasons(
+            &self,
+            _: DateTime<Utc>,
+            _: DateTime<Utc>,
+            _: TrafficFilter,
+        ) -> anyhow::Result<Vec<FailureReason>> {
+            unreachable!()
+        }
+
+        async fn prune(&self, _: RetentionPolicy) -> anyhow::Result<PruneReport> {
+            unreachable!()
+        }
+    }
+
+    fn rows(ids: std::ops::Range<u64>) -> Vec<ConnectionRow> {
+        ids.map(|id| ConnectionRow {
+            ts: 1_700_000_000,
+            name: "pg".to_string(),
+            log_name: "connection_closed".to_string(),
+            local_port: 6432,
+            remote_address: "db.internal".to_string(),
+            remote_port: 5432,
+            client_addr: None,
+            bytes_from_to: 1,
+            bytes_to_from: 1,
+            session_id: id,
+            instance_id: None,
+            upstream_ip: None,
+            error: None,
+            close_reason: None,
+            duration_ms: None,
+        })
+        .collect()
+    }
+
+    fn flusher(storage: Arc<FlakyStorage>, path: &std::path::Path, max_bytes: u64) -> Flusher {
+        Flusher {
+            storage,
+            spool_path: Some(path.to_path_buf()),
+            spool_max_bytes: max_bytes,
+            stats: Arc::new(WriterStats::default()),
+        }
+    }
+
+    fn journal_ids(path: &std::path::Path) -> Vec<u64> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<ConnectionRow>(line)
+                    .unwrap()
+                    .session_id
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn spooled_rows_are_replayed_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("writer.spool");
+        let storage = FlakyStorage::new(-1);
+        let flusher = flusher(storage.clone(), &path, 1 << 20);
+        flusher.spool(&rows(0..3)).await;
+        flusher.spool(&rows(3..5)).await;
+        assert_eq!(journal_ids(&path), vec![0, 1, 2, 3, 4]);
+        let stats = flusher.stats.snapshot();
+        assert_eq!(stats.spooled_rows, 5);
+        assert_eq!(stats.spool_bytes, std::fs::metadata(&path).unwrap().len());
+
+        assert_eq!(flusher.replay().await.unwrap(), 5);
+        assert_eq!(storage.stored(), vec![0, 1, 2, 3, 4]);
+        assert!(!path.exists());
+        let stats = flusher.stats.snapshot();
+        assert_eq!((stats.replayed_rows, stats.spool_bytes), (5, 0));
+    }
+
+    #[tokio::test]
+    async fn failed_replay_keeps_only_unwritten_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("writer.spool");
+        let total = REPLAY_CHUNK as u64 + 5;
+        // The first chunk goes in, then the database fails again.
+        let storage = FlakyStorage::new(1);
+        let flusher = flusher(storage.clone(), &path, 1 << 30);
+        flusher.spool(&rows(0..total)).await;
+
+        assert!(flusher.replay().await.is_err());
+        assert_eq!(storage.stored().len(), REPLAY_CHUNK);
+        assert_eq!(
+            journal_ids(&path),
+            (REPLAY_CHUNK as u64..total).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            flusher.stats.snapshot().spool_bytes,
+            std::fs::metadata(&path).unwrap().len()
+        );
+
+        storage.allowed.store(-1, Ordering::SeqCst);
+        assert_eq!(flusher.replay().await.unwrap(), 5);
+        // Every row exactly once.
+        assert_eq!(storage.stored(), (0..total).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn torn_last_line_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("writer.spool");
+        let storage = FlakyStorage::new(-1);
+        let flusher = flusher(storage.clone(), &path, 1 << 20);
+        flusher.spool(&rows(0..2)).await;
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(b"\n{\"ts\":17000");
+        std::fs::write(&path, data).unwrap();
+
+        assert_eq!(flusher.replay().await.unwrap(), 2);
+        assert_eq!(storage.stored(), vec![0, 1]);
+        assert_eq!(flusher.stats.snapshot().dropped_rows, 1);
+    }
+
+    #[tokio::test]
+    async fn full_journal_drops_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("writer.spool");
+        // Room for one row and a bit, not for two more.
+        let row_size = serde_json::to_vec(&rows(0..1)[0]).unwrap().len() as u64 + 1;
+        let flusher = flusher(FlakyStorage::new(-1), &path, row_size * 2);
+        flusher.spool(&rows(0..1)).await;
+        let size = std::fs::metadata(&path).unwrap().len();
+        flusher.spool(&rows(1..3)).await;
+        // The whole batch is dropped, the journal is unchanged.
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+        let stats = flusher.stats.snapshot();
+        assert_eq!((stats.spooled_rows, stats.dropped_rows), (1, 2));
+    }
+
+    #[tokio::test]
+    async fn cut_journal_drops_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("writer.spool");
+        let flusher = flusher(FlakyStorage::new(-1), &path, 1 << 20);
+        std::fs::write(&path, b"first\nsecond\n").unwrap();
+        flusher.cut_journal(6).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second\n");
+        assert_eq!(flusher.stats.snapshot().spool_bytes, 7);
+        // An offset past the end leaves an empty journal.
+        flusher.cut_journal(100).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"");
+        assert!(!path.with_extension("tmp").exists());
+    }
+}